@@ -1,29 +1,34 @@
-use std::str::FromStr;
-
 #[cfg(not(feature = "library"))]
 use cosmwasm_std::entry_point;
 use cosmwasm_std::{
-    ensure_eq, has_coins, to_binary, to_json_binary, wasm_execute, Addr, Api, BalanceResponse, BankMsg, BankQuery, Binary, Coin, CosmosMsg, Decimal, Deps, DepsMut, Empty, Env, MessageInfo, Order, QueryRequest, Response, StdResult, Storage, Timestamp, Uint128, WasmMsg, WasmQuery
+    ensure_eq, from_json, to_json_binary, wasm_execute, Addr, Api, BankMsg, Binary, Coin, CosmosMsg, Decimal, Deps, DepsMut, Empty, Env, HexBinary, MessageInfo, Order, QuerierWrapper, Reply, Response, StdResult, SubMsg, SubMsgResult, Storage, Timestamp, Uint128, WasmMsg
 };
-use cw2::set_contract_version;
+use cw2::{get_contract_version, set_contract_version};
+use cw_storage_plus::{Bound, Map, PrimaryKey};
+use semver::Version;
 
-use cw721::{Cw721ExecuteMsg, Cw721QueryMsg, NftInfoResponse};
+use cw20::{Cw20CoinVerified, Cw20ExecuteMsg, Cw20ReceiveMsg};
+use cw721::{Cw721ExecuteMsg, Cw721QueryMsg, Cw721ReceiveMsg};
 use cw721_base::ExecuteMsg as Cw721BaseExecuteMsg;
 
-use cw20::Cw20ExecuteMsg;
 use nois::{randomness_from_str, select_from_weighted, sub_randomness_with_key, NoisCallback, ProxyExecuteMsg};
 
-use crate::{error::ContractError, msg::{ExecuteMsg, InstantiateMsg, QueryMsg}, state::{AuragonURI, Config, GemInfo, GemMetadata, Metadata, RandomJob, RequestForgeGemInfo, Trait, UserInfo, AURAGON_LATEST_TOKEN_ID, AURAGON_URI, BLUE_GEM_WORK_POWER, CONFIG, CURRENT_QUEUE_ID, GEM_RATIO, GEM_WORK_LOAD, GOLD_GEM_WORK_POWER, RANDOM_JOBS, RANDOM_SEED, RED_GEM_WORK_POWER, SHIELD_LATEST_TOKEN_ID, SHIELD_URI, USERS_IN_QUEUE, WHITE_GEM_WORK_POWER}};
+use crate::{error::ContractError, migrations::migrate_state, msg::{AcceptedTokensResponse, AllFragmentsResponse, DragonGemsResponse, ExecuteMsg, FailurePenaltiesResponse, ForgeGemResponse, FragmentsResponse, FusionRulesResponse, ForgeResultsResponse, GemTierMsg, GemTiersResponse, InstantiateMsg, MigrateMsg, NoisBalanceResponse, OwnershipResponse, PityStatus, PityStatusResponse, QueryMsg, QueueEntryResponse, QueueLengthResponse, QueuePositionResponse, QueueResponse, RandomGemSaleResponse, RandomJobsResponse, RandomMintsResponse, ReceiveCw20Msg, ReceiveNftMsg, TreasuryResponse}, state::{forge_results, AcceptedToken, Config, FusionRule, PauseState, ACCEPTED_TOKENS, BUFFERED_RANDOMNESS, CURRENT_JOB_ID, CW20_PENDING_FEES, CW20_TREASURY, DRAGON_GEMS, Escrow, FailurePenalty, ForgeOutcome, ForgeResult, GemInfo, GemKind, GemTier, JobParticipant, Metadata, RandomGemSale, PityConfig, RandomJob, RandomMint, SalvageConfig, Trait, UserInfo, AURAGON_LATEST_TOKEN_ID, CONFIG, CURRENT_QUEUE_ID, ESCROWS, FAILURE_PENALTIES, FORGE_RESULT_COUNT, FRAGMENTS, FUSION_RULES, GEM_TIERS, NOIS_BALANCE, PAUSE_STATE, PENDING_FEES, PENDING_OWNER, PITY_CONFIG, PITY_COUNTERS, RANDOM_GEMS_SOLD, RANDOM_GEM_SALE, RANDOM_JOBS, RANDOM_MINTS, RANDOM_SEED, SALVAGE_CONFIG, SETTLING_JOBS, SHIELD_LATEST_TOKEN_ID, SHIELD_URI, TREASURY, QUEUE_LENGTH, QUEUE_PAGES, users_in_queue}};
 
 
 // version info for migration info
 const CONTRACT_NAME: &str = "crates.io:wheel-of-fortune";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-const MAX_TEXT_LENGTH: usize = 253;
-const MAX_VEC_ITEM: usize = 65536;
-const MAX_SPINS_PER_TURN: u32 = 10;
-const DEFAULT_ACTIVATE: bool = false;
+// success rates are expressed in basis points
+pub const SUCCESS_RATE_PRECISION: u32 = 10_000;
+
+// settings for pagination
+const DEFAULT_LIMIT: u32 = 10;
+const MAX_LIMIT: u32 = 30;
+
+// entry ids counted together to find the position of an entry in the queue
+const QUEUE_PAGE_SIZE: u64 = 100;
 
 /// Handling contract instantiation
 #[cfg_attr(not(feature = "library"), entry_point)]
@@ -38,46 +43,40 @@ pub fn instantiate(
     let nois_proxy = addr_validate(deps.api, &msg.nois_proxy)?;
 
     let config = Config {
+        owner: Some(info.sender.clone()),
         nois_proxy,
         dragon_collection: addr_validate(deps.api, &msg.dragon_collection)?,
         auragon_collection: addr_validate(deps.api, &msg.auragon_collection)?,
         shield_collection: addr_validate(deps.api, &msg.shield_collection)?,
+        job_timeout: msg.job_timeout,
+        nois_price: validate_coins(msg.nois_price)?,
     };
     CONFIG.save(deps.storage, &config)?;
 
-    AURAGON_URI.save(
-        deps.storage,
-        &AuragonURI {
-            white: msg.white_gem_uri,
-            blue: msg.blue_gem_uri,
-            gold: msg.gold_gem_uri,
-            red: msg.red_gem_uri,
-        },
-    )?;
+    for tier in msg.gem_tiers {
+        let (color, gem_tier) = gem_tier_from_msg(tier)?;
+        if GEM_TIERS.has(deps.storage, &color) {
+            return Err(ContractError::GemTierExists { color });
+        }
+        GEM_TIERS.save(deps.storage, &color, &gem_tier)?;
+    }
+    for (token_uri, gem) in msg.dragon_gems {
+        validate_gem(deps.storage, &gem)?;
+        DRAGON_GEMS.save(deps.storage, &token_uri, &gem)?;
+    }
 
     SHIELD_URI.save(deps.storage, &msg.shield_uri)?;
 
     // save the init RANDOM_SEED to the storage
-    let randomness = randomness_from_str(msg.random_seed).unwrap();
+    let randomness = randomness_from_str(msg.random_seed).map_err(|_| ContractError::InvalidSeed {})?;
     RANDOM_SEED.save(deps.storage, &randomness)?;
-    // save white gem work power to the storage
-    WHITE_GEM_WORK_POWER.save(deps.storage, &msg.white_gem_work_power)?;
-    // save blue gem work power to the storage
-    BLUE_GEM_WORK_POWER.save(deps.storage, &msg.blue_gem_work_power)?;
-    // save gold gem work power to the storage
-    GOLD_GEM_WORK_POWER.save(deps.storage, &msg.gold_gem_work_power)?;
-    // save red gem work power to the storage
-    RED_GEM_WORK_POWER.save(deps.storage, &msg.red_gem_work_power)?;
-    // save gem ratio to the storage
-    GEM_RATIO.save(deps.storage, &msg.gem_ratio)?;
-    // save gem work load to the storage
-    GEM_WORK_LOAD.save(deps.storage, &msg.gem_work_load)?;
     // Initialize the token id
     AURAGON_LATEST_TOKEN_ID.save(deps.storage, &0)?;
     SHIELD_LATEST_TOKEN_ID.save(deps.storage, &0)?;
 
     // Initialize the current queue id
     CURRENT_QUEUE_ID.save(deps.storage, &0)?;
+    QUEUE_LENGTH.save(deps.storage, &0)?;
 
     Ok(Response::new()
         .add_attribute("method", "instantiate")
@@ -92,16 +91,21 @@ pub fn execute(
     msg: ExecuteMsg,
 ) -> Result<Response, ContractError> {
     match msg {
-        ExecuteMsg::JoinQueue {
-            gem_base,
-            gem_materials,
-            shield_id,
-        } => execute_join_queue(deps, env, info, gem_base, gem_materials, shield_id),
-        ExecuteMsg::ForgeGem { request_forge_id, forge_gem_list }
-            => execute_forge_gem(deps, env, info, request_forge_id, forge_gem_list),
+        ExecuteMsg::ReceiveNft(receive_msg) => execute_receive_nft(deps, env, info, receive_msg),
+        ExecuteMsg::Receive(receive_msg) => execute_receive_cw20(deps, env, info, receive_msg),
+        ExecuteMsg::WithdrawNft { nft_contract, token_id }
+            => execute_withdraw_nft(deps, env, info, nft_contract, token_id),
+        ExecuteMsg::ForgeGem { entry_ids } => execute_forge_gem(deps, env, info, entry_ids),
+        ExecuteMsg::LeaveQueue { entry_id } => execute_leave_queue(deps, env, info, entry_id),
+        ExecuteMsg::ExpireJob { job_id } => execute_expire_job(deps, env, info, job_id),
         // ExecuteMsg::ForgeGemType1 { user_list } => execute_forge_gem_type_1(deps, env, info, user_list),
         //nois callback
         ExecuteMsg::NoisReceive { callback } => nois_receive(deps, env, info, callback),
+        ExecuteMsg::SettleBufferedJobs { limit } => execute_settle_buffered_jobs(deps, env, info, limit),
+        ExecuteMsg::SettleBufferedJob { job_id, randomness } => {
+            execute_settle_buffered_job(deps, env, info, job_id, randomness)
+        }
+        ExecuteMsg::SetPauseState { pause_state } => execute_set_pause_state(deps, env, info, pause_state),
         ExecuteMsg::UpdateCollection {
             dragon_collection,
             auragon_collection,
@@ -110,469 +114,931 @@ pub fn execute(
         ExecuteMsg::MintAuragonGem { owner, gem_trait
         } => mint_auragon_gem(deps, env, info, owner, gem_trait),
         ExecuteMsg::MintShieldGem { owner } => mint_shield_gem(deps, env, info, owner),
+        ExecuteMsg::MintRandomGem {} => execute_mint_random_gem(deps, env, info),
+        ExecuteMsg::FuseGems { gems } => execute_fuse_gems(deps, env, info, gems),
+        ExecuteMsg::SalvageGem { gems } => execute_salvage_gem(deps, env, info, gems),
+        ExecuteMsg::CraftShield {} => execute_craft_shield(deps, env, info),
+        ExecuteMsg::UpdateSalvageConfig { salvage_config } => update_salvage_config(deps, env, info, salvage_config),
+        ExecuteMsg::UpdatePityConfig { pity_config } => update_pity_config(deps, env, info, pity_config),
+        ExecuteMsg::UpdateFusionRule { color, star, rule } => update_fusion_rule(deps, env, info, color, star, rule),
+        ExecuteMsg::UpdateRandomGemSale { sale } => update_random_gem_sale(deps, env, info, sale),
+        ExecuteMsg::AddGemTier { tier } => add_gem_tier(deps, env, info, tier),
+        ExecuteMsg::UpdateGemTier { tier } => update_gem_tier(deps, env, info, tier),
+        ExecuteMsg::RetireGemTier { color } => retire_gem_tier(deps, env, info, color),
+        ExecuteMsg::UpdateDragonGem { token_uri, gem } => update_dragon_gem(deps, env, info, token_uri, gem),
+        ExecuteMsg::UpdateJobTimeout { job_timeout } => update_job_timeout(deps, env, info, job_timeout),
+        ExecuteMsg::UpdateNoisPrice { nois_price } => update_nois_price(deps, env, info, nois_price),
+        ExecuteMsg::TopUpNoisBalance {} => execute_top_up_nois_balance(deps, env, info),
+        ExecuteMsg::WithdrawNoisBalance { amount, recipient }
+            => execute_withdraw_nois_balance(deps, env, info, amount, recipient),
+        ExecuteMsg::WithdrawTreasury { amount, recipient }
+            => execute_withdraw_treasury(deps, env, info, amount, recipient),
+        ExecuteMsg::WithdrawCw20Treasury { token, amount, recipient }
+            => execute_withdraw_cw20_treasury(deps, env, info, token, amount, recipient),
+        ExecuteMsg::UpdateAcceptedToken { token, prices } => update_accepted_token(deps, env, info, token, prices),
+        ExecuteMsg::UpdateFailurePenalty { color, penalty }
+            => update_failure_penalty(deps, env, info, color, penalty),
+        ExecuteMsg::TransferOwnership { new_owner } => execute_transfer_ownership(deps, env, info, new_owner),
+        ExecuteMsg::AcceptOwnership {} => execute_accept_ownership(deps, env, info),
+        ExecuteMsg::RenounceOwnership {} => execute_renounce_ownership(deps, env, info),
     }
 }
 
-pub fn execute_join_queue(
+pub fn execute_receive_nft(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    gem_base: GemInfo,
-    gem_materials: Vec<GemInfo>,
-    shield_id: Option<String>,
+    receive_msg: Cw721ReceiveMsg,
 ) -> Result<Response, ContractError> {
-
     // Load the config
     let config = CONFIG.load(deps.storage)?;
 
-    // Load the auragon_collection
-    let auragon_collection = config.auragon_collection;
-
-    // Load the shield_collection
-    let shield_collection = config.shield_collection;
-
-    // Load the latest token id
-    let mut latest_token_id = AURAGON_LATEST_TOKEN_ID.load(deps.storage)?;
-
-    // Load current queue id
-    let mut current_queue_id = CURRENT_QUEUE_ID.load(deps.storage)?;
-
-    let mut res = Response::new();
-    // Approve the gem_base NFT
-    let approve_gem_base = wasm_execute(
-        gem_base.nft_contract.clone(),
-        &Cw721ExecuteMsg::Approve {
-            spender: env.contract.address.to_string(),
-            token_id: gem_base.nft_id.to_string(),
-            expires: None,
-        },
-        vec![],
-    )?;
-
-    res = res.add_message(approve_gem_base);
-
-    // Approve the gem_materials NFTs
-    for gem_material in &gem_materials {
-        let approve_gem_material = wasm_execute(
-            gem_material.nft_contract.clone(),
-            &Cw721ExecuteMsg::Approve {
-                spender: env.contract.address.to_string(),
-                token_id: gem_material.nft_id.to_string(),
-                expires: None,
-            },
-            vec![],
-        )?;
-        res = res.add_message(approve_gem_material);
+    // Only the gems of our collections are accepted
+    let nft_contract = info.sender;
+    if ![&config.dragon_collection, &config.auragon_collection, &config.shield_collection].contains(&&nft_contract) {
+        return Err(ContractError::InvalidCollection { collection: nft_contract.to_string() });
     }
 
-    // Approve, Transfer the shield NFT to this contract and burn it
-    if let Some(ref shield_id) = shield_id {
-        let approve_shield = wasm_execute(
-            shield_collection.clone(),
-            &Cw721ExecuteMsg::Approve {
-                spender: env.contract.address.to_string(),
-                token_id: shield_id.to_string(),
-                expires: None,
-            },
-            vec![],
-        )?;
-        res = res.add_message(approve_shield);
+    // The gem is held by this contract on behalf of the user from now on
+    let user = addr_validate(deps.api, &receive_msg.sender)?;
+    let gem = GemInfo {
+        nft_id: receive_msg.token_id,
+        nft_contract,
+    };
+    ESCROWS.save(deps.storage, (&gem.nft_contract, &gem.nft_id), &Escrow { owner: user.clone(), entry_id: None })?;
+
+    match from_json(&receive_msg.msg)? {
+        ReceiveNftMsg::Deposit {} => Ok(Response::new()
+            .add_attribute("action", "deposit")
+            .add_attribute("user", user)
+            .add_attribute("nft_contract", gem.nft_contract)
+            .add_attribute("token_id", gem.nft_id)),
+        ReceiveNftMsg::JoinQueue { gem_materials, shield_id } => {
+            // a gem that cannot be upgraded would wait in the queue for nothing
+            upgradable_tier(&deps.querier, deps.storage, &config, &gem)?;
+            let entry_id = join_queue(deps.storage, &env, &config, &user, gem, gem_materials, shield_id)?;
+            Ok(Response::new()
+                .add_attribute("action", "join_queue")
+                .add_attribute("user", user)
+                .add_attribute("entry_id", entry_id.to_string()))
+        }
+        ReceiveNftMsg::Forge { gem_materials, shield_id } => {
+            // No funds come with the gem, the tiers with forge fees are paid through ForgeGem
+            let (gem_kind, tier) = upgradable_tier(&deps.querier, deps.storage, &config, &gem)?;
+            if !tier.forge_fees.is_empty() {
+                return Err(ContractError::ForgeFeesRequired { color: gem_kind.color });
+            }
+            let entry_id = join_queue(deps.storage, &env, &config, &user, gem, gem_materials, shield_id)?;
+            // The entry is forged alone in a job of its own
+            let res = request_forge(deps, &env, &config, &user, vec![entry_id], Payment::Native(vec![]))?;
+            Ok(res
+                .add_attribute("user", user)
+                .add_attribute("entry_id", entry_id.to_string()))
+        }
+    }
+}
 
-        let transfer_shield = wasm_execute(
-            shield_collection.clone(),
-            &Cw721ExecuteMsg::TransferNft {
-                recipient: env.contract.address.to_string(),
-                token_id: shield_id.to_string(),
-            },
-            vec![],
-        )?;
-        res = res.add_message(transfer_shield);
+pub fn execute_receive_cw20(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    receive_msg: Cw20ReceiveMsg,
+) -> Result<Response, ContractError> {
+    // Only the accepted tokens are taken
+    let token = info.sender;
+    let accepted_token = ACCEPTED_TOKENS
+        .may_load(deps.storage, &token)?
+        .ok_or(ContractError::UnacceptedToken { token: token.to_string() })?;
+
+    // The user who sent the tokens
+    let user = addr_validate(deps.api, &receive_msg.sender)?;
+
+    match from_json(&receive_msg.msg)? {
+        ReceiveCw20Msg::ForgeGem { entry_ids } => {
+            let config = CONFIG.load(deps.storage)?;
+            ensure_can_forge(deps.storage, &config, &user, &entry_ids)?;
+            let sent = Cw20CoinVerified { address: token, amount: receive_msg.amount };
+            let res = request_forge(deps, &env, &config, &user, entry_ids, Payment::Cw20(sent, accepted_token))?;
+            Ok(res.add_attribute("user", user))
+        }
+        ReceiveCw20Msg::BuyShield {} => {
+            let config = CONFIG.load(deps.storage)?;
+            let price = accepted_token
+                .shield_price
+                .ok_or(ContractError::UnacceptedToken { token: token.to_string() })?;
+            let excess = receive_msg
+                .amount
+                .checked_sub(price)
+                .map_err(|_| ContractError::InsufficentFund {})?;
+
+            // The price is earned right away
+            add_balance(deps.storage, CW20_TREASURY, &token, price)?;
+            let (mint_gem, token_id) = mint_shield_msg(deps.storage, &config, user.as_str())?;
+
+            let mut res = Response::new().add_message(mint_gem);
+            if !excess.is_zero() {
+                res = res.add_message(transfer_cw20_msg(&token, excess, &user)?);
+            }
+            Ok(res
+                .add_attribute("action", "buy_shield")
+                .add_attribute("token_id", token_id)
+                .add_attribute("owner", user))
+        }
+    }
+}
 
-        let burn_shield = wasm_execute(
-            shield_collection.clone(),
-            &Cw721ExecuteMsg::Burn {
-                token_id: shield_id.to_string(),
-            },
-            vec![],
-        )?;
+/// add an entry to the queue with the gems escrowed by the user, returns its entry id
+fn join_queue(
+    storage: &mut dyn Storage,
+    env: &Env,
+    config: &Config,
+    user: &Addr,
+    gem_base: GemInfo,
+    gem_materials: Vec<GemInfo>,
+    shield_id: Option<String>,
+) -> Result<u64, ContractError> {
+    ensure_not_paused(storage, "join_queue", |pause_state| pause_state.join_queue)?;
 
-        res = res.add_message(burn_shield);
+    // Shields cannot be forged
+    if gem_base.nft_contract == config.shield_collection {
+        return Err(ContractError::InvalidCollection { collection: gem_base.nft_contract.to_string() });
     }
 
-    // Transfer the gem_base NFT to this contract
-    let transfer_gem_base = wasm_execute(
-        gem_base.nft_contract.clone(),
-        &Cw721ExecuteMsg::TransferNft {
-            recipient: env.contract.address.to_string(),
-            token_id: gem_base.nft_id.to_string(),
-        },
-        vec![],
-    )?;
-
-    res = res.add_message(transfer_gem_base);
+    let entry_id = CURRENT_QUEUE_ID.load(storage)?;
 
-    // Transfer the gem_materials NFTs to this contract
+    // Lock the gems of the user for the entry
+    lock_escrow(storage, &gem_base, user, entry_id)?;
     for gem_material in &gem_materials {
-        let transfer_gem_material = wasm_execute(
-            gem_material.nft_contract.clone(),
-            &Cw721ExecuteMsg::TransferNft {
-                recipient: env.contract.address.to_string(),
-                token_id: gem_material.nft_id.to_string(),
-            },
-            vec![],
-        )?;
-        res = res.add_message(transfer_gem_material);
+        if gem_material.nft_contract == config.shield_collection {
+            return Err(ContractError::InvalidCollection { collection: gem_material.nft_contract.to_string() });
+        }
+        lock_escrow(storage, gem_material, user, entry_id)?;
+    }
+    if let Some(ref shield_id) = shield_id {
+        let shield = GemInfo {
+            nft_id: shield_id.clone(),
+            nft_contract: config.shield_collection.clone(),
+        };
+        lock_escrow(storage, &shield, user, entry_id)?;
     }
 
     // Add the user to the queue
     let user_in_queue = UserInfo {
-        user_addr: info.sender.clone(),
-        gem_base: gem_base.clone(),
-        gem_materials: gem_materials.clone(),
+        user_addr: user.clone(),
+        gem_base,
+        gem_materials,
         shield_id,
         timestamp: env.block.time,
+        job_id: None,
     };
+    push_queue_entry(storage, entry_id, &user_in_queue)?;
+    CURRENT_QUEUE_ID.save(storage, &(entry_id + 1))?;
 
-    // Add the user to the queue
-    USERS_IN_QUEUE.push_back(deps.storage, &user_in_queue)?;
-
-    // // Burn the gem_base, gem_materials NFTs
-    // let burn_gem_base = wasm_execute(
-    //     gem_base.nft_contract.clone(),
-    //     &Cw721ExecuteMsg::Burn {
-    //         token_id: gem_base.nft_id.to_string(),
-    //     },
-    //     vec![],
-    // )?;
-
-    // res = res.add_message(burn_gem_base);
-
-    // Burn the gem_materials NFTs
-    for gem_material in gem_materials {
-        let burn_gem_material = wasm_execute(
-            gem_material.nft_contract.clone(),
-            &Cw721ExecuteMsg::Burn {
-                token_id: gem_material.nft_id.to_string(),
-            },
-            vec![],
-        )?;
-        res = res.add_message(burn_gem_material);
-    }
-
-    // // Mint the new gem NFT
-    // let extension = GemMetadata {
-    //     color: "red".to_string(),
-    //     level: 1,
-    // };
-
-    // // Mint the new gem NFT from auragon_collection with token id increment by 1
-    // latest_token_id += 1;
-
-    // // Mint the new gem NFT from auragon_collection
-    // let mint_gem = wasm_execute(
-    //     auragon_collection.to_string(),
-    //     &Cw721BaseExecuteMsg::Mint::<GemMetadata, Empty> {
-    //         token_id: latest_token_id.to_string(),
-    //         owner: info.sender.to_string(),
-    //         token_uri: None,
-    //         extension,
-    //     },
-    //     vec![],
-    // )?;
+    Ok(entry_id)
+}
 
-    // res = res.add_message(mint_gem);
+/// add an entry at the back of the queue
+fn push_queue_entry(storage: &mut dyn Storage, entry_id: u64, user_info: &UserInfo) -> StdResult<()> {
+    users_in_queue().save(storage, entry_id, user_info)?;
+    QUEUE_LENGTH.update(storage, |length| -> StdResult<_> { Ok(length + 1) })?;
+    QUEUE_PAGES.update(storage, entry_id / QUEUE_PAGE_SIZE, |count| -> StdResult<_> {
+        Ok(count.unwrap_or_default() + 1)
+    })?;
+    Ok(())
+}
 
-    // Update the latest token id
-    // LATEST_TOKEN_ID.save(deps.storage, &latest_token_id)?;
+/// take an entry out of the queue, wherever it is
+fn remove_queue_entry(storage: &mut dyn Storage, entry_id: u64) -> StdResult<()> {
+    if !users_in_queue().has(storage, entry_id) {
+        return Ok(());
+    }
+    users_in_queue().remove(storage, entry_id)?;
+    QUEUE_LENGTH.update(storage, |length| -> StdResult<_> { Ok(length.saturating_sub(1)) })?;
+    let page = entry_id / QUEUE_PAGE_SIZE;
+    match QUEUE_PAGES.load(storage, page)?.saturating_sub(1) {
+        0 => QUEUE_PAGES.remove(storage, page),
+        count => QUEUE_PAGES.save(storage, page, &count)?,
+    }
+    Ok(())
+}
 
-    Ok(res.add_attributes(vec![
-        ("action", "join_queue"),
-        ("user", info.sender.as_str()),
-    ]))
+/// number of entries ahead of an entry id, counted by page and then within its page
+fn entries_ahead(storage: &dyn Storage, entry_id: u64) -> StdResult<u32> {
+    let page = entry_id / QUEUE_PAGE_SIZE;
+    let mut ahead = 0;
+    for count in QUEUE_PAGES.range(storage, None, Some(Bound::exclusive(page)), Order::Ascending) {
+        ahead += count?.1;
+    }
+    let in_page = users_in_queue()
+        .keys(storage, Some(Bound::inclusive(page * QUEUE_PAGE_SIZE)), Some(Bound::exclusive(entry_id)), Order::Ascending)
+        .count();
+    Ok(ahead + in_page as u32)
 }
 
 pub fn execute_forge_gem(
     deps: DepsMut,
     env: Env,
     info: MessageInfo,
-    request_forge_id: String,
-    forge_gem_list: Vec<RequestForgeGemInfo>,
+    entry_ids: Vec<u64>,
 ) -> Result<Response, ContractError> {
     // Load the config
     let config = CONFIG.load(deps.storage)?;
-    // Load auragon uri
-    let auragon_uri = AURAGON_URI.load(deps.storage)?;
-    // Load the nois_proxy
-    let nois_proxy = config.nois_proxy;
-
-    // get dragon_collection
-    let dragon_collection = config.dragon_collection;
-
-    // Load the auragon_collection
-    let auragon_collection = config.auragon_collection;
-
-    // Load the shield_collection
-    let shield_collection = config.shield_collection;
-
-    // Get user address list from forge_gem_list
-    let user_addr_list: Vec<Addr> = forge_gem_list.iter().map(|forge_gem| {
-        forge_gem.user_addr.clone()
-    }).collect();
-
-    // Get gem_base list from forge_gem_list
-    let gem_base_list: Vec<GemInfo> = forge_gem_list.iter().map(|forge_gem| {
-        forge_gem.gem_base.clone()
-    }).collect();
-
-    // Check owner of gem_base should be the same as the user_addr
-    for (user_addr, gem_base) in user_addr_list.iter().zip(gem_base_list.iter()) {
-        let query_msg = Cw721QueryMsg::OwnerOf {
-            token_id: gem_base.nft_id.clone(),
-            include_expired: None,
-        };
-
-        let query_response: StdResult<cw721::OwnerOfResponse> =
-        deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-            contract_addr: gem_base.nft_contract.to_string(),
-            msg: to_json_binary(&query_msg).unwrap(),
-        }));
 
-        match query_response {
-            Ok(response) => {
-                if response.owner != user_addr.to_string() {
-                    return Err(ContractError::Unauthorized {});
-                }
-            },
-            Err(_) => return Err(ContractError::Unauthorized {}),
-        }
-    }
-
-    // get gem_base nft_contract
-    let gem_base_nft_contract_user_list: Vec<Addr> = forge_gem_list.iter().map(|forge_gem| {
-        forge_gem.gem_base.nft_contract.clone()
-    }).collect();
-    // get gem_base_nft_id_user_list
-    let gem_base_nft_id_user_list: Vec<String> = forge_gem_list.iter().map(|forge_gem| {
-        forge_gem.gem_base.nft_id.clone()
-    }).collect();
-
-    // loop through gem_base_nft_contract_user_list and gem_base_nft_id_user_list and get token uri if contract is dragon_collection and get color and star if contract is auragon_collection
-    let gem_base_nft_color_and_star_user_list: Vec<String>
-        = gem_base_nft_contract_user_list.iter().zip(gem_base_nft_id_user_list.iter()).map(|(contract, id)| {
-        if contract == &dragon_collection {
-            let query_msg = Cw721QueryMsg::NftInfo { token_id: id.clone() };
-
-            let query_response: StdResult<cw721::NftInfoResponse<Metadata>> =
-            deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-                contract_addr: contract.to_string(),
-                msg: to_json_binary(&query_msg).unwrap(),
-            }));
-
-            match query_response {
-                Ok(response) => {
-                    match response.token_uri.unwrap().as_str() {
-                        "ipfs://Qme1dXSRNSqYvVQSDEmoL6WHMLqrYajZkszYhbRGj2F2oa" => "white-1".to_string(),
-                        "ipfs://QmSp3iYpenTNr69g2EDSS128Vs1oRV2EHW8vakZ2Ro8G6P" => "blue-1".to_string(),
-                        "ipfs://QmQP3N4jxJKGXPx18PgrjdhGLqYjX2qtinZ4q4YBeQhpw7" => "gold-1".to_string(),
-                        "ipfs://QmTUy7E1UnLcbasfQap38kfxBAsFWzPfLmQimTh7pNw4QT" => "red-1".to_string(),
-                        _ => "".to_string()
-                    }
-                },
-                Err(_) => "".to_string()
-            }
-        } else {
-            let query_msg = Cw721QueryMsg::NftInfo { token_id: id.clone() };
-
-            let query_response: StdResult<cw721::NftInfoResponse<Metadata>> =
-            deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-                contract_addr: contract.to_string(),
-                msg: to_json_binary(&query_msg).unwrap(),
-            }));
-
-            match query_response {
-                Ok(response) => {
-                    match response.extension.attributes.as_ref().unwrap()[0].value.as_str() {
-                        "white" => "white-".to_string() + &response.extension.attributes.unwrap()[1].value,
-                        "blue" => "blue-".to_string() + &response.extension.attributes.unwrap()[1].value,
-                        "gold" => "gold-".to_string() + &response.extension.attributes.unwrap()[1].value,
-                        "red" => "red-".to_string() + &response.extension.attributes.unwrap()[1].value,
-                        _ => "".to_string()
-                    }
-                },
-                Err(_) => "".to_string()
-            }
-        }
-    }).collect();
+    ensure_can_forge(deps.storage, &config, &info.sender, &entry_ids)?;
 
-    // Get gem_materials list from forge_gem_list
-    let gem_materials_list: Vec<Vec<GemInfo>> = forge_gem_list.iter().map(|forge_gem| {
-        forge_gem.gem_materials.clone()
-    }).collect();
+    request_forge(deps, &env, &config, &info.sender, entry_ids, Payment::Native(info.funds))
+}
 
-    // Check owner of gem_materials should be the same as the user_addr
-    for (user_addr, gem_materials) in user_addr_list.iter().zip(gem_materials_list.iter()) {
-        for gem_material in gem_materials {
-            let query_msg = Cw721QueryMsg::OwnerOf {
-                token_id: gem_material.nft_id.clone(),
-                include_expired: None,
-            };
+/// the owner can forge gems for the users in the queue, a user can only forge their own entries
+fn ensure_can_forge(
+    storage: &dyn Storage,
+    config: &Config,
+    sender: &Addr,
+    entry_ids: &[u64],
+) -> Result<(), ContractError> {
+    if ensure_owner(config, sender).is_ok() {
+        return Ok(());
+    }
+    for entry_id in entry_ids {
+        let user_info = users_in_queue()
+            .may_load(storage, *entry_id)?
+            .ok_or(ContractError::QueueEntryNotFound { entry_id: *entry_id })?;
+        if user_info.user_addr != sender {
+            return Err(ContractError::Unauthorized {});
+        }
+    }
+    Ok(())
+}
 
-            let query_response: StdResult<cw721::OwnerOfResponse> =
-            deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-                contract_addr: gem_material.nft_contract.to_string(),
-                msg: to_json_binary(&query_msg).unwrap(),
-            }));
+/// funds paying the forge fees of a job
+enum Payment {
+    Native(Vec<Coin>),
+    // tokens sent with the prices of the token
+    Cw20(Cw20CoinVerified, AcceptedToken),
+}
 
-            match query_response {
-                Ok(response) => {
-                    if response.owner != user_addr.to_string() {
-                        return Err(ContractError::Unauthorized {});
-                    }
-                },
-                Err(_) => return Err(ContractError::Unauthorized {}),
-            }
+/// request the randomness to forge the queue entries, the payer pays the forge fees with `payment`
+fn request_forge(
+    deps: DepsMut,
+    env: &Env,
+    config: &Config,
+    payer: &Addr,
+    entry_ids: Vec<u64>,
+    payment: Payment,
+) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.storage, "forge", |pause_state| pause_state.forge)?;
+
+    let job_id = next_job_id(deps.storage)?;
+
+    // Load the queue entries, an entry can only be forged once at a time
+    let mut entries: Vec<(u64, UserInfo)> = vec![];
+    for entry_id in entry_ids {
+        let user_info = users_in_queue()
+            .may_load(deps.storage, entry_id)?
+            .ok_or(ContractError::QueueEntryNotFound { entry_id })?;
+        if let Some(job_id) = user_info.job_id {
+            return Err(ContractError::QueueEntryInJob { entry_id, job_id });
         }
+        if entries.iter().any(|(id, _)| *id == entry_id) {
+            return Err(ContractError::QueueEntryInJob { entry_id, job_id: job_id.clone() });
+        }
+        // A user can only be forged once per job, the results are recorded per job and user
+        if entries.iter().any(|(_, entry)| entry.user_addr == user_info.user_addr) {
+            return Err(ContractError::DuplicateParticipant { user: user_info.user_addr.to_string() });
+        }
+        entries.push((entry_id, user_info));
     }
 
-    // Get shield_id list from forge_gem_list
-    let shield_id_list: Vec<Option<String>> = forge_gem_list.iter().map(|forge_gem| {
-        forge_gem.shield_id.clone()
-    }).collect();
+    // Calculate the success rate of each user from the work power of the materials and the work load of the gem_base
+    let mut participants: Vec<JobParticipant> = vec![];
+    let mut fees: Vec<Coin> = vec![];
+    for (entry_id, mut user_info) in entries {
+        // The gems of the entry must be held by this contract until the job is settled
+        for gem in entry_gems(config, &user_info) {
+            ensure_escrowed(deps.storage, &gem, entry_id)?;
+        }
 
-    // Check owner of shield_id should be the same as the user_addr
-    for (user_addr, shield_id) in user_addr_list.iter().zip(shield_id_list.iter()) {
-        if let Some(ref shield_id) = shield_id {
-            let query_msg = Cw721QueryMsg::OwnerOf {
-                token_id: shield_id.clone(),
-                include_expired: None,
-            };
+        // get color and star of gem_base from the token uri if contract is dragon_collection or from the attributes if contract is auragon_collection
+        let (gem_base_kind, base_tier) = upgradable_tier(&deps.querier, deps.storage, config, &user_info.gem_base)?;
 
-            let query_response: StdResult<cw721::OwnerOfResponse> =
-            deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-                contract_addr: shield_collection.to_string(),
-                msg: to_json_binary(&query_msg).unwrap(),
-            }));
+        let mut material_work_power = Decimal::zero();
+        for gem_material in &user_info.gem_materials {
+            let material_kind = query_gem_kind(&deps.querier, deps.storage, &config.dragon_collection, gem_material)?;
+            material_work_power += gem_work_power(deps.storage, &material_kind)?;
+        }
 
-            match query_response {
-                Ok(response) => {
-                    if response.owner != user_addr.to_string() {
-                        return Err(ContractError::Unauthorized {});
-                    }
-                },
-                Err(_) => return Err(ContractError::Unauthorized {}),
+        // the work load to reach the next star
+        let work_load = base_tier
+            .work_loads
+            .get(usize::from(gem_base_kind.star).wrapping_sub(1))
+            .ok_or(ContractError::StarOutOfRange {})?;
+        add_coins(&mut fees, &base_tier.forge_fees);
+
+        // Mark the entry as being forged so that it cannot leave the queue
+        user_info.job_id = Some(job_id.clone());
+        users_in_queue().save(deps.storage, entry_id, &user_info)?;
+
+        participants.push(JobParticipant {
+            entry_id: Some(entry_id),
+            user_addr: user_info.user_addr,
+            gem_base: Some(user_info.gem_base),
+            gem_base_kind,
+            gem_materials: user_info.gem_materials,
+            shield_id: user_info.shield_id,
+            success_rate: compute_success_rate(material_work_power, *work_load),
+        });
+    }
+
+    // The fees are held for the job, the rest of the funds is returned to the payer
+    let mut refunds: Vec<CosmosMsg> = vec![];
+    let (fees, cw20_fees) = match payment {
+        Payment::Native(funds) => {
+            if let Some(refund) = charge_funds(funds, &fees, payer)? {
+                refunds.push(refund.into());
+            }
+            (fees, vec![])
+        }
+        // the token replaces the native fees, it must have a fee for the color of every gem_base
+        Payment::Cw20(sent, accepted_token) => {
+            let mut fee = Uint128::zero();
+            for participant in &participants {
+                fee += accepted_token
+                    .forge_fee(&participant.gem_base_kind.color)
+                    .ok_or(ContractError::UnacceptedToken { token: sent.address.to_string() })?;
+            }
+            let excess = sent
+                .amount
+                .checked_sub(fee)
+                .map_err(|_| ContractError::InsufficentFund {})?;
+            if !excess.is_zero() {
+                refunds.push(transfer_cw20_msg(&sent.address, excess, payer)?.into());
             }
+            let cw20_fees = if fee.is_zero() {
+                vec![]
+            } else {
+                vec![Cw20CoinVerified { address: sent.address, amount: fee }]
+            };
+            (vec![], cw20_fees)
         }
+    };
+
+    let msg_make_randomess = request_randomness_msg(deps.storage, config, &job_id)?;
+    add_balances(deps.storage, PENDING_FEES, &fees)?;
+    for fee in &cw20_fees {
+        add_balance(deps.storage, CW20_PENDING_FEES, &fee.address, fee.amount)?;
     }
 
-    let funds = info.funds;
+    // save job for mapping callback response to request
+    let random_job = RandomJob {
+        participants,
+        timestamp: env.block.time,
+        payer: Some(payer.clone()),
+        fees,
+        cw20_fees,
+    };
+
+    RANDOM_JOBS.save(deps.storage, job_id.clone(), &random_job)?;
+    Ok(Response::new()
+        .set_data(to_json_binary(&ForgeGemResponse { job_id: job_id.clone() })?)
+        .add_message(msg_make_randomess)
+        .add_messages(refunds)
+        .add_attribute("action", "forge_gem")
+        .add_attribute("job_id", job_id))
+}
 
-    let mut res = Response::new();
+/// jobs are numbered in the order they are requested, skipping the ids taken by jobs named by the callers before
+fn next_job_id(storage: &mut dyn Storage) -> StdResult<String> {
+    let mut job_number = CURRENT_JOB_ID.may_load(storage)?.unwrap_or_default();
+    while RANDOM_JOBS.has(storage, job_number.to_string()) || RANDOM_MINTS.has(storage, job_number.to_string()) {
+        job_number += 1;
+    }
+    CURRENT_JOB_ID.save(storage, &(job_number + 1))?;
+    Ok(job_number.to_string())
+}
+
+/// take the fees out of the funds, returns the message sending the rest back to the payer
+fn charge_funds(funds: Vec<Coin>, fees: &[Coin], payer: &Addr) -> Result<Option<BankMsg>, ContractError> {
+    let mut excess = funds;
+    for fee in fees {
+        let paid = excess
+            .iter_mut()
+            .find(|coin| coin.denom == fee.denom)
+            .ok_or(ContractError::InsufficentFund {})?;
+        paid.amount = paid
+            .amount
+            .checked_sub(fee.amount)
+            .map_err(|_| ContractError::InsufficentFund {})?;
+    }
+    excess.retain(|coin| !coin.amount.is_zero());
+    if excess.is_empty() {
+        return Ok(None);
+    }
+    Ok(Some(BankMsg::Send {
+        to_address: payer.to_string(),
+        amount: excess,
+    }))
+}
 
-    // Get user_user_success_rate_list from forge_gem_list
-    let user_success_rate_list: Vec<(Addr, u32)> = forge_gem_list.iter().map(|forge_gem| {
-        (forge_gem.user_addr.clone(), forge_gem.success_rate.parse::<u32>().unwrap())
-    }).collect();
+/// request the randomness of a job from the Nois proxy
+fn request_randomness_msg(storage: &mut dyn Storage, config: &Config, job_id: &str) -> Result<WasmMsg, ContractError> {
+    // The randomness is paid out of the prepaid Nois balance, the proxy has no price query so the price is configured
+    for price in &config.nois_price {
+        let balance = NOIS_BALANCE.may_load(storage, &price.denom)?.unwrap_or_default();
+        if balance < price.amount {
+            return Err(ContractError::InsufficientNoisBalance { denom: price.denom.clone() });
+        }
+    }
+    sub_balances(storage, NOIS_BALANCE, &config.nois_price)?;
 
-    // Make randomness request message to NOIS proxy contract
-    let msg_make_randomess = CosmosMsg::Wasm(WasmMsg::Execute {
-        contract_addr: nois_proxy.into(),
+    Ok(WasmMsg::Execute {
+        contract_addr: config.nois_proxy.to_string(),
         msg: to_json_binary(&ProxyExecuteMsg::GetNextRandomness {
-            job_id: request_forge_id.clone(),
+            job_id: job_id.to_string(),
         })?,
-        funds,
-    });
+        funds: config.nois_price.clone(),
+    })
+}
 
-    res = res.add_message(msg_make_randomess);
+pub fn execute_mint_random_gem(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
 
-    // save job for mapping callback response to request
-    let random_job = RandomJob {
-        gem_base_nft_color_and_star_user_list,
-        user_success_rate_list,
+    ensure_not_paused(deps.storage, "mint", |pause_state| pause_state.mint)?;
+
+    let sale = RANDOM_GEM_SALE
+        .may_load(deps.storage)?
+        .ok_or(ContractError::RandomGemSaleClosed {})?;
+    if gem_ratio_weights(deps.storage)?.is_empty() {
+        return Err(ContractError::RandomGemSaleClosed {});
+    }
+
+    // The gem counts against the supply from the request, it is given back if the job expires
+    let sold = RANDOM_GEMS_SOLD.may_load(deps.storage)?.unwrap_or_default();
+    if sale.supply_limit.is_some_and(|supply_limit| sold >= supply_limit) {
+        return Err(ContractError::RandomGemSoldOut {});
+    }
+    RANDOM_GEMS_SOLD.save(deps.storage, &(sold + 1))?;
+
+    // The price is held for the job, the rest of the funds is returned to the buyer
+    let refund = charge_funds(info.funds, &sale.price, &info.sender)?;
+
+    let job_id = next_job_id(deps.storage)?;
+    let msg_make_randomess = request_randomness_msg(deps.storage, &config, &job_id)?;
+    add_balances(deps.storage, PENDING_FEES, &sale.price)?;
+
+    let random_mint = RandomMint {
+        owner: info.sender.clone(),
         timestamp: env.block.time,
+        fees: sale.price,
     };
+    RANDOM_MINTS.save(deps.storage, job_id.clone(), &random_mint)?;
 
-    RANDOM_JOBS.save(deps.storage, request_forge_id.clone(), &random_job)?;
-    Ok(res)
+    let mut res = Response::new()
+        .set_data(to_json_binary(&ForgeGemResponse { job_id: job_id.clone() })?)
+        .add_message(msg_make_randomess);
+    if let Some(refund) = refund {
+        res = res.add_message(refund);
+    }
+    Ok(res
+        .add_attribute("action", "mint_random_gem")
+        .add_attribute("owner", info.sender)
+        .add_attribute("job_id", job_id))
 }
 
-// pub fn execute_forge_gem_type_1(
-//     deps: DepsMut,
-//     env: Env,
-//     info: MessageInfo,
-//     user_list: Vec<UserInfo>,
-// ) -> Result<Response, ContractError> {
-//     // Load the config
-//     let config = CONFIG.load(deps.storage)?;
-//     // Load auragon uri
-//     let auragon_uri = AURAGON_URI.load(deps.storage)?;
-//     // Load the nois_proxy
-//     let nois_proxy = config.nois_proxy;
+/// (color, weight) of the colors a random gem is drawn from, the retired tiers and the ones with a zero ratio are left out
+fn gem_ratio_weights(storage: &dyn Storage) -> Result<Vec<(String, u32)>, ContractError> {
+    let mut weights = vec![];
+    for item in GEM_TIERS.range(storage, None, None, Order::Ascending) {
+        let (color, tier) = item?;
+        // the ratios are weighted in basis points like the success rates
+        let weight = Uint128::from(SUCCESS_RATE_PRECISION).mul_floor(tier.gem_ratio);
+        let weight = u32::try_from(weight.u128()).unwrap_or(u32::MAX);
+        if weight > 0 && !tier.retired {
+            weights.push((color, weight));
+        }
+    }
+    Ok(weights)
+}
 
-//     // Load the auragon_collection
-//     let auragon_collection = config.auragon_collection;
+/// mint the random gem of a job with its randomness and remove the job
+fn settle_random_mint(
+    storage: &mut dyn Storage,
+    job_id: &str,
+    randomness: [u8; 32],
+    random_mint: RandomMint,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(storage)?;
 
-//     // Load the shield_collection
-//     let shield_collection = config.shield_collection;
+    let weights = gem_ratio_weights(storage)?;
+    let color = select_from_weighted(randomness, &weights).map_err(|_| ContractError::RandomGemSaleClosed {})?;
+    let tier = load_gem_tier(storage, &color)?;
 
-//     // Load the latest token id
-//     let mut latest_token_id = AURAGON_LATEST_TOKEN_ID.load(deps.storage)?;
+    let latest_token_id = AURAGON_LATEST_TOKEN_ID.load(storage)? + 1;
+    let mint_gem = mint_gem_msg(&tier, &config.auragon_collection, latest_token_id, &random_mint.owner, &color, 1)?;
+    AURAGON_LATEST_TOKEN_ID.save(storage, &latest_token_id)?;
 
-//     let job_id = format!("{}/{}", env.block.time, info.sender);
+    // the price is earned once the gem is minted
+    sub_balances(storage, PENDING_FEES, &random_mint.fees)?;
+    add_balances(storage, TREASURY, &random_mint.fees)?;
+    RANDOM_MINTS.remove(storage, job_id.to_string());
 
-//     let mut funds = info.funds;
+    Ok(Response::new()
+        .add_message(mint_gem)
+        .add_attribute("owner", random_mint.owner)
+        .add_attribute("color", color)
+        .add_attribute("token_id", latest_token_id.to_string()))
+}
 
-//     let mut res = Response::new();
+pub fn execute_fuse_gems(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    gems: Vec<GemInfo>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
 
-//     // A list of user and their success rate
-//     let user_success_rate_list: Vec<(Addr, u32)> = convert_to_user_success_rate_list(&deps, user_list.clone());
+    ensure_not_paused(deps.storage, "mint", |pause_state| pause_state.mint)?;
 
-//     // Make randomness request message to NOIS proxy contract
-//     let msg_make_randomess = CosmosMsg::Wasm(WasmMsg::Execute {
-//         contract_addr: nois_proxy.into(),
-//         msg: to_json_binary(&ProxyExecuteMsg::GetNextRandomness {
-//             job_id: job_id.clone(),
-//         })?,
-//         funds,
-//     });
+    let mut gem_kinds = vec![];
+    for (index, gem) in gems.iter().enumerate() {
+        if gems[..index].contains(gem) {
+            return Err(ContractError::InvalidFusion { reason: format!("gem {} is given more than once", gem.nft_id) });
+        }
+        ensure_deposited(deps.storage, &config, gem, &info.sender)?;
+        gem_kinds.push(query_gem_kind(&deps.querier, deps.storage, &config.dragon_collection, gem)?);
+    }
 
-//     res = res.add_message(msg_make_randomess);
+    let gem_kind = gem_kinds
+        .first()
+        .cloned()
+        .ok_or(ContractError::InvalidFusion { reason: "no gem given".to_string() })?;
+    if gem_kinds.iter().any(|other| *other != gem_kind) {
+        return Err(ContractError::InvalidFusion { reason: "gems must be of the same color and star".to_string() });
+    }
+    let rule = FUSION_RULES
+        .may_load(deps.storage, (&gem_kind.color, gem_kind.star))?
+        .ok_or(ContractError::NoFusionRule {
+            color: gem_kind.color.clone(),
+            star: gem_kind.star,
+        })?;
+    if gems.len() != rule.gem_count as usize {
+        return Err(ContractError::InvalidFusion {
+            reason: format!("{} gems are fused at once, {} given", rule.gem_count, gems.len()),
+        });
+    }
 
-//     // save job for mapping callback response to request
-//     let random_job = RandomJob {
-//         user_success_rate_list,
-//         timestamp: env.block.time,
-//     };
+    // The tier of the result color may be retired since the rule was set
+    let color = rule.result_color.clone();
+    let tier = load_gem_tier(deps.storage, &color)?;
+    if tier.retired {
+        return Err(ContractError::GemTierRetired { color });
+    }
+    validate_gem(deps.storage, &GemKind { color: color.clone(), star: rule.result_star })?;
 
-//     RANDOM_JOBS.save(deps.storage, job_id.clone(), &random_job)?;
+    let mut burn_msgs = vec![];
+    for gem in &gems {
+        ESCROWS.remove(deps.storage, (&gem.nft_contract, &gem.nft_id));
+        burn_msgs.push(burn_nft_msg(gem)?);
+    }
+    let latest_token_id = AURAGON_LATEST_TOKEN_ID.load(deps.storage)? + 1;
+    let mint_gem = mint_gem_msg(&tier, &config.auragon_collection, latest_token_id, &info.sender, &color, rule.result_star)?;
+    AURAGON_LATEST_TOKEN_ID.save(deps.storage, &latest_token_id)?;
 
-//     // Loop through user_list and forge the gem
-//     for user in user_list {
-//         // Mint the new gem NFT
-//         let gem_trait = GemMetadata {
-//             color: "red".to_string(),
-//             star: 1,
-//         };
+    Ok(Response::new()
+        .add_messages(burn_msgs)
+        .add_message(mint_gem)
+        .add_attribute("action", "fuse_gems")
+        .add_attribute("owner", info.sender)
+        .add_attribute("color", color)
+        .add_attribute("star", rule.result_star.to_string())
+        .add_attribute("token_id", latest_token_id.to_string()))
+}
 
-//         let extension = Metadata {
-//             attributes: vec![
-//                 Trait {
-//                     display_type: None,
-//                     trait_type: "color".to_string(),
-//                     value: gem_trait.color.clone(),
-//                 },
-//                 Trait {
-//                     display_type: None,
-//                     trait_type: "star".to_string(),
-//                     value: gem_trait.star.to_string(),
-//                 }
-//             ].into(),
-//             ..Default::default()
-//         };
+/// make sure the gem is deposited by `user` and not used by a queue entry, shields are not accepted
+fn ensure_deposited(storage: &dyn Storage, config: &Config, gem: &GemInfo, user: &Addr) -> Result<(), ContractError> {
+    if gem.nft_contract == config.shield_collection {
+        return Err(ContractError::InvalidCollection { collection: gem.nft_contract.to_string() });
+    }
+    let escrow = ESCROWS
+        .may_load(storage, (&gem.nft_contract, &gem.nft_id))?
+        .ok_or(ContractError::GemNotEscrowed {
+            nft_contract: gem.nft_contract.to_string(),
+            nft_id: gem.nft_id.clone(),
+        })?;
+    if escrow.owner != *user {
+        return Err(ContractError::Unauthorized {});
+    }
+    if let Some(entry_id) = escrow.entry_id {
+        return Err(ContractError::GemInUse {
+            nft_contract: gem.nft_contract.to_string(),
+            nft_id: gem.nft_id.clone(),
+            entry_id,
+        });
+    }
+    Ok(())
+}
 
-//         let token_uri = match gem_trait.color.as_str() {
-//             "white" => auragon_uri.white[gem_trait.star as usize].clone(),
-//             "blue" => auragon_uri.blue[gem_trait.star as usize].clone(),
-//             "gold" => auragon_uri.gold[gem_trait.star as usize].clone(),
+pub fn execute_salvage_gem(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    gems: Vec<GemInfo>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    let salvage_config = SALVAGE_CONFIG
+        .may_load(deps.storage)?
+        .ok_or(ContractError::SalvageClosed {})?;
+    if gems.is_empty() {
+        return Err(ContractError::NoGemToSalvage {});
+    }
+
+    // The fragments are given for the work power of all the gems at once
+    let mut work_power = Decimal::zero();
+    let mut burn_msgs = vec![];
+    for gem in &gems {
+        ensure_deposited(deps.storage, &config, gem, &info.sender)?;
+        let gem_kind = query_gem_kind(&deps.querier, deps.storage, &config.dragon_collection, gem)?;
+        work_power += gem_work_power(deps.storage, &gem_kind)?;
+        ESCROWS.remove(deps.storage, (&gem.nft_contract, &gem.nft_id));
+        burn_msgs.push(burn_nft_msg(gem)?);
+    }
+    let fragments = Uint128::one().mul_floor(work_power * salvage_config.fragments_per_work_power);
+    add_balance(deps.storage, FRAGMENTS, &info.sender, fragments)?;
+
+    Ok(Response::new()
+        .add_messages(burn_msgs)
+        .add_attribute("action", "salvage_gem")
+        .add_attribute("owner", info.sender)
+        .add_attribute("fragments", fragments))
+}
+
+pub fn execute_craft_shield(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    let salvage_config = SALVAGE_CONFIG
+        .may_load(deps.storage)?
+        .ok_or(ContractError::SalvageClosed {})?;
+
+    let balance = FRAGMENTS.may_load(deps.storage, &info.sender)?.unwrap_or_default();
+    if balance < salvage_config.shield_cost {
+        return Err(ContractError::InsufficientFragments {
+            fragments: salvage_config.shield_cost,
+            balance,
+        });
+    }
+    sub_balance(deps.storage, FRAGMENTS, &info.sender, salvage_config.shield_cost)?;
+    let (mint_gem, token_id) = mint_shield_msg(deps.storage, &config, info.sender.as_str())?;
+
+    Ok(Response::new()
+        .add_message(mint_gem)
+        .add_attribute("action", "craft_shield")
+        .add_attribute("token_id", token_id)
+        .add_attribute("owner", info.sender))
+}
+
+pub fn execute_leave_queue(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    entry_id: u64,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    let user_info = users_in_queue()
+        .may_load(deps.storage, entry_id)?
+        .ok_or(ContractError::QueueEntryNotFound { entry_id })?;
+
+    // Only the user of the entry, or the owner in an emergency
+    if info.sender != user_info.user_addr {
+        ensure_owner(&config, &info.sender)?;
+    }
+
+    // The gems are needed to settle the job
+    if let Some(job_id) = user_info.job_id {
+        return Err(ContractError::QueueEntryInJob { entry_id, job_id });
+    }
+
+    let refunds = refund_entry(deps.storage, &config, entry_id, &user_info)?;
+
+    Ok(Response::new()
+        .add_messages(refunds)
+        .add_attribute("action", "leave_queue")
+        .add_attribute("entry_id", entry_id.to_string())
+        .add_attribute("user", user_info.user_addr))
+}
+
+pub fn execute_expire_job(
+    deps: DepsMut,
+    env: Env,
+    _info: MessageInfo,
+    job_id: String,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    // A random gem is not minted, its price is returned and it goes back to the supply
+    if let Some(random_mint) = RANDOM_MINTS.may_load(deps.storage, job_id.clone())? {
+        ensure_expired(deps.storage, &env, &config, &job_id, random_mint.timestamp)?;
+        BUFFERED_RANDOMNESS.remove(deps.storage, job_id.clone());
+        sub_balances(deps.storage, PENDING_FEES, &random_mint.fees)?;
+        RANDOM_GEMS_SOLD.update(deps.storage, |sold| -> StdResult<_> { Ok(sold.saturating_sub(1)) })?;
+        RANDOM_MINTS.remove(deps.storage, job_id.clone());
+        let mut res = Response::new();
+        if !random_mint.fees.is_empty() {
+            res = res.add_message(BankMsg::Send {
+                to_address: random_mint.owner.to_string(),
+                amount: random_mint.fees,
+            });
+        }
+        return Ok(res
+            .add_attribute("action", "expire_job")
+            .add_attribute("job_id", job_id));
+    }
+
+    let random_job = RANDOM_JOBS
+        .may_load(deps.storage, job_id.clone())?
+        .ok_or(ContractError::RandomJobNotFound {})?;
+    ensure_expired(deps.storage, &env, &config, &job_id, random_job.timestamp)?;
+    // The randomness buffered for the job is not used once it expired
+    BUFFERED_RANDOMNESS.remove(deps.storage, job_id.clone());
+
+    // The entries of the job leave the queue with their gems, a late callback finds no job to settle
+    let mut res = Response::new();
+    for participant in &random_job.participants {
+        let Some(entry_id) = participant.entry_id else {
+            continue;
+        };
+        if let Some(user_info) = users_in_queue().may_load(deps.storage, entry_id)? {
+            let refunds = refund_entry(deps.storage, &config, entry_id, &user_info)?;
+            res = res
+                .add_messages(refunds)
+                .add_attribute("refunded_entry_id", entry_id.to_string());
+        }
+    }
+    sub_balances(deps.storage, PENDING_FEES, &random_job.fees)?;
+    for fee in &random_job.cw20_fees {
+        sub_balance(deps.storage, CW20_PENDING_FEES, &fee.address, fee.amount)?;
+    }
+    if let Some(payer) = random_job.payer {
+        if !random_job.fees.is_empty() {
+            res = res.add_message(BankMsg::Send {
+                to_address: payer.to_string(),
+                amount: random_job.fees,
+            });
+        }
+        for fee in &random_job.cw20_fees {
+            res = res.add_message(transfer_cw20_msg(&fee.address, fee.amount, &payer)?);
+        }
+    }
+    RANDOM_JOBS.remove(deps.storage, job_id.clone());
+
+    Ok(res
+        .add_attribute("action", "expire_job")
+        .add_attribute("job_id", job_id))
+}
+
+/// the randomness of a job may still come back, or its buffered randomness be settled, until the timeout passed
+fn ensure_expired(
+    storage: &dyn Storage,
+    env: &Env,
+    config: &Config,
+    job_id: &str,
+    requested_at: Timestamp,
+) -> Result<(), ContractError> {
+    let expires_at = requested_at.plus_seconds(config.job_timeout);
+    if env.block.time < expires_at {
+        if BUFFERED_RANDOMNESS.has(storage, job_id.to_string()) {
+            return Err(ContractError::JobSettlementPending { job_id: job_id.to_string() });
+        }
+        return Err(ContractError::JobNotExpired { job_id: job_id.to_string(), expires_at });
+    }
+    Ok(())
+}
+
+pub fn execute_withdraw_nft(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    nft_contract: String,
+    token_id: String,
+) -> Result<Response, ContractError> {
+    let gem = GemInfo {
+        nft_id: token_id,
+        nft_contract: addr_validate(deps.api, &nft_contract)?,
+    };
+    let escrow = ESCROWS
+        .may_load(deps.storage, (&gem.nft_contract, &gem.nft_id))?
+        .ok_or(ContractError::GemNotEscrowed {
+            nft_contract: gem.nft_contract.to_string(),
+            nft_id: gem.nft_id.clone(),
+        })?;
+
+    ensure_eq!(info.sender, escrow.owner, ContractError::Unauthorized {});
+
+    // Gems of a queue entry are returned by leaving the queue
+    if let Some(entry_id) = escrow.entry_id {
+        return Err(ContractError::GemInUse {
+            nft_contract: gem.nft_contract.to_string(),
+            nft_id: gem.nft_id,
+            entry_id,
+        });
+    }
+
+    ESCROWS.remove(deps.storage, (&gem.nft_contract, &gem.nft_id));
+
+    Ok(Response::new()
+        .add_message(transfer_nft_msg(&gem, &escrow.owner)?)
+        .add_attribute("action", "withdraw_nft")
+        .add_attribute("user", escrow.owner)
+        .add_attribute("nft_contract", gem.nft_contract)
+        .add_attribute("token_id", gem.nft_id))
+}
+
+// pub fn execute_forge_gem_type_1(
+//     deps: DepsMut,
+//     env: Env,
+//     info: MessageInfo,
+//     user_list: Vec<UserInfo>,
+// ) -> Result<Response, ContractError> {
+//     // Load the config
+//     let config = CONFIG.load(deps.storage)?;
+//     // Load auragon uri
+//     let auragon_uri = AURAGON_URI.load(deps.storage)?;
+//     // Load the nois_proxy
+//     let nois_proxy = config.nois_proxy;
+
+//     // Load the auragon_collection
+//     let auragon_collection = config.auragon_collection;
+
+//     // Load the shield_collection
+//     let shield_collection = config.shield_collection;
+
+//     // Load the latest token id
+//     let mut latest_token_id = AURAGON_LATEST_TOKEN_ID.load(deps.storage)?;
+
+//     let job_id = format!("{}/{}", env.block.time, info.sender);
+
+//     let mut funds = info.funds;
+
+//     let mut res = Response::new();
+
+//     // A list of user and their success rate
+//     let user_success_rate_list: Vec<(Addr, u32)> = convert_to_user_success_rate_list(&deps, user_list.clone());
+
+//     // Make randomness request message to NOIS proxy contract
+//     let msg_make_randomess = CosmosMsg::Wasm(WasmMsg::Execute {
+//         contract_addr: nois_proxy.into(),
+//         msg: to_json_binary(&ProxyExecuteMsg::GetNextRandomness {
+//             job_id: job_id.clone(),
+//         })?,
+//         funds,
+//     });
+
+//     res = res.add_message(msg_make_randomess);
+
+//     // save job for mapping callback response to request
+//     let random_job = RandomJob {
+//         user_success_rate_list,
+//         timestamp: env.block.time,
+//     };
+
+//     RANDOM_JOBS.save(deps.storage, job_id.clone(), &random_job)?;
+
+//     // Loop through user_list and forge the gem
+//     for user in user_list {
+//         // Mint the new gem NFT
+//         let gem_trait = GemKind {
+//             color: "red".to_string(),
+//             star: 1,
+//         };
+
+//         let extension = Metadata {
+//             attributes: vec![
+//                 Trait {
+//                     display_type: None,
+//                     trait_type: "color".to_string(),
+//                     value: gem_trait.color.clone(),
+//                 },
+//                 Trait {
+//                     display_type: None,
+//                     trait_type: "star".to_string(),
+//                     value: gem_trait.star.to_string(),
+//                 }
+//             ].into(),
+//             ..Default::default()
+//         };
+
+//         let token_uri = match gem_trait.color.as_str() {
+//             "white" => auragon_uri.white[gem_trait.star as usize].clone(),
+//             "blue" => auragon_uri.blue[gem_trait.star as usize].clone(),
+//             "gold" => auragon_uri.gold[gem_trait.star as usize].clone(),
 //             "red" => auragon_uri.red[gem_trait.star as usize].clone(),
 //             _ => "".to_string(),
 //         };
@@ -610,98 +1076,66 @@ pub fn update_collection(
 ) -> Result<Response, ContractError> {
     let config: Config = CONFIG.load(deps.storage)?;
 
-    // ensure_eq!(
-    //     info.sender,
-    //     config.nois_proxy,
-    //     ContractError::Unauthorized {}
-    // );
+    ensure_owner(&config, &info.sender)?;
+
+    let mut res = Response::new().add_attribute("action", "update_collection");
 
     if let Some(ref dragon_collection) = dragon_collection {
         CONFIG.update(deps.storage, |mut config| -> Result<_, ContractError> {
-            config.dragon_collection = addr_validate(deps.api, &dragon_collection)?;
+            config.dragon_collection = addr_validate(deps.api, dragon_collection)?;
             Ok(config)
         })?;
+        res = res.add_attribute("dragon_collection", dragon_collection);
     }
 
     if let Some(ref auragon_collection) = auragon_collection {
         CONFIG.update(deps.storage, |mut config| -> Result<_, ContractError> {
-            config.auragon_collection = addr_validate(deps.api, &auragon_collection)?;
+            config.auragon_collection = addr_validate(deps.api, auragon_collection)?;
             Ok(config)
         })?;
+        res = res.add_attribute("auragon_collection", auragon_collection);
     }
 
     if let Some(ref shield_collection) = shield_collection {
         CONFIG.update(deps.storage, |mut config| -> Result<_, ContractError> {
-            config.shield_collection = addr_validate(deps.api, &shield_collection)?;
+            config.shield_collection = addr_validate(deps.api, shield_collection)?;
             Ok(config)
         })?;
+        res = res.add_attribute("shield_collection", shield_collection);
     }
 
-    Ok(Response::new()
-        .add_attribute("action", "update_collection")
-        .add_attribute("dragon_collection", dragon_collection.unwrap_or_default())
-        .add_attribute("auragon_collection", auragon_collection.unwrap_or_default())
-        .add_attribute("shield_collection", shield_collection.unwrap_or_default()))
+    Ok(res)
 }
 
 pub fn mint_auragon_gem(
     deps: DepsMut,
-    env: Env,
+    _env: Env,
     info: MessageInfo,
     owner: String,
-    gem_trait: GemMetadata,
+    gem_trait: GemKind,
 ) -> Result<Response, ContractError> {
     let config: Config = CONFIG.load(deps.storage)?;
     // Load the latest token id
     let mut latest_token_id = AURAGON_LATEST_TOKEN_ID.load(deps.storage)?;
-    // Load auragon uri
-    let auragon_uri = AURAGON_URI.load(deps.storage)?;
 
-    // ensure_eq!(
-    //     info.sender,
-    //     config.nois_proxy,
-    //     ContractError::Unauthorized {}
-    // );
+    ensure_owner(&config, &info.sender)?;
+    ensure_not_paused(deps.storage, "mint", |pause_state| pause_state.mint)?;
 
-    let auragon_collection = config.auragon_collection;
+    let owner = addr_validate(deps.api, &owner)?;
+    let tier = load_gem_tier(deps.storage, &gem_trait.color)?;
+    if tier.retired {
+        return Err(ContractError::GemTierRetired { color: gem_trait.color });
+    }
 
     // Mint the new gem NFT from auragon_collection with token id increment by 1
     latest_token_id += 1;
-
-    let extension = Metadata {
-        attributes: vec![
-            Trait {
-                display_type: None,
-                trait_type: "color".to_string(),
-                value: gem_trait.color.clone(),
-            },
-            Trait {
-                display_type: None,
-                trait_type: "star".to_string(),
-                value: gem_trait.star.to_string(),
-            }
-        ].into(),
-        ..Default::default()
-    };
-
-    let token_uri = match gem_trait.color.as_str() {
-        "white" => auragon_uri.white[gem_trait.star as usize].clone(),
-        "blue" => auragon_uri.blue[gem_trait.star as usize].clone(),
-        "gold" => auragon_uri.gold[gem_trait.star as usize].clone(),
-        "red" => auragon_uri.red[gem_trait.star as usize].clone(),
-        _ => "".to_string(),
-    };
-
-    // Mint the new gem NFT from auragon_collection
-    let mint_gem = wasm_execute(
-        auragon_collection.to_string(),
-        &Cw721BaseExecuteMsg::Mint::<Metadata, Empty> {
-            token_id: latest_token_id.to_string(),
-            owner: owner.to_string(),
-            token_uri: Some(token_uri),
-            extension,
-        },
-        vec![],
+    let mint_gem = mint_gem_msg(
+        &tier,
+        &config.auragon_collection,
+        latest_token_id,
+        &owner,
+        &gem_trait.color,
+        gem_trait.star,
     )?;
 
     // Update the latest token id
@@ -710,36 +1144,42 @@ pub fn mint_auragon_gem(
     Ok(Response::new()
         .add_message(mint_gem)
         .add_attribute("action", "mint_auragon_gem")
-        .add_attribute("token_id", &latest_token_id.to_string())
+        .add_attribute("token_id", latest_token_id.to_string())
         .add_attribute("owner", owner))
 }
 
 pub fn mint_shield_gem(
     deps: DepsMut,
-    env: Env,
+    _env: Env,
     info: MessageInfo,
     owner: String,
 ) -> Result<Response, ContractError> {
     let config: Config = CONFIG.load(deps.storage)?;
-    // Load the latest token id
-    let mut latest_token_id = SHIELD_LATEST_TOKEN_ID.load(deps.storage)?;
-    // Load shield uri
-    let shield_uri = SHIELD_URI.load(deps.storage)?;
 
-    // ensure_eq!(
-    //     info.sender,
-    //     config.nois_proxy,
-    //     ContractError::Unauthorized {}
-    // );
+    ensure_owner(&config, &info.sender)?;
+
+    let (mint_gem, token_id) = mint_shield_msg(deps.storage, &config, &owner)?;
+
+    Ok(Response::new()
+        .add_message(mint_gem)
+        .add_attribute("action", "mint_shield_gem")
+        .add_attribute("token_id", token_id)
+        .add_attribute("owner", owner))
+}
+
+/// mint the next shield from shield_collection, returns the message and the token id
+fn mint_shield_msg(storage: &mut dyn Storage, config: &Config, owner: &str) -> Result<(WasmMsg, String), ContractError> {
+    ensure_not_paused(storage, "mint", |pause_state| pause_state.mint)?;
 
-    let shield_collection = config.shield_collection;
+    // Load shield uri
+    let shield_uri = SHIELD_URI.load(storage)?;
 
     // Mint the new gem NFT from shield_collection with token id increment by 1
-    latest_token_id += 1;
+    let latest_token_id = SHIELD_LATEST_TOKEN_ID.load(storage)? + 1;
+    SHIELD_LATEST_TOKEN_ID.save(storage, &latest_token_id)?;
 
-    // Mint the new gem NFT from shield_collection
     let mint_gem = wasm_execute(
-        shield_collection.to_string(),
+        config.shield_collection.to_string(),
         &Cw721BaseExecuteMsg::Mint::<Metadata, Empty> {
             token_id: latest_token_id.to_string(),
             owner: owner.to_string(),
@@ -748,324 +1188,1554 @@ pub fn mint_shield_gem(
         },
         vec![],
     )?;
+    Ok((mint_gem, latest_token_id.to_string()))
+}
 
-    // Update the latest token id
-    SHIELD_LATEST_TOKEN_ID.save(deps.storage, &latest_token_id)?;
+pub fn add_gem_tier(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    tier: GemTierMsg,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    let (color, gem_tier) = gem_tier_from_msg(tier)?;
+    if GEM_TIERS.has(deps.storage, &color) {
+        return Err(ContractError::GemTierExists { color });
+    }
+    GEM_TIERS.save(deps.storage, &color, &gem_tier)?;
 
     Ok(Response::new()
-        .add_message(mint_gem)
-        .add_attribute("action", "mint_shield_gem")
-        .add_attribute("token_id", &latest_token_id.to_string())
-        .add_attribute("owner", owner))
+        .add_attribute("action", "add_gem_tier")
+        .add_attribute("color", color)
+        .add_attribute("max_star", gem_tier.max_star.to_string()))
 }
 
-pub fn nois_receive(
+pub fn update_gem_tier(
     deps: DepsMut,
-    env: Env,
+    _env: Env,
     info: MessageInfo,
-    callback: NoisCallback,
+    tier: GemTierMsg,
 ) -> Result<Response, ContractError> {
     let config: Config = CONFIG.load(deps.storage)?;
 
-    ensure_eq!(
-        info.sender,
-        config.nois_proxy,
-        ContractError::Unauthorized {}
-    );
+    ensure_owner(&config, &info.sender)?;
 
-    let job_id = callback.job_id;
-    let randomness: [u8; 32] = callback
-        .randomness
-        .to_array()
-        .map_err(|_| ContractError::InvalidRandomness {})?;
+    let (color, mut gem_tier) = gem_tier_from_msg(tier)?;
+    // a retired color stays retired
+    gem_tier.retired = load_gem_tier(deps.storage, &color)?.retired;
+    GEM_TIERS.save(deps.storage, &color, &gem_tier)?;
 
-    let random_job: RandomJob =
-        if let Some(job) = RANDOM_JOBS.may_load(deps.storage, job_id.clone())? {
-            job
-        } else {
-            return Err(ContractError::RandomJobNotFound {});
-        };
+    Ok(Response::new()
+        .add_attribute("action", "update_gem_tier")
+        .add_attribute("color", color)
+        .add_attribute("max_star", gem_tier.max_star.to_string()))
+}
 
-    // init a key for the random provider from the job id
-    let key = format!("{}", job_id.clone());
+pub fn retire_gem_tier(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    color: String,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
 
-    let res = select_gem_rewards(
-        deps.storage,
-        randomness,
-        &key,
-        random_job.timestamp,
-    )?;
+    ensure_owner(&config, &info.sender)?;
 
-    // job finished, just remove
-    RANDOM_JOBS.remove(deps.storage, job_id.clone());
+    // the tier is kept so that the gems already minted can still be read
+    let mut gem_tier = load_gem_tier(deps.storage, &color)?;
+    gem_tier.retired = true;
+    GEM_TIERS.save(deps.storage, &color, &gem_tier)?;
 
-    Ok(res
-        .add_attribute("action", "nois_receive")
-        .add_attribute("job_id", job_id))
+    Ok(Response::new()
+        .add_attribute("action", "retire_gem_tier")
+        .add_attribute("color", color))
 }
 
-fn select_gem_rewards(
-    storage: &mut dyn Storage,
-    random_seed: [u8; 32],
-    key: &String,
-    forges: Timestamp,
+pub fn update_dragon_gem(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    token_uri: String,
+    gem: Option<GemKind>,
 ) -> Result<Response, ContractError> {
-    // update random seed
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    let mut res = Response::new()
+        .add_attribute("action", "update_dragon_gem")
+        .add_attribute("token_uri", token_uri.clone());
+    match gem {
+        Some(gem) => {
+            validate_gem(deps.storage, &gem)?;
+            DRAGON_GEMS.save(deps.storage, &token_uri, &gem)?;
+            res = res.add_attribute("gem", gem.to_string());
+        }
+        None => DRAGON_GEMS.remove(deps.storage, &token_uri),
+    }
+
+    Ok(res)
+}
+
+pub fn update_accepted_token(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    token: String,
+    prices: Option<AcceptedToken>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    let token = addr_validate(deps.api, &token)?;
+    match prices {
+        Some(prices) => {
+            for (index, (color, fee)) in prices.forge_fees.iter().enumerate() {
+                load_gem_tier(deps.storage, color)?;
+                if fee.is_zero() {
+                    return Err(ContractError::InvalidCoins { reason: format!("zero forge fee of {}", color) });
+                }
+                if prices.forge_fees[..index].iter().any(|(other, _)| other == color) {
+                    return Err(ContractError::InvalidCoins { reason: format!("duplicate forge fee of {}", color) });
+                }
+            }
+            // a shield is never given for free
+            if prices.shield_price.is_some_and(|price| price.is_zero()) {
+                return Err(ContractError::InvalidCoins { reason: "zero shield price".to_string() });
+            }
+            ACCEPTED_TOKENS.save(deps.storage, &token, &prices)?;
+        }
+        None => ACCEPTED_TOKENS.remove(deps.storage, &token),
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "update_accepted_token")
+        .add_attribute("token", token))
+}
+
+pub fn update_fusion_rule(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    color: String,
+    star: u8,
+    rule: Option<FusionRule>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    match rule {
+        Some(rule) => {
+            validate_gem(deps.storage, &GemKind { color: color.clone(), star })?;
+            validate_gem(deps.storage, &GemKind { color: rule.result_color.clone(), star: rule.result_star })?;
+            if rule.gem_count == 0 {
+                return Err(ContractError::InvalidFusion { reason: "gem_count must be at least 1".to_string() });
+            }
+            FUSION_RULES.save(deps.storage, (&color, star), &rule)?;
+        }
+        None => FUSION_RULES.remove(deps.storage, (&color, star)),
+    }
+
+    Ok(Response::new()
+        .add_attribute("action", "update_fusion_rule")
+        .add_attribute("color", color)
+        .add_attribute("star", star.to_string()))
+}
+
+pub fn update_pity_config(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    pity_config: Option<PityConfig>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    match pity_config {
+        Some(pity_config) => {
+            let mut bonuses = pity_config.bonus_curve.iter().chain([&pity_config.max_bonus]);
+            if let Some(bonus) = bonuses.find(|bonus| **bonus > SUCCESS_RATE_PRECISION) {
+                return Err(ContractError::InvalidPityConfig {
+                    reason: format!("bonus {} is over {} basis points", bonus, SUCCESS_RATE_PRECISION),
+                });
+            }
+            PITY_CONFIG.save(deps.storage, &pity_config)?;
+        }
+        None => PITY_CONFIG.remove(deps.storage),
+    }
+
+    Ok(Response::new().add_attribute("action", "update_pity_config"))
+}
+
+pub fn update_salvage_config(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    salvage_config: Option<SalvageConfig>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    match salvage_config {
+        Some(salvage_config) => {
+            // free shields could be crafted over and over
+            if salvage_config.shield_cost.is_zero() {
+                return Err(ContractError::InvalidSalvageConfig { reason: "shield_cost must not be zero".to_string() });
+            }
+            if salvage_config.fragments_per_work_power.is_zero() {
+                return Err(ContractError::InvalidSalvageConfig {
+                    reason: "fragments_per_work_power must not be zero".to_string(),
+                });
+            }
+            SALVAGE_CONFIG.save(deps.storage, &salvage_config)?;
+        }
+        None => SALVAGE_CONFIG.remove(deps.storage),
+    }
+
+    Ok(Response::new().add_attribute("action", "update_salvage_config"))
+}
+
+pub fn update_random_gem_sale(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    sale: Option<RandomGemSale>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    match sale {
+        Some(sale) => {
+            let sale = RandomGemSale {
+                price: validate_coins(sale.price)?,
+                supply_limit: sale.supply_limit,
+            };
+            RANDOM_GEM_SALE.save(deps.storage, &sale)?;
+        }
+        None => RANDOM_GEM_SALE.remove(deps.storage),
+    }
+
+    Ok(Response::new().add_attribute("action", "update_random_gem_sale"))
+}
+
+pub fn execute_set_pause_state(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    pause_state: PauseState,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    PAUSE_STATE.save(deps.storage, &pause_state)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "set_pause_state")
+        .add_attribute("join_queue", pause_state.join_queue.to_string())
+        .add_attribute("forge", pause_state.forge.to_string())
+        .add_attribute("mint", pause_state.mint.to_string())
+        .add_attribute("settlement", pause_state.settlement.to_string()))
+}
+
+pub fn update_job_timeout(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    job_timeout: u64,
+) -> Result<Response, ContractError> {
+    let mut config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    // the timeout applies to the pending jobs as well
+    config.job_timeout = job_timeout;
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_job_timeout")
+        .add_attribute("job_timeout", job_timeout.to_string()))
+}
+
+pub fn update_nois_price(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    nois_price: Vec<Coin>,
+) -> Result<Response, ContractError> {
+    let mut config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    config.nois_price = validate_coins(nois_price)?;
+    CONFIG.save(deps.storage, &config)?;
+
+    Ok(Response::new().add_attribute("action", "update_nois_price"))
+}
+
+pub fn execute_top_up_nois_balance(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    if info.funds.is_empty() {
+        return Err(ContractError::InvalidCoins { reason: "nothing to top up".to_string() });
+    }
+    add_balances(deps.storage, NOIS_BALANCE, &info.funds)?;
+
+    Ok(Response::new().add_attribute("action", "top_up_nois_balance"))
+}
+
+pub fn execute_withdraw_nois_balance(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    amount: Vec<Coin>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    let amount = validate_coins(amount)?;
+    if amount.is_empty() {
+        return Err(ContractError::InvalidCoins { reason: "nothing to withdraw".to_string() });
+    }
+    let recipient = match recipient {
+        Some(recipient) => addr_validate(deps.api, &recipient)?,
+        None => info.sender,
+    };
+    for coin in &amount {
+        let balance = NOIS_BALANCE.may_load(deps.storage, &coin.denom)?.unwrap_or_default();
+        if balance < coin.amount {
+            return Err(ContractError::InsufficientNoisBalance { denom: coin.denom.clone() });
+        }
+    }
+    sub_balances(deps.storage, NOIS_BALANCE, &amount)?;
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount,
+        })
+        .add_attribute("action", "withdraw_nois_balance")
+        .add_attribute("recipient", recipient))
+}
+
+pub fn execute_withdraw_treasury(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    amount: Vec<Coin>,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    let amount = validate_coins(amount)?;
+    if amount.is_empty() {
+        return Err(ContractError::InvalidCoins { reason: "nothing to withdraw".to_string() });
+    }
+    let recipient = match recipient {
+        Some(recipient) => addr_validate(deps.api, &recipient)?,
+        None => info.sender,
+    };
+    for coin in &amount {
+        let balance = TREASURY.may_load(deps.storage, &coin.denom)?.unwrap_or_default();
+        if balance < coin.amount {
+            return Err(ContractError::InsufficientTreasury { denom: coin.denom.clone() });
+        }
+    }
+    sub_balances(deps.storage, TREASURY, &amount)?;
+
+    Ok(Response::new()
+        .add_message(BankMsg::Send {
+            to_address: recipient.to_string(),
+            amount,
+        })
+        .add_attribute("action", "withdraw_treasury")
+        .add_attribute("recipient", recipient))
+}
+
+pub fn execute_withdraw_cw20_treasury(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    token: String,
+    amount: Uint128,
+    recipient: Option<String>,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    if amount.is_zero() {
+        return Err(ContractError::InvalidCoins { reason: "nothing to withdraw".to_string() });
+    }
+    let token = addr_validate(deps.api, &token)?;
+    let recipient = match recipient {
+        Some(recipient) => addr_validate(deps.api, &recipient)?,
+        None => info.sender,
+    };
+    let balance = CW20_TREASURY.may_load(deps.storage, &token)?.unwrap_or_default();
+    if balance < amount {
+        return Err(ContractError::InsufficientTreasury { denom: token.to_string() });
+    }
+    sub_balance(deps.storage, CW20_TREASURY, &token, amount)?;
+
+    Ok(Response::new()
+        .add_message(transfer_cw20_msg(&token, amount, &recipient)?)
+        .add_attribute("action", "withdraw_cw20_treasury")
+        .add_attribute("token", token)
+        .add_attribute("recipient", recipient))
+}
+
+pub fn update_failure_penalty(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    color: String,
+    penalty: FailurePenalty,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    load_gem_tier(deps.storage, &color)?;
+    FAILURE_PENALTIES.save(deps.storage, &color, &penalty)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "update_failure_penalty")
+        .add_attribute("color", color)
+        .add_attribute("penalty", format!("{:?}", penalty)))
+}
+
+pub fn execute_transfer_ownership(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    new_owner: String,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    // Proposing again replaces any previous pending owner
+    let new_owner = addr_validate(deps.api, &new_owner)?;
+    PENDING_OWNER.save(deps.storage, &new_owner)?;
+
+    Ok(Response::new()
+        .add_attribute("action", "transfer_ownership")
+        .add_attribute("pending_owner", new_owner))
+}
+
+pub fn execute_accept_ownership(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let pending_owner = PENDING_OWNER
+        .may_load(deps.storage)?
+        .ok_or(ContractError::NoPendingOwner {})?;
+
+    ensure_eq!(info.sender, pending_owner, ContractError::Unauthorized {});
+
+    CONFIG.update(deps.storage, |mut config| -> Result<_, ContractError> {
+        config.owner = Some(pending_owner.clone());
+        Ok(config)
+    })?;
+    PENDING_OWNER.remove(deps.storage);
+
+    Ok(Response::new()
+        .add_attribute("action", "accept_ownership")
+        .add_attribute("owner", pending_owner))
+}
+
+pub fn execute_renounce_ownership(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+) -> Result<Response, ContractError> {
+    let mut config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_owner(&config, &info.sender)?;
+
+    config.owner = None;
+    CONFIG.save(deps.storage, &config)?;
+    PENDING_OWNER.remove(deps.storage);
+
+    Ok(Response::new().add_attribute("action", "renounce_ownership"))
+}
+
+pub fn nois_receive(
+    deps: DepsMut,
+    _env: Env,
+    info: MessageInfo,
+    callback: NoisCallback,
+) -> Result<Response, ContractError> {
+    let config: Config = CONFIG.load(deps.storage)?;
+
+    ensure_eq!(
+        info.sender,
+        config.nois_proxy,
+        ContractError::Unauthorized {}
+    );
+
+    let job_id = callback.job_id;
+    let randomness: [u8; 32] = callback
+        .randomness
+        .to_array()
+        .map_err(|_| ContractError::InvalidRandomness {})?;
+
+    if !RANDOM_JOBS.has(deps.storage, job_id.clone()) && !RANDOM_MINTS.has(deps.storage, job_id.clone()) {
+        return Err(ContractError::RandomJobNotFound {});
+    }
+
+    // Keep the randomness until the settlement resumes rather than losing the callback
+    if PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default().settlement {
+        BUFFERED_RANDOMNESS.save(deps.storage, job_id.clone(), &randomness)?;
+        return Ok(Response::new()
+            .add_attribute("action", "buffer_randomness")
+            .add_attribute("job_id", job_id));
+    }
+
+    let res = settle_randomness(deps.storage, &job_id, randomness)?;
+
+    Ok(res
+        .add_attribute("action", "nois_receive")
+        .add_attribute("job_id", job_id))
+}
+
+pub fn execute_settle_buffered_jobs(
+    deps: DepsMut,
+    env: Env,
+    _info: MessageInfo,
+    limit: Option<u32>,
+) -> Result<Response, ContractError> {
+    ensure_not_paused(deps.storage, "settlement", |pause_state| pause_state.settlement)?;
+
+    // Settle the jobs in the order of their ids, a limit keeps the gas of a message bounded
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let buffered: Vec<(String, [u8; 32])> = BUFFERED_RANDOMNESS
+        .range(deps.storage, None, None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<_>>()?;
+
+    // Each job is settled in a sub message of its own, a job failing to settle is reverted alone
+    // and leaves the buffer so it does not hold back the next ones, it is refunded once it expires
+    let mut res = Response::new().add_attribute("action", "settle_buffered_jobs");
+    for (reply_id, (job_id, randomness)) in buffered.into_iter().enumerate() {
+        BUFFERED_RANDOMNESS.remove(deps.storage, job_id.clone());
+        SETTLING_JOBS.save(deps.storage, reply_id as u64, &job_id)?;
+        let settle_job = wasm_execute(
+            env.contract.address.to_string(),
+            &ExecuteMsg::SettleBufferedJob {
+                job_id: job_id.clone(),
+                randomness: HexBinary::from(randomness),
+            },
+            vec![],
+        )?;
+        res = res
+            .add_submessage(SubMsg::reply_always(settle_job, reply_id as u64))
+            .add_attribute("job_id", job_id);
+    }
+    Ok(res)
+}
+
+pub fn execute_settle_buffered_job(
+    deps: DepsMut,
+    env: Env,
+    info: MessageInfo,
+    job_id: String,
+    randomness: HexBinary,
+) -> Result<Response, ContractError> {
+    ensure_eq!(info.sender, env.contract.address, ContractError::Unauthorized {});
+
+    let randomness: [u8; 32] = randomness.to_array().map_err(|_| ContractError::InvalidRandomness {})?;
+    let res = settle_randomness(deps.storage, &job_id, randomness)?;
+
+    Ok(res
+        .add_attribute("action", "settle_buffered_job")
+        .add_attribute("job_id", job_id))
+}
+
+/// Handling the replies of the buffered jobs settled in sub messages
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn reply(deps: DepsMut, _env: Env, msg: Reply) -> Result<Response, ContractError> {
+    let job_id = SETTLING_JOBS.load(deps.storage, msg.id)?;
+    SETTLING_JOBS.remove(deps.storage, msg.id);
+
+    match msg.result {
+        SubMsgResult::Ok(_) => Ok(Response::new()),
+        // the job stays until it expires and is refunded
+        SubMsgResult::Err(err) => Ok(Response::new()
+            .add_attribute("action", "settle_buffered_job_failed")
+            .add_attribute("job_id", job_id)
+            .add_attribute("error", err)),
+    }
+}
+
+/// settle the forge job or the random gem the randomness was requested for
+fn settle_randomness(storage: &mut dyn Storage, job_id: &str, randomness: [u8; 32]) -> Result<Response, ContractError> {
+    match RANDOM_MINTS.may_load(storage, job_id.to_string())? {
+        Some(random_mint) => settle_random_mint(storage, job_id, randomness, random_mint),
+        None => settle_job(storage, job_id, randomness),
+    }
+}
+
+/// settle a random job with its randomness and remove it
+fn settle_job(storage: &mut dyn Storage, job_id: &str, randomness: [u8; 32]) -> Result<Response, ContractError> {
+    let random_job = RANDOM_JOBS
+        .may_load(storage, job_id.to_string())?
+        .ok_or(ContractError::RandomJobNotFound {})?;
+
+    // the fees of the job are earned once it is settled
+    let fees = random_job.fees.clone();
+    let cw20_fees = random_job.cw20_fees.clone();
+    let res = select_gem_rewards(storage, randomness, job_id, random_job)?;
+    sub_balances(storage, PENDING_FEES, &fees)?;
+    add_balances(storage, TREASURY, &fees)?;
+    for fee in &cw20_fees {
+        sub_balance(storage, CW20_PENDING_FEES, &fee.address, fee.amount)?;
+        add_balance(storage, CW20_TREASURY, &fee.address, fee.amount)?;
+    }
+
+    // job finished, just remove
+    RANDOM_JOBS.remove(storage, job_id.to_string());
+
+    Ok(res)
+}
+
+fn select_gem_rewards(
+    storage: &mut dyn Storage,
+    random_seed: [u8; 32],
+    job_id: &str,
+    random_job: RandomJob,
+) -> Result<Response, ContractError> {
+    // update random seed
     RANDOM_SEED.save(storage, &random_seed)?;
     // load config
     let config: Config = CONFIG.load(storage)?;
-    // load random job
-    let random_job: RandomJob = RANDOM_JOBS.load(storage, key.clone())?;
-    // load user success rate list
-    let user_success_rate_list = random_job.user_success_rate_list;
-    // load gem base nft color and star user list
-    let mut gem_base_nft_color_and_star_user_list = random_job.gem_base_nft_color_and_star_user_list;
     let mut res = Response::new();
     let mut latest_token_id = AURAGON_LATEST_TOKEN_ID.load(storage)?;
-    // loop through user_success_rate_list and select gem rewards with select_from_weighted
-    for (user_addr, success_rate) in user_success_rate_list {
-        let weights_list: Vec<(&str, u32)>;
-        // make a new vec of success rate for each user by sub with 100
-        let failure_rate = 100 - success_rate;
-        if failure_rate == 0 {
-            weights_list = vec![("success", success_rate)];
-        } else {
-            weights_list = vec![("success", success_rate), ("failure", failure_rate)];
+    let mut result_count = FORGE_RESULT_COUNT.may_load(storage)?.unwrap_or_default();
+    let pity_config = PITY_CONFIG.may_load(storage)?;
+    // loop through the participants and select gem rewards with select_from_weighted
+    for (index, participant) in random_job.participants.into_iter().enumerate() {
+        let JobParticipant { entry_id, user_addr, gem_base, gem_base_kind, gem_materials, shield_id, success_rate } = participant;
+        // each user draws from its own randomness so the outcomes within a job are independent
+        let user_randomness = user_randomness(random_seed, job_id, index);
+        if success_rate > SUCCESS_RATE_PRECISION {
+            return Err(ContractError::InvalidSuccessRate { success_rate });
         }
+        // the failures in a row of the user on the color raise their success rate
+        let failures = PITY_COUNTERS
+            .may_load(storage, (&user_addr, &gem_base_kind.color))?
+            .unwrap_or_default();
+        let pity_bonus = pity_bonus(pity_config.as_ref(), failures);
+        let boosted_rate = success_rate.saturating_add(pity_bonus).min(SUCCESS_RATE_PRECISION);
+        // make a new vec of success rate for each user by sub with SUCCESS_RATE_PRECISION
+        let failure_rate = SUCCESS_RATE_PRECISION - boosted_rate;
+        // select_from_weighted does not accept zero weights
+        let weights_list: Vec<(&str, u32)> = [("success", boosted_rate), ("failure", failure_rate)]
+            .into_iter()
+            .filter(|(_, weight)| *weight > 0)
+            .collect();
         // select from weighted
-        let selected = select_from_weighted(random_seed, &weights_list).unwrap();
-        // if selected is success, mint the new gem NFT with color and star = star + 1 from gem_base_nft_color_and_star_user_list
-        if selected == "success" {
-            let color_and_star = gem_base_nft_color_and_star_user_list.pop().unwrap();
-            let color_and_star_split: Vec<&str> = color_and_star.split("-").collect();
-            let color = color_and_star_split[0];
-            let star = color_and_star_split[1].parse::<u32>().unwrap();
-            let auragon_collection = config.auragon_collection.clone();
+        let selected = select_from_weighted(user_randomness, &weights_list)
+            .map_err(|_| ContractError::InvalidSuccessRate { success_rate: boosted_rate })?;
+        let success = selected == "success";
+        let (color, star) = (gem_base_kind.color.as_str(), gem_base_kind.star);
+        if success {
+            PITY_COUNTERS.remove(storage, (&user_addr, color));
+        } else {
+            PITY_COUNTERS.save(storage, (&user_addr, color), &failures.saturating_add(1))?;
+        }
+        // the job is settled even if the color was retired meanwhile
+        let tier = load_gem_tier(storage, color)?;
+        let mut minted_token_id = None;
+        // if selected is success, mint the new gem NFT with color and star = star + 1 from the gem_base
+        if success {
             // increase the latest token id by 1
             latest_token_id += 1;
-            let token_uri = match color {
-                "white" => AURAGON_URI.load(storage)?.white[star as usize].clone(),
-                "blue" => AURAGON_URI.load(storage)?.blue[star as usize].clone(),
-                "gold" => AURAGON_URI.load(storage)?.gold[star as usize].clone(),
-                "red" => AURAGON_URI.load(storage)?.red[star as usize].clone(),
-                _ => "".to_string(),
-            };
-            let extension = Metadata {
-                attributes: vec![
-                    Trait {
-                        display_type: None,
-                        trait_type: "color".to_string(),
-                        value: color.to_string(),
-                    },
-                    Trait {
-                        display_type: None,
-                        trait_type: "star".to_string(),
-                        value: (star + 1).to_string(),
-                    }
-                ].into(),
-                ..Default::default()
-            };
-            let mint_gem = wasm_execute(
-                auragon_collection.to_string(),
-                &Cw721BaseExecuteMsg::Mint::<Metadata, Empty> {
-                    token_id: (latest_token_id).to_string(),
-                    owner: user_addr.to_string(),
-                    token_uri: Some(token_uri),
-                    extension,
-                },
-                vec![],
-            )?;
-            // add message mint_gem and approve_all to the response
+            let next_star = star.checked_add(1).ok_or(ContractError::StarOutOfRange {})?;
+            let mint_gem = mint_gem_msg(&tier, &config.auragon_collection, latest_token_id, &user_addr, color, next_star)?;
             res = res.add_message(mint_gem);
             // add attribute to the response user success and token id minted
             res = res.add_attribute("user_success", user_addr.to_string())
                 .add_attribute("new_token_id", (latest_token_id).to_string());
+            minted_token_id = Some(latest_token_id.to_string());
         } else {
             // add attribute to the response user failure
             res = res.add_attribute("user_failure", user_addr.to_string());
         }
+        let mut penalty = None;
+        let mut shield_used = false;
+        // the entry leaves the queue and its escrowed gems are settled, all the messages of the job
+        // are executed in the same transaction so either every mint and burn happens or none do
+        if let (Some(entry_id), Some(gem_base)) = (entry_id, &gem_base) {
+            remove_queue_entry(storage, entry_id)?;
+            // the materials are consumed whatever the outcome
+            for gem_material in &gem_materials {
+                ESCROWS.remove(storage, (&gem_material.nft_contract, &gem_material.nft_id));
+                res = res.add_message(burn_nft_msg(gem_material)?);
+            }
+            // a 1 star gem cannot lose a star
+            let failure_penalty = match FAILURE_PENALTIES.may_load(storage, color)?.unwrap_or(FailurePenalty::Keep) {
+                FailurePenalty::Downgrade if star <= 1 => FailurePenalty::Keep,
+                failure_penalty => failure_penalty,
+            };
+            // the shield is only consumed when it protects the gem_base, otherwise it goes back to the user
+            if let Some(ref shield_id) = shield_id {
+                let shield = GemInfo {
+                    nft_id: shield_id.clone(),
+                    nft_contract: config.shield_collection.clone(),
+                };
+                ESCROWS.remove(storage, (&shield.nft_contract, &shield.nft_id));
+                shield_used = !success && failure_penalty != FailurePenalty::Keep;
+                if shield_used {
+                    res = res.add_message(burn_nft_msg(&shield)?);
+                    res = res.add_attribute("shield_used", user_addr.to_string());
+                } else {
+                    res = res.add_message(transfer_nft_msg(&shield, &user_addr)?);
+                }
+            }
+            ESCROWS.remove(storage, (&gem_base.nft_contract, &gem_base.nft_id));
+            if success {
+                // the gem_base is consumed by the upgrade
+                res = res.add_message(burn_nft_msg(gem_base)?);
+            } else if shield_used {
+                res = res.add_message(transfer_nft_msg(gem_base, &user_addr)?);
+            } else {
+                match failure_penalty {
+                    FailurePenalty::Keep => {
+                        res = res.add_message(transfer_nft_msg(gem_base, &user_addr)?);
+                    }
+                    FailurePenalty::Burn => {
+                        res = res.add_message(burn_nft_msg(gem_base)?);
+                    }
+                    FailurePenalty::Downgrade => {
+                        latest_token_id += 1;
+                        res = res
+                            .add_message(burn_nft_msg(gem_base)?)
+                            .add_message(mint_gem_msg(&tier, &config.auragon_collection, latest_token_id, &user_addr, color, star - 1)?)
+                            .add_attribute("downgraded_token_id", latest_token_id.to_string());
+                        minted_token_id = Some(latest_token_id.to_string());
+                    }
+                }
+                penalty = Some(failure_penalty);
+            }
+        }
+        // keep the result of the user once the job is gone
+        let outcome = if success { ForgeOutcome::Success } else { ForgeOutcome::Failure };
+        let forge_result = ForgeResult {
+            id: result_count,
+            job_id: job_id.to_string(),
+            user_addr: user_addr.clone(),
+            gem_base,
+            gem_base_kind,
+            gem_materials,
+            shield_id,
+            success_rate,
+            pity_bonus,
+            outcome,
+            penalty,
+            shield_used,
+            minted_token_id,
+            randomness: HexBinary::from(user_randomness),
+            timestamp: random_job.timestamp,
+        };
+        forge_results().save(storage, (job_id.to_string(), user_addr), &forge_result)?;
+        result_count += 1;
     }
     // update the latest token id
     AURAGON_LATEST_TOKEN_ID.save(storage, &(latest_token_id))?;
+    FORGE_RESULT_COUNT.save(storage, &result_count)?;
     Ok(res.add_attribute("action", "select_gem_rewards DONEEEEEEE"))
 }
 
-// fn convert_to_user_success_rate_list(deps: &DepsMut, user_list: Vec<UserInfo>) -> Vec<(Addr, u32)> {
-//     // get CONFIG
-//     let config: Config = CONFIG.load(deps.storage).unwrap();
-//     // get white gem work power
-//     let white_gem_work_power = WHITE_GEM_WORK_POWER.load(deps.storage).unwrap();
-//     // get blue gem work power
-//     let blue_gem_work_power = BLUE_GEM_WORK_POWER.load(deps.storage).unwrap();
-//     // get gold gem work power
-//     let gold_gem_work_power = GOLD_GEM_WORK_POWER.load(deps.storage).unwrap();
-//     // get red gem work power
-//     let red_gem_work_power = RED_GEM_WORK_POWER.load(deps.storage).unwrap();
-//     // get gem ratio
-//     let gem_ratio = GEM_RATIO.load(deps.storage).unwrap();
-//     // get gem work load from n star to n+1 star
-//     let gem_work_load = GEM_WORK_LOAD.load(deps.storage).unwrap();
-//     // get dragon_collection
-//     let dragon_collection = config.dragon_collection;
-//     // get gem_base nft_id
-//     let gem_base_nft_id_user_list: Vec<String> = user_list.iter().map(|user| user.gem_base.nft_id.clone()).collect();
-//     // get gem_base nft_contract
-//     let gem_base_nft_contract_user_list: Vec<Addr> = user_list.iter().map(|user| user.gem_base.nft_contract.clone()).collect();
-//     // get gem_materials nft_id
-//     let gem_materials_nft_id_user_list: Vec<Vec<String>> = user_list.iter().map(|user| user.gem_materials.iter().map(|gem| gem.nft_id.clone()).collect()).collect();
-//     // get gem_materials nft_contract
-//     let gem_materials_nft_contract_user_list: Vec<Vec<Addr>> = user_list.iter().map(|user| user.gem_materials.iter().map(|gem| gem.nft_contract.clone()).collect()).collect();
-//     // loop through gem_base_nft_contract_user_list and gem_base_nft_id_user_list and get token uri if contract is dragon_collection and get color and star if contract is auragon_collection
-//     let gem_base_nft_color_and_star_user_list: Vec<String>
-//         = gem_base_nft_contract_user_list.iter().zip(gem_base_nft_id_user_list.iter()).map(|(contract, id)| {
-//         if contract == &dragon_collection {
-//             let query_msg = Cw721QueryMsg::NftInfo { token_id: id.clone() };
-
-//             let query_response: StdResult<cw721::NftInfoResponse<Metadata>> =
-//             deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-//                 contract_addr: contract.to_string(),
-//                 msg: to_json_binary(&query_msg).unwrap(),
-//             }));
-
-//             match query_response {
-//                 Ok(response) => {
-//                     match response.token_uri.unwrap().as_str() {
-//                         "ipfs://Qme1dXSRNSqYvVQSDEmoL6WHMLqrYajZkszYhbRGj2F2oa" => "white-1".to_string(),
-//                         "ipfs://QmSp3iYpenTNr69g2EDSS128Vs1oRV2EHW8vakZ2Ro8G6P" => "blue-1".to_string(),
-//                         "ipfs://QmQP3N4jxJKGXPx18PgrjdhGLqYjX2qtinZ4q4YBeQhpw7" => "gold-1".to_string(),
-//                         "ipfs://QmTUy7E1UnLcbasfQap38kfxBAsFWzPfLmQimTh7pNw4QT" => "red-1".to_string(),
-//                         _ => "".to_string()
-//                     }
-//                 },
-//                 Err(_) => "".to_string()
-//             }
-//         } else {
-//             let query_msg = Cw721QueryMsg::NftInfo { token_id: id.clone() };
-
-//             let query_response: StdResult<cw721::NftInfoResponse<Metadata>> =
-//             deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-//                 contract_addr: contract.to_string(),
-//                 msg: to_json_binary(&query_msg).unwrap(),
-//             }));
-
-//             match query_response {
-//                 Ok(response) => {
-//                     match response.extension.attributes.as_ref().unwrap()[0].value.as_str() {
-//                         "white" => "white-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                         "blue" => "blue-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                         "gold" => "gold-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                         "red" => "red-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                         _ => "".to_string()
-//                     }
-//                 },
-//                 Err(_) => "".to_string()
-//             }
-//         }
-//     }).collect();
-//     // loop through gem_materials_nft_contract_user_list and gem_materials_nft_id_user_list and get token uri if contract is dragon_collection and get color and star if contract is auragon_collection
-//     let gem_materials_nft_color_and_star_user_list: Vec<Vec<String>>
-//         = gem_materials_nft_contract_user_list.iter().zip(gem_materials_nft_id_user_list.iter()).map(|(contract, id)| {
-//         contract.iter().zip(id.iter()).map(|(contract, id)| {
-//             if contract == &dragon_collection {
-//                 let query_msg = Cw721QueryMsg::NftInfo { token_id: id.clone() };
-
-//                 let query_response: StdResult<cw721::NftInfoResponse<Metadata>> =
-//                 deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-//                     contract_addr: contract.to_string(),
-//                     msg: to_json_binary(&query_msg).unwrap(),
-//                 }));
-
-//                 match query_response {
-//                     Ok(response) => {
-//                         match response.token_uri.unwrap().as_str() {
-//                             "ipfs://Qme1dXSRNSqYvVQSDEmoL6WHMLqrYajZkszYhbRGj2F2oa" => "white-1".to_string(),
-//                             "ipfs://QmSp3iYpenTNr69g2EDSS128Vs1oRV2EHW8vakZ2Ro8G6P" => "blue-1".to_string(),
-//                             "ipfs://QmQP3N4jxJKGXPx18PgrjdhGLqYjX2qtinZ4q4YBeQhpw7" => "gold-1".to_string(),
-//                             "ipfs://QmTUy7E1UnLcbasfQap38kfxBAsFWzPfLmQimTh7pNw4QT" => "red-1".to_string(),
-//                             _ => "".to_string()
-//                         }
-//                     },
-//                     Err(_) => "".to_string()
-//                 }
-//             } else {
-//                 let query_msg = Cw721QueryMsg::NftInfo { token_id: id.clone() };
-
-//                 let query_response: StdResult<cw721::NftInfoResponse<Metadata>> =
-//                 deps.querier.query(&QueryRequest::Wasm(WasmQuery::Smart {
-//                     contract_addr: contract.to_string(),
-//                     msg: to_json_binary(&query_msg).unwrap(),
-//                 }));
-
-//                 match query_response {
-//                     Ok(response) => {
-//                         match response.extension.attributes.as_ref().unwrap()[0].value.as_str() {
-//                             "white" => "white-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                             "blue" => "blue-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                             "gold" => "gold-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                             "red" => "red-".to_string() + &response.extension.attributes.unwrap()[1].value,
-//                             _ => "".to_string()
-//                         }
-//                     },
-//                     Err(_) => "".to_string()
-//                 }
-//             }
-//         }).collect()
-//     }).collect();
-
-//     // convert gem_materials_nft_color_and_star_user_list to gem_materials_work_power_user_list base on WHITE_GEM_WORK_POWER, BLUE_GEM_WORK_POWER, GOLD_GEM_WORK_POWER, RED_GEM_WORK_POWER
-//     // take the star from color-star string and convert it to u8
-//     let gem_materials_work_power_user_list: Vec<Vec<Decimal>> = gem_materials_nft_color_and_star_user_list.iter().map(|gem_materials| {
-//         gem_materials.iter().map(|gem_material| {
-//             let star: u8 = gem_material.split("-").collect::<Vec<&str>>()[1].parse().unwrap();
-//             match gem_material.split("-").collect::<Vec<&str>>()[0] {
-//                 "white" => white_gem_work_power[star as usize - 1],
-//                 "blue" => blue_gem_work_power[star as usize - 1],
-//                 "gold" => gold_gem_work_power[star as usize - 1],
-//                 "red" => red_gem_work_power[star as usize - 1],
-//                 _ => Decimal::zero()
-//             }
-//         }).collect()
-//     }).collect();
-
-//     // convert gem_materials_work_power_user_list to gem_materials_work_power_user_list_sum
-//     let gem_materials_work_power_user_list_sum: Vec<Decimal> = gem_materials_work_power_user_list.iter().map(|gem_materials| {
-//         gem_materials.iter().sum()
-//     }).collect();
-
-//     // calculate the user work load base on star of gem_base
-//     let user_work_load: Vec<Decimal> = gem_base_nft_color_and_star_user_list.iter().map(|gem_base| {
-//         let star: u8 = gem_base.split("-").collect::<Vec<&str>>()[1].parse().unwrap();
-//         gem_work_load[star as usize - 1]
-//     }).collect();
-
-//     // calculate the user success rate base on user work load and gem_materials_work_power_user_list_sum (success_rate = gem_materials_work_power_user_list_sum / user_work_load)
-//     let user_success_rate: Vec<Decimal> = user_work_load.iter().zip(gem_materials_work_power_user_list_sum.iter()).map(|(work_load, work_power)| {
-//         work_power / work_load
-//     }).collect();
-
-//     // convert user_addr and user_success_rate to Vec<(Addr, u32)>
-//     let user_success_rate_list: Vec<(Addr, u32)> = user_list.iter().map(|user| {
-//         (user.user_addr.clone(), user_success_rate[user_list.iter().position(|x| x == user).unwrap()].to_u32().unwrap())
-//     }).collect();
-
-//     user_success_rate_list
-// }
+/// bonus in basis points after `failures` failures in a row, capped at the max bonus
+fn pity_bonus(pity_config: Option<&PityConfig>, failures: u32) -> u32 {
+    let Some(pity_config) = pity_config else {
+        return 0;
+    };
+    // the last step of the curve applies to the longer streaks
+    let step = usize::try_from(failures).unwrap_or(usize::MAX).min(pity_config.bonus_curve.len());
+    step.checked_sub(1)
+        .and_then(|index| pity_config.bonus_curve.get(index))
+        .map_or(0, |bonus| (*bonus).min(pity_config.max_bonus))
+}
+
+/// mint an auragon gem of the given color and star, with the token uri and name of its tier
+fn mint_gem_msg(
+    tier: &GemTier,
+    auragon_collection: &Addr,
+    token_id: u64,
+    owner: &Addr,
+    color: &str,
+    star: u8,
+) -> Result<WasmMsg, ContractError> {
+    let token_uri = tier
+        .uris
+        .get(usize::from(star).wrapping_sub(1))
+        .cloned()
+        .ok_or(ContractError::StarOutOfRange {})?;
+    let extension = Metadata {
+        name: Some(format!("{} {} Star", tier.display_name, star)),
+        attributes: vec![
+            Trait {
+                display_type: None,
+                trait_type: "color".to_string(),
+                value: color.to_string(),
+            },
+            Trait {
+                display_type: None,
+                trait_type: "star".to_string(),
+                value: star.to_string(),
+            }
+        ].into(),
+        ..Default::default()
+    };
+    Ok(wasm_execute(
+        auragon_collection.to_string(),
+        &Cw721BaseExecuteMsg::Mint::<Metadata, Empty> {
+            token_id: token_id.to_string(),
+            owner: owner.to_string(),
+            token_uri: Some(token_uri),
+            extension,
+        },
+        vec![],
+    )?)
+}
+
+/// sub-randomness of the user at `index` in the job, keyed by the job id and the index
+fn user_randomness(random_seed: [u8; 32], job_id: &str, index: usize) -> [u8; 32] {
+    sub_randomness_with_key(random_seed, format!("{}/{}", job_id, index)).provide()
+}
+
+/// get the kind of a gem, from the registered token uri for dragon gems or from the attributes for auragon gems
+fn query_gem_kind(
+    querier: &QuerierWrapper,
+    storage: &dyn Storage,
+    dragon_collection: &Addr,
+    gem: &GemInfo,
+) -> Result<GemKind, ContractError> {
+    let query_msg = Cw721QueryMsg::NftInfo { token_id: gem.nft_id.clone() };
+    // the extension of the dragon gems is empty
+    let response: cw721::NftInfoResponse<Option<Metadata>> =
+        querier.query_wasm_smart(gem.nft_contract.to_string(), &query_msg)?;
+
+    if gem.nft_contract == *dragon_collection {
+        let gem_kind = match response.token_uri {
+            Some(ref token_uri) => DRAGON_GEMS.may_load(storage, token_uri)?,
+            None => None,
+        };
+        gem_kind.ok_or(ContractError::UnknownDragonGem {
+            nft_id: gem.nft_id.clone(),
+            token_uri: response.token_uri,
+        })
+    } else {
+        let attributes = response.extension.and_then(|extension| extension.attributes).unwrap_or_default();
+        gem_kind_from_attributes(&gem.nft_id, &attributes)
+    }
+}
+
+/// decode the kind of a gem from its "color" and "star" attributes, whatever their order
+fn gem_kind_from_attributes(nft_id: &str, attributes: &[Trait]) -> Result<GemKind, ContractError> {
+    let attribute = |trait_type: &str| {
+        attributes
+            .iter()
+            .find(|attribute| attribute.trait_type == trait_type)
+            .map(|attribute| attribute.value.as_str())
+            .ok_or(ContractError::MissingGemAttribute {
+                nft_id: nft_id.to_string(),
+                trait_type: trait_type.to_string(),
+            })
+    };
+    let invalid = |trait_type: &str, value: &str| ContractError::InvalidGemAttribute {
+        nft_id: nft_id.to_string(),
+        trait_type: trait_type.to_string(),
+        value: value.to_string(),
+    };
+
+    let color = attribute("color")?;
+    if color.is_empty() {
+        return Err(invalid("color", color));
+    }
+    let star = attribute("star")?;
+    let star = match star.parse::<u8>() {
+        Ok(star) if star > 0 => star,
+        _ => return Err(invalid("star", star)),
+    };
+    Ok(GemKind {
+        color: color.to_string(),
+        star,
+    })
+}
+
+/// lock a gem deposited by `user` for the queue entry
+fn lock_escrow(storage: &mut dyn Storage, gem: &GemInfo, user: &Addr, entry_id: u64) -> Result<(), ContractError> {
+    let key = (&gem.nft_contract, gem.nft_id.as_str());
+    let mut escrow = ESCROWS.may_load(storage, key)?.ok_or(ContractError::GemNotEscrowed {
+        nft_contract: gem.nft_contract.to_string(),
+        nft_id: gem.nft_id.clone(),
+    })?;
+    if escrow.owner != *user {
+        return Err(ContractError::Unauthorized {});
+    }
+    if let Some(entry_id) = escrow.entry_id {
+        return Err(ContractError::GemInUse {
+            nft_contract: gem.nft_contract.to_string(),
+            nft_id: gem.nft_id.clone(),
+            entry_id,
+        });
+    }
+    escrow.entry_id = Some(entry_id);
+    ESCROWS.save(storage, key, &escrow)?;
+    Ok(())
+}
+
+/// make sure the gem is escrowed for the queue entry
+fn ensure_escrowed(storage: &dyn Storage, gem: &GemInfo, entry_id: u64) -> Result<(), ContractError> {
+    match ESCROWS.may_load(storage, (&gem.nft_contract, &gem.nft_id))? {
+        Some(escrow) if escrow.entry_id == Some(entry_id) => Ok(()),
+        _ => Err(ContractError::GemNotEscrowed {
+            nft_contract: gem.nft_contract.to_string(),
+            nft_id: gem.nft_id.clone(),
+        }),
+    }
+}
+
+/// remove a queue entry and return its escrowed gems to the user
+fn refund_entry(
+    storage: &mut dyn Storage,
+    config: &Config,
+    entry_id: u64,
+    user_info: &UserInfo,
+) -> StdResult<Vec<WasmMsg>> {
+    remove_queue_entry(storage, entry_id)?;
+    entry_gems(config, user_info)
+        .iter()
+        .map(|gem| {
+            ESCROWS.remove(storage, (&gem.nft_contract, &gem.nft_id));
+            transfer_nft_msg(gem, &user_info.user_addr)
+        })
+        .collect()
+}
+
+/// add the coins to `total`, merging the coins of a same denom
+fn add_coins(total: &mut Vec<Coin>, coins: &[Coin]) {
+    for coin in coins {
+        match total.iter_mut().find(|total_coin| total_coin.denom == coin.denom) {
+            Some(total_coin) => total_coin.amount += coin.amount,
+            None => total.push(coin.clone()),
+        }
+    }
+}
+
+/// add the coins to the balances kept by denom
+fn add_balances(storage: &mut dyn Storage, balances: Map<&str, Uint128>, coins: &[Coin]) -> StdResult<()> {
+    for coin in coins {
+        add_balance(storage, balances.clone(), &coin.denom, coin.amount)?;
+    }
+    Ok(())
+}
+
+/// take the coins from the balances kept by denom
+fn sub_balances(storage: &mut dyn Storage, balances: Map<&str, Uint128>, coins: &[Coin]) -> StdResult<()> {
+    for coin in coins {
+        sub_balance(storage, balances.clone(), &coin.denom, coin.amount)?;
+    }
+    Ok(())
+}
+
+/// add an amount to a balance kept in storage
+fn add_balance<'a, K: PrimaryKey<'a>>(
+    storage: &mut dyn Storage,
+    balances: Map<'a, K, Uint128>,
+    key: K,
+    amount: Uint128,
+) -> StdResult<()> {
+    balances.update(storage, key, |balance| -> StdResult<_> {
+        Ok(balance.unwrap_or_default().checked_add(amount)?)
+    })?;
+    Ok(())
+}
+
+/// take an amount from a balance kept in storage, an emptied balance is removed
+fn sub_balance<'a, K: PrimaryKey<'a> + Clone>(
+    storage: &mut dyn Storage,
+    balances: Map<'a, K, Uint128>,
+    key: K,
+    amount: Uint128,
+) -> StdResult<()> {
+    let balance = balances
+        .may_load(storage, key.clone())?
+        .unwrap_or_default()
+        .checked_sub(amount)?;
+    if balance.is_zero() {
+        balances.remove(storage, key);
+    } else {
+        balances.save(storage, key, &balance)?;
+    }
+    Ok(())
+}
+
+/// coins set by the owner must have distinct denoms and non zero amounts
+fn validate_coins(coins: Vec<Coin>) -> Result<Vec<Coin>, ContractError> {
+    for (index, coin) in coins.iter().enumerate() {
+        if coin.amount.is_zero() {
+            return Err(ContractError::InvalidCoins { reason: format!("zero amount of {}", coin.denom) });
+        }
+        if coins[..index].iter().any(|other| other.denom == coin.denom) {
+            return Err(ContractError::InvalidCoins { reason: format!("duplicate denom {}", coin.denom) });
+        }
+    }
+    Ok(coins)
+}
+
+/// the gem_base, the materials and the shield of a queue entry
+fn entry_gems(config: &Config, user_info: &UserInfo) -> Vec<GemInfo> {
+    let mut gems = vec![user_info.gem_base.clone()];
+    gems.extend(user_info.gem_materials.iter().cloned());
+    if let Some(ref shield_id) = user_info.shield_id {
+        gems.push(GemInfo {
+            nft_id: shield_id.clone(),
+            nft_contract: config.shield_collection.clone(),
+        });
+    }
+    gems
+}
+
+/// transfer a gem held by this contract
+fn transfer_nft_msg(gem: &GemInfo, recipient: &Addr) -> StdResult<WasmMsg> {
+    wasm_execute(
+        gem.nft_contract.to_string(),
+        &Cw721ExecuteMsg::TransferNft {
+            recipient: recipient.to_string(),
+            token_id: gem.nft_id.clone(),
+        },
+        vec![],
+    )
+}
+
+/// transfer cw20 tokens held by this contract
+fn transfer_cw20_msg(token: &Addr, amount: Uint128, recipient: &Addr) -> StdResult<WasmMsg> {
+    wasm_execute(
+        token.to_string(),
+        &Cw20ExecuteMsg::Transfer {
+            recipient: recipient.to_string(),
+            amount,
+        },
+        vec![],
+    )
+}
+
+/// burn a gem held by this contract
+fn burn_nft_msg(gem: &GemInfo) -> StdResult<WasmMsg> {
+    wasm_execute(
+        gem.nft_contract.to_string(),
+        &Cw721ExecuteMsg::Burn {
+            token_id: gem.nft_id.clone(),
+        },
+        vec![],
+    )
+}
+
+/// work power of a gem used as a material
+fn gem_work_power(storage: &dyn Storage, gem_kind: &GemKind) -> Result<Decimal, ContractError> {
+    load_gem_tier(storage, &gem_kind.color)?
+        .work_powers
+        .get(usize::from(gem_kind.star).wrapping_sub(1))
+        .copied()
+        .ok_or(ContractError::StarOutOfRange {})
+}
+
+/// tier of the gems of a color
+fn load_gem_tier(storage: &dyn Storage, color: &str) -> Result<GemTier, ContractError> {
+    GEM_TIERS
+        .may_load(storage, color)?
+        .ok_or(ContractError::GemTierNotFound { color: color.to_string() })
+}
+
+/// the kind and the tier of a gem_base, a gem of a retired tier or of the max star cannot be upgraded
+fn upgradable_tier(
+    querier: &QuerierWrapper,
+    storage: &dyn Storage,
+    config: &Config,
+    gem_base: &GemInfo,
+) -> Result<(GemKind, GemTier), ContractError> {
+    let gem_kind = query_gem_kind(querier, storage, &config.dragon_collection, gem_base)?;
+    let tier = load_gem_tier(storage, &gem_kind.color)?;
+    if tier.retired {
+        return Err(ContractError::GemTierRetired { color: gem_kind.color });
+    }
+    if gem_kind.star >= tier.max_star {
+        return Err(ContractError::MaxStarReached { color: gem_kind.color, max_star: tier.max_star });
+    }
+    Ok((gem_kind, tier))
+}
+
+/// make sure the gem has a tier and its star is in range
+fn validate_gem(storage: &dyn Storage, gem: &GemKind) -> Result<(), ContractError> {
+    let tier = load_gem_tier(storage, &gem.color)?;
+    if gem.star == 0 || gem.star > tier.max_star {
+        return Err(ContractError::StarOutOfRange {});
+    }
+    Ok(())
+}
+
+/// validate the settings of a color, a tier has a uri and a work power per star and a work load per upgrade
+fn gem_tier_from_msg(msg: GemTierMsg) -> Result<(String, GemTier), ContractError> {
+    let invalid = |reason: &str| ContractError::InvalidGemTier {
+        color: msg.color.clone(),
+        reason: reason.to_string(),
+    };
+    // gems are identified as "color-star"
+    if msg.color.is_empty() || msg.color.contains('-') {
+        return Err(invalid("color must be non empty and without '-'"));
+    }
+    if msg.max_star == 0 {
+        return Err(invalid("max_star must be at least 1"));
+    }
+    let max_star = usize::from(msg.max_star);
+    if msg.uris.len() != max_star {
+        return Err(invalid("there must be one uri per star"));
+    }
+    if msg.work_powers.len() != max_star {
+        return Err(invalid("there must be one work power per star"));
+    }
+    if msg.work_loads.len() != max_star - 1 {
+        return Err(invalid("there must be one work load per star below max_star"));
+    }
+    let forge_fees = validate_coins(msg.forge_fees).map_err(|err| invalid(&err.to_string()))?;
+    Ok((
+        msg.color,
+        GemTier {
+            display_name: msg.display_name,
+            max_star: msg.max_star,
+            uris: msg.uris,
+            work_powers: msg.work_powers,
+            work_loads: msg.work_loads,
+            forge_fees,
+            gem_ratio: msg.gem_ratio,
+            retired: false,
+        },
+    ))
+}
+
+/// success rate in basis points: the work power of the materials over the work load of the gem_base, capped at 100%
+fn compute_success_rate(material_work_power: Decimal, work_load: Decimal) -> u32 {
+    // a zero work load or an overflowing ratio means the upgrade is certain
+    let rate = material_work_power
+        .checked_div(work_load)
+        .unwrap_or(Decimal::MAX)
+        .min(Decimal::one());
+    let success_rate = Uint128::from(SUCCESS_RATE_PRECISION).mul_floor(rate);
+    success_rate.u128() as u32
+}
+
+/// Handling contract migration
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn migrate(mut deps: DepsMut, env: Env, msg: MigrateMsg) -> Result<Response, ContractError> {
+    let stored = get_contract_version(deps.storage)?;
+    if stored.contract != CONTRACT_NAME {
+        return Err(ContractError::InvalidMigrationContract {
+            contract: stored.contract,
+        });
+    }
+
+    // refuse to downgrade, the state of a newer version cannot be read back
+    let from_version: Version = stored.version.parse()?;
+    let to_version: Version = CONTRACT_VERSION.parse()?;
+    if from_version > to_version {
+        return Err(ContractError::MigrationDowngrade {
+            from: stored.version,
+            to: CONTRACT_VERSION.to_string(),
+        });
+    }
+
+    let (applied, attributes) = migrate_state(deps.branch(), &env, &from_version, &msg)?;
+
+    set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)?;
+
+    let mut res = Response::new()
+        .add_attribute("method", "migrate")
+        .add_attribute("from_version", stored.version)
+        .add_attribute("to_version", CONTRACT_VERSION);
+    if !applied.is_empty() {
+        res = res.add_attribute("migrations", applied.join(","));
+    }
+    Ok(res.add_attributes(attributes))
+}
+
+/// Handling contract query
+#[cfg_attr(not(feature = "library"), entry_point)]
+pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
+    match msg {
+        QueryMsg::Config {} => to_json_binary(&query_config(deps)?),
+        QueryMsg::RandomGemSale {} => to_json_binary(&RandomGemSaleResponse {
+            sale: RANDOM_GEM_SALE.may_load(deps.storage)?,
+            sold: RANDOM_GEMS_SOLD.may_load(deps.storage)?.unwrap_or_default(),
+        }),
+        QueryMsg::PauseState {} => to_json_binary(&PAUSE_STATE.may_load(deps.storage)?.unwrap_or_default()),
+        QueryMsg::RandomSeed {} => to_json_binary(&query_random_seed(deps)?),
+        QueryMsg::Ownership {} => to_json_binary(&query_ownership(deps)?),
+        QueryMsg::ForgeResult { job_id } => to_json_binary(&query_forge_result(deps, job_id)?),
+        QueryMsg::ForgeHistoryByUser { user, start_after, limit } => {
+            to_json_binary(&query_forge_history_by_user(deps, user, start_after, limit)?)
+        }
+        QueryMsg::RecentForges { start_after, limit } => {
+            to_json_binary(&query_recent_forges(deps, start_after, limit)?)
+        }
+        QueryMsg::Queue { start_after, limit } => to_json_binary(&query_queue(deps, start_after, limit)?),
+        QueryMsg::QueueLength {} => to_json_binary(&query_queue_length(deps)?),
+        QueryMsg::QueuePosition { user } => to_json_binary(&query_queue_position(deps, user)?),
+        QueryMsg::UserQueueEntries { user } => to_json_binary(&query_user_queue_entries(deps, user)?),
+        QueryMsg::Escrow { nft_contract, token_id } => to_json_binary(&query_escrow(deps, nft_contract, token_id)?),
+        QueryMsg::ExpiredJobs { start_after, limit } => {
+            to_json_binary(&query_expired_jobs(deps, env, start_after, limit)?)
+        }
+        QueryMsg::ExpiredRandomMints { start_after, limit } => {
+            to_json_binary(&query_expired_random_mints(deps, env, start_after, limit)?)
+        }
+        QueryMsg::GemTier { color } => to_json_binary(&query_gem_tier(deps, color)?),
+        QueryMsg::GemTiers { start_after, limit } => to_json_binary(&query_gem_tiers(deps, start_after, limit)?),
+        QueryMsg::DragonGem { token_uri } => to_json_binary(&query_dragon_gem(deps, token_uri)?),
+        QueryMsg::DragonGems { start_after, limit } => to_json_binary(&query_dragon_gems(deps, start_after, limit)?),
+        QueryMsg::NoisBalance {} => to_json_binary(&query_nois_balance(deps)?),
+        QueryMsg::Treasury {} => to_json_binary(&query_treasury(deps)?),
+        QueryMsg::AcceptedToken { token } => to_json_binary(&query_accepted_token(deps, token)?),
+        QueryMsg::AcceptedTokens { start_after, limit } => {
+            to_json_binary(&query_accepted_tokens(deps, start_after, limit)?)
+        }
+        QueryMsg::FailurePenalties {} => to_json_binary(&query_failure_penalties(deps)?),
+        QueryMsg::SalvageConfig {} => to_json_binary(&SALVAGE_CONFIG.may_load(deps.storage)?),
+        QueryMsg::Fragments { user } => to_json_binary(&query_fragments(deps, user)?),
+        QueryMsg::AllFragments { start_after, limit } => {
+            to_json_binary(&query_all_fragments(deps, start_after, limit)?)
+        }
+        QueryMsg::PityConfig {} => to_json_binary(&PITY_CONFIG.may_load(deps.storage)?),
+        QueryMsg::PityStatus { user } => to_json_binary(&query_pity_status(deps, user)?),
+        QueryMsg::FusionRules {} => to_json_binary(&query_fusion_rules(deps)?),
+    }
+}
+
+fn query_config(deps: Deps) -> StdResult<Config> {
+    CONFIG.load(deps.storage)
+}
+
+fn query_random_seed(deps: Deps) -> StdResult<[u8; 32]> {
+    RANDOM_SEED.load(deps.storage)
+}
+
+fn query_ownership(deps: Deps) -> StdResult<OwnershipResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    Ok(OwnershipResponse {
+        owner: config.owner,
+        pending_owner: PENDING_OWNER.may_load(deps.storage)?,
+    })
+}
+
+fn query_forge_result(deps: Deps, job_id: String) -> StdResult<ForgeResultsResponse> {
+    let results = forge_results()
+        .prefix(job_id)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| item.map(|(_, result)| result))
+        .collect::<StdResult<_>>()?;
+    Ok(ForgeResultsResponse { results })
+}
+
+fn query_forge_history_by_user(
+    deps: Deps,
+    user: String,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<ForgeResultsResponse> {
+    let user = deps.api.addr_validate(&user)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let results = forge_results()
+        .idx
+        .user
+        .prefix(user)
+        .range(deps.storage, None, start_after.map(Bound::exclusive), Order::Descending)
+        .take(limit)
+        .map(|item| item.map(|(_, result)| result))
+        .collect::<StdResult<_>>()?;
+    Ok(ForgeResultsResponse { results })
+}
+
+fn query_recent_forges(
+    deps: Deps,
+    start_after: Option<u64>,
+    limit: Option<u32>,
+) -> StdResult<ForgeResultsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let results = forge_results()
+        .idx
+        .id
+        .range(deps.storage, None, start_after.map(Bound::exclusive), Order::Descending)
+        .take(limit)
+        .map(|item| item.map(|(_, result)| result))
+        .collect::<StdResult<_>>()?;
+    Ok(ForgeResultsResponse { results })
+}
+
+fn query_queue(deps: Deps, start_after: Option<u64>, limit: Option<u32>) -> StdResult<QueueResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    // the position of the first entry is the number of entries ahead of it
+    let ahead = match start_after {
+        Some(start_after) => entries_ahead(deps.storage, start_after.saturating_add(1))?,
+        None => 0,
+    };
+    let entries = users_in_queue()
+        .range(deps.storage, start_after.map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .enumerate()
+        .map(|(index, item)| {
+            let (entry_id, user_info) = item?;
+            Ok(QueueEntryResponse {
+                entry_id,
+                position: ahead + index as u32,
+                user_info,
+            })
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(QueueResponse { entries })
+}
+
+fn query_queue_length(deps: Deps) -> StdResult<QueueLengthResponse> {
+    Ok(QueueLengthResponse {
+        length: QUEUE_LENGTH.load(deps.storage)?,
+    })
+}
+
+fn query_queue_position(deps: Deps, user: String) -> StdResult<QueuePositionResponse> {
+    let user = deps.api.addr_validate(&user)?;
+    let first_entry = users_in_queue()
+        .idx
+        .user
+        .prefix(user)
+        .keys(deps.storage, None, None, Order::Ascending)
+        .next()
+        .transpose()?;
+    let position = first_entry
+        .map(|entry_id| entries_ahead(deps.storage, entry_id))
+        .transpose()?;
+    Ok(QueuePositionResponse { position })
+}
+
+fn query_user_queue_entries(deps: Deps, user: String) -> StdResult<QueueResponse> {
+    let user = deps.api.addr_validate(&user)?;
+    let entries = users_in_queue()
+        .idx
+        .user
+        .prefix(user)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (entry_id, user_info) = item?;
+            Ok(QueueEntryResponse {
+                entry_id,
+                position: entries_ahead(deps.storage, entry_id)?,
+                user_info,
+            })
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(QueueResponse { entries })
+}
+
+fn query_escrow(deps: Deps, nft_contract: String, token_id: String) -> StdResult<Option<Escrow>> {
+    let nft_contract = deps.api.addr_validate(&nft_contract)?;
+    ESCROWS.may_load(deps.storage, (&nft_contract, &token_id))
+}
+
+fn query_expired_jobs(
+    deps: Deps,
+    env: Env,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<RandomJobsResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let mut jobs = vec![];
+    for item in RANDOM_JOBS.range(deps.storage, start_after.map(Bound::exclusive), None, Order::Ascending) {
+        if jobs.len() == limit {
+            break;
+        }
+        let (job_id, random_job) = item?;
+        // the jobs whose randomness is buffered expire too if they are not settled in time
+        if random_job.timestamp.plus_seconds(config.job_timeout) <= env.block.time {
+            jobs.push((job_id, random_job));
+        }
+    }
+    Ok(RandomJobsResponse { jobs })
+}
+
+fn query_expired_random_mints(
+    deps: Deps,
+    env: Env,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<RandomMintsResponse> {
+    let config = CONFIG.load(deps.storage)?;
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let mut mints = vec![];
+    for item in RANDOM_MINTS.range(deps.storage, start_after.map(Bound::exclusive), None, Order::Ascending) {
+        if mints.len() == limit {
+            break;
+        }
+        let (job_id, random_mint) = item?;
+        if random_mint.timestamp.plus_seconds(config.job_timeout) <= env.block.time {
+            mints.push((job_id, random_mint));
+        }
+    }
+    Ok(RandomMintsResponse { mints })
+}
+
+fn query_gem_tier(deps: Deps, color: String) -> StdResult<GemTier> {
+    GEM_TIERS.load(deps.storage, &color)
+}
+
+fn query_gem_tiers(deps: Deps, start_after: Option<String>, limit: Option<u32>) -> StdResult<GemTiersResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let tiers = GEM_TIERS
+        .range(deps.storage, start_after.as_deref().map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<_>>()?;
+    Ok(GemTiersResponse { tiers })
+}
+
+fn query_dragon_gem(deps: Deps, token_uri: String) -> StdResult<Option<GemKind>> {
+    DRAGON_GEMS.may_load(deps.storage, &token_uri)
+}
+
+fn query_dragon_gems(deps: Deps, start_after: Option<String>, limit: Option<u32>) -> StdResult<DragonGemsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let gems = DRAGON_GEMS
+        .range(deps.storage, start_after.as_deref().map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<_>>()?;
+    Ok(DragonGemsResponse { gems })
+}
+
+fn query_nois_balance(deps: Deps) -> StdResult<NoisBalanceResponse> {
+    let balances = NOIS_BALANCE
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (denom, amount) = item?;
+            Ok(Coin { denom, amount })
+        })
+        .collect::<StdResult<_>>()?;
+    let nois_price = CONFIG.load(deps.storage)?.nois_price;
+    Ok(NoisBalanceResponse { balances, nois_price })
+}
+
+fn query_treasury(deps: Deps) -> StdResult<TreasuryResponse> {
+    let balances = TREASURY
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (denom, amount) = item?;
+            Ok(Coin { denom, amount })
+        })
+        .collect::<StdResult<_>>()?;
+    let cw20_balances = CW20_TREASURY
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (address, amount) = item?;
+            Ok(Cw20CoinVerified { address, amount })
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(TreasuryResponse { balances, cw20_balances })
+}
+
+fn query_accepted_token(deps: Deps, token: String) -> StdResult<Option<AcceptedToken>> {
+    let token = deps.api.addr_validate(&token)?;
+    ACCEPTED_TOKENS.may_load(deps.storage, &token)
+}
+
+fn query_accepted_tokens(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<AcceptedTokensResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start_after = start_after.map(|token| deps.api.addr_validate(&token)).transpose()?;
+    let tokens = ACCEPTED_TOKENS
+        .range(deps.storage, start_after.as_ref().map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<_>>()?;
+    Ok(AcceptedTokensResponse { tokens })
+}
 
-/// Handling contract query
-#[cfg_attr(not(feature = "library"), entry_point)]
-pub fn query(deps: Deps, env: Env, msg: QueryMsg) -> StdResult<Binary> {
-    match msg {
-        QueryMsg::Config {} => to_json_binary(&query_config(deps)?),
-        QueryMsg::RandomSeed {} => to_json_binary(&query_random_seed(deps)?),
-    }
+fn query_failure_penalties(deps: Deps) -> StdResult<FailurePenaltiesResponse> {
+    let penalties = GEM_TIERS
+        .keys(deps.storage, None, None, Order::Ascending)
+        .map(|color| {
+            let color = color?;
+            let penalty = FAILURE_PENALTIES.may_load(deps.storage, &color)?.unwrap_or(FailurePenalty::Keep);
+            Ok((color, penalty))
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(FailurePenaltiesResponse { penalties })
 }
 
-fn query_config(deps: Deps) -> StdResult<Config> {
-    CONFIG.load(deps.storage)
+fn query_pity_status(deps: Deps, user: String) -> StdResult<PityStatusResponse> {
+    let user = deps.api.addr_validate(&user)?;
+    let pity_config = PITY_CONFIG.may_load(deps.storage)?;
+    let statuses = PITY_COUNTERS
+        .prefix(&user)
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let (color, failures) = item?;
+            let bonus = pity_bonus(pity_config.as_ref(), failures);
+            Ok(PityStatus { color, failures, bonus })
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(PityStatusResponse { statuses })
 }
 
-fn query_random_seed(deps: Deps) -> StdResult<[u8; 32]> {
-    RANDOM_SEED.load(deps.storage)
+fn query_fragments(deps: Deps, user: String) -> StdResult<FragmentsResponse> {
+    let user = deps.api.addr_validate(&user)?;
+    let fragments = FRAGMENTS.may_load(deps.storage, &user)?.unwrap_or_default();
+    Ok(FragmentsResponse { fragments })
+}
+
+fn query_all_fragments(
+    deps: Deps,
+    start_after: Option<String>,
+    limit: Option<u32>,
+) -> StdResult<AllFragmentsResponse> {
+    let limit = limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT) as usize;
+    let start_after = start_after.map(|user| deps.api.addr_validate(&user)).transpose()?;
+    let fragments = FRAGMENTS
+        .range(deps.storage, start_after.as_ref().map(Bound::exclusive), None, Order::Ascending)
+        .take(limit)
+        .collect::<StdResult<_>>()?;
+    Ok(AllFragmentsResponse { fragments })
+}
+
+fn query_fusion_rules(deps: Deps) -> StdResult<FusionRulesResponse> {
+    let rules = FUSION_RULES
+        .range(deps.storage, None, None, Order::Ascending)
+        .map(|item| {
+            let ((color, star), rule) = item?;
+            Ok((color, star, rule))
+        })
+        .collect::<StdResult<_>>()?;
+    Ok(FusionRulesResponse { rules })
 }
 
 /// validate string if it is valid bench32 string addresss
@@ -1076,49 +2746,1106 @@ fn addr_validate(api: &dyn Api, addr: &str) -> Result<Addr, ContractError> {
     Ok(addr)
 }
 
+/// fail if the owner paused the operation
+fn ensure_not_paused(
+    storage: &dyn Storage,
+    operation: &str,
+    is_paused: fn(&PauseState) -> bool,
+) -> Result<(), ContractError> {
+    let pause_state = PAUSE_STATE.may_load(storage)?.unwrap_or_default();
+    if is_paused(&pause_state) {
+        return Err(ContractError::Paused { operation: operation.to_string() });
+    }
+    Ok(())
+}
+
+/// only the current owner is allowed to send privileged messages
+fn ensure_owner(config: &Config, sender: &Addr) -> Result<(), ContractError> {
+    match config.owner {
+        Some(ref owner) if owner == sender => Ok(()),
+        _ => Err(ContractError::Unauthorized {}),
+    }
+}
+
+// Storage shared by the unit tests
+#[cfg(test)]
+mod test_fixtures {
+    use cosmwasm_std::{testing::{mock_dependencies, MockApi, MockQuerier, MockStorage}, to_json_binary, Addr, ContractResult, Decimal, OwnedDeps, SystemResult, WasmQuery};
+    use cw721::NftInfoResponse;
+
+    use crate::state::{Config, GemKind, GemTier, Metadata, AURAGON_LATEST_TOKEN_ID, CONFIG, DRAGON_GEMS, GEM_TIERS, QUEUE_LENGTH, SHIELD_LATEST_TOKEN_ID, SHIELD_URI};
+
+    // an empty queue, white gems have 2 stars and every gem is a white-1 dragon gem
+    pub fn mock_deps_with_white_tier() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { .. } => {
+                let nft_info = NftInfoResponse::<Option<Metadata>> {
+                    token_uri: Some("ipfs://white-1".to_string()),
+                    extension: None,
+                };
+                SystemResult::Ok(ContractResult::Ok(to_json_binary(&nft_info).unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+        let config = Config {
+            owner: Some(Addr::unchecked("owner")),
+            nois_proxy: Addr::unchecked("nois_proxy"),
+            dragon_collection: Addr::unchecked("dragon_collection"),
+            auragon_collection: Addr::unchecked("auragon_collection"),
+            shield_collection: Addr::unchecked("shield_collection"),
+            job_timeout: 3600,
+            nois_price: vec![],
+        };
+        CONFIG.save(&mut deps.storage, &config).unwrap();
+        let tier = GemTier {
+            display_name: "White".to_string(),
+            max_star: 2,
+            uris: vec!["ipfs://white-1".to_string(), "ipfs://white-2".to_string()],
+            work_powers: vec![Decimal::one(); 2],
+            work_loads: vec![Decimal::one()],
+            forge_fees: vec![],
+            gem_ratio: Decimal::zero(),
+            retired: false,
+        };
+        GEM_TIERS.save(&mut deps.storage, "white", &tier).unwrap();
+        DRAGON_GEMS.save(&mut deps.storage, "ipfs://white-1", &GemKind { color: "white".to_string(), star: 1 }).unwrap();
+        SHIELD_URI.save(&mut deps.storage, &"ipfs://shield".to_string()).unwrap();
+        SHIELD_LATEST_TOKEN_ID.save(&mut deps.storage, &0).unwrap();
+        AURAGON_LATEST_TOKEN_ID.save(&mut deps.storage, &0).unwrap();
+        QUEUE_LENGTH.save(&mut deps.storage, &0).unwrap();
+        deps
+    }
+}
+
 // Unit test for select_gem_rewards
 #[cfg(test)]
 mod test_select_gem_rewards {
-    use cosmwasm_std::{testing::mock_dependencies, Addr, Timestamp};
+    use cosmwasm_std::{from_json, testing::{mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage}, Addr, CosmosMsg, Decimal, Empty, HexBinary, OwnedDeps, Response, Storage, Timestamp, WasmMsg};
+    use cw721_base::ExecuteMsg as Cw721BaseExecuteMsg;
+    use nois::{sub_randomness, NoisCallback};
+
+    use crate::{contract::{nois_receive, pity_bonus, push_queue_entry, query, select_gem_rewards, user_randomness}, error::ContractError, msg::{ForgeResultsResponse, PityStatusResponse, QueryMsg}, state::{Config, FailurePenalty, ForgeOutcome, ForgeResult, GemInfo, GemKind, GemTier, JobParticipant, Metadata, PityConfig, RandomJob, UserInfo, AURAGON_LATEST_TOKEN_ID, CONFIG, FAILURE_PENALTIES, GEM_TIERS, PITY_CONFIG, PITY_COUNTERS, QUEUE_LENGTH, users_in_queue}};
+
+    // "color-star" as a gem kind
+    fn gem_kind(color_and_star: &str) -> GemKind {
+        let (color, star) = color_and_star.split_once('-').unwrap();
+        GemKind {
+            color: color.to_string(),
+            star: star.parse().unwrap(),
+        }
+    }
+
+    fn participant(user_addr: &str, gem_base_color_and_star: &str, success_rate: u32) -> JobParticipant {
+        JobParticipant {
+            entry_id: None,
+            user_addr: Addr::unchecked(user_addr),
+            gem_base: Some(GemInfo {
+                nft_id: format!("{}-base", user_addr),
+                nft_contract: Addr::unchecked("auragon_collection"),
+            }),
+            gem_base_kind: gem_kind(gem_base_color_and_star),
+            gem_materials: vec![GemInfo {
+                nft_id: format!("{}-material", user_addr),
+                nft_contract: Addr::unchecked("auragon_collection"),
+            }],
+            shield_id: None,
+            success_rate,
+        }
+    }
+
+    fn query_results(deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, msg: QueryMsg) -> Vec<ForgeResult> {
+        from_json::<ForgeResultsResponse>(query(deps.as_ref(), mock_env(), msg).unwrap()).unwrap().results
+    }
+
+    const RANDOM_SEED: [u8; 32] = [231, 176, 72, 156, 81, 254, 186, 90, 6, 217, 100, 59, 104, 255, 174, 43, 10, 192, 5, 213, 175, 182, 53, 224, 165, 219, 23, 212, 104, 217, 54, 105];
+
+    fn setup_storage() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        QUEUE_LENGTH.save(&mut deps.storage, &0).unwrap();
+        let config = Config {
+            owner: Some(Addr::unchecked("owner")),
+            nois_proxy: Addr::unchecked("nois_proxy"),
+            auragon_collection: Addr::unchecked("auragon_collection"),
+            shield_collection: Addr::unchecked("shield_collection"),
+            job_timeout: 3600,
+            nois_price: vec![],
+            dragon_collection: Addr::unchecked("dragon_collection"),
+        };
+        CONFIG.save(&mut deps.storage, &config).unwrap();
+        AURAGON_LATEST_TOKEN_ID.save(&mut deps.storage, &0).unwrap();
+        save_gem_tiers(&mut deps.storage);
+        deps
+    }
+
+    // a 7 star tier for each of the four colors
+    fn save_gem_tiers(storage: &mut dyn Storage) {
+        for color in ["white", "blue", "gold", "red"] {
+            let tier = GemTier {
+                display_name: color.to_string(),
+                max_star: 7,
+                uris: (1..=7).map(|star| format!("ipfs://{}-{}", color, star)).collect(),
+                work_powers: vec![Decimal::one(); 7],
+                work_loads: vec![Decimal::one(); 6],
+                forge_fees: vec![],
+                gem_ratio: Decimal::zero(),
+                retired: false,
+            };
+            GEM_TIERS.save(storage, color, &tier).unwrap();
+        }
+    }
+
+    // a job of `users` users forging a white-1 gem with the same success rate
+    fn uniform_job(users: usize, success_rate: u32) -> RandomJob {
+        RandomJob {
+            participants: (0..users).map(|i| participant(&format!("addr{}", i), "white-1", success_rate)).collect(),
+            timestamp: Timestamp::from_seconds(0),
+            payer: None,
+            fees: vec![],
+            cw20_fees: vec![],
+        }
+    }
+
+    // the (owner, color, star) of every gem minted in the response
+    fn minted_gems(res: &Response) -> Vec<(String, String, String)> {
+        cw721_messages(res)
+            .into_iter()
+            .filter_map(|(_, msg)| match msg {
+                Cw721BaseExecuteMsg::Mint { owner, extension, .. } => {
+                    let attributes = extension.attributes.unwrap();
+                    Some((owner, attributes[0].value.clone(), attributes[1].value.clone()))
+                }
+                _ => None,
+            })
+            .collect()
+    }
 
-    use crate::{contract::select_gem_rewards, state::{AuragonURI, Config, RandomJob, AURAGON_LATEST_TOKEN_ID, AURAGON_URI, CONFIG, RANDOM_JOBS}};
+    // every cw721 message of the response along with the collection it is sent to
+    fn cw721_messages(res: &Response) -> Vec<(String, Cw721BaseExecuteMsg<Metadata, Empty>)> {
+        res.messages
+            .iter()
+            .map(|sub_msg| match &sub_msg.msg {
+                CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => (contract_addr.clone(), from_json(msg).unwrap()),
+                _ => panic!("unexpected message"),
+            })
+            .collect()
+    }
 
     #[test]
     fn test_select_gem_rewards() {
         let mut deps = mock_dependencies();
         let config = {
             Config {
+                owner: Some(Addr::unchecked("owner")),
                 nois_proxy: Addr::unchecked("nois_proxy"),
                 auragon_collection: Addr::unchecked("auragon_collection"),
                 shield_collection: Addr::unchecked("shield_collection"),
+                job_timeout: 3600,
+                nois_price: vec![],
                 dragon_collection: Addr::unchecked("dragon_collection"),
             }
         };
         CONFIG.save(&mut deps.storage, &config).unwrap();
         let auragon_gem_latest_token_id = 1;
         AURAGON_LATEST_TOKEN_ID.save(&mut deps.storage, &auragon_gem_latest_token_id).unwrap();
-        let auragon_uri = AuragonURI {
-            white: ["ipfs://1".to_string(), "ipfs://2".to_string(), "ipfs://3".to_string(), "ipfs://4".to_string(), "ipfs://5".to_string(), "ipfs://6".to_string(), "ipfs://7".to_string()],
-            blue: ["ipfs://1".to_string(), "ipfs://2".to_string(), "ipfs://3".to_string(), "ipfs://4".to_string(), "ipfs://5".to_string(), "ipfs://6".to_string(), "ipfs://7".to_string()],
-            gold: ["ipfs://1".to_string(), "ipfs://2".to_string(), "ipfs://3".to_string(), "ipfs://4".to_string(), "ipfs://5".to_string(), "ipfs://6".to_string(), "ipfs://7".to_string()],
-            red: ["ipfs://1".to_string(), "ipfs://2".to_string(), "ipfs://3".to_string(), "ipfs://4".to_string(), "ipfs://5".to_string(), "ipfs://6".to_string(), "ipfs://7".to_string()],
-        };
-        AURAGON_URI.save(&mut deps.storage, &auragon_uri).unwrap();
+        save_gem_tiers(&mut deps.storage);
 
         let random_job = RandomJob {
-            gem_base_nft_color_and_star_user_list: vec!["white-1".to_string(), "blue-1".to_string(), "gold-1".to_string(), "red-1".to_string()],
-            user_success_rate_list: vec![(Addr::unchecked("addr1"), 100), (Addr::unchecked("addr2"), 100), (Addr::unchecked("addr3"), 100), (Addr::unchecked("addr4"), 100)],
+            participants: vec![participant("addr1", "white-1", 10_000), participant("addr2", "blue-1", 10_000), participant("addr3", "gold-1", 10_000), participant("addr4", "red-1", 10_000)],
             timestamp: Timestamp::from_seconds(0),
+            payer: None,
+            fees: vec![],
+            cw20_fees: vec![],
         };
-        let key = "1".to_string();
-
-        RANDOM_JOBS.save(&mut deps.storage, key.clone(), &random_job).unwrap();
         let random_seed: [u8; 32] = [231, 176, 72, 156, 81, 254, 186, 90, 6, 217, 100, 59, 104, 255, 174, 43, 10, 192, 5, 213, 175, 182, 53, 224, 165, 219, 23, 212, 104, 217, 54, 105];
-        let forges = Timestamp::from_seconds(0);
-        let res = select_gem_rewards(&mut deps.storage, random_seed, &key, forges);
-        // print AURA LATEST TOKEN ID
+        let res = select_gem_rewards(&mut deps.storage, random_seed, "1", random_job).unwrap();
+        // every user has a 100% success rate so each of them gets a new gem
+        assert_eq!(res.messages.len(), 4);
         let auragon_gem_latest_token_id = AURAGON_LATEST_TOKEN_ID.load(&deps.storage).unwrap();
-        println!("AURA LATEST TOKEN: {:?}", auragon_gem_latest_token_id);
-        assert_eq!(res.unwrap().messages, vec![]);
+        assert_eq!(auragon_gem_latest_token_id, 5);
+    }
+
+    #[test]
+    fn test_each_user_gets_its_own_gem_base_upgraded() {
+        let mut deps = setup_storage();
+        let random_job = RandomJob {
+            participants: vec![participant("addr1", "white-1", 10_000), participant("addr2", "blue-2", 10_000), participant("addr3", "gold-3", 10_000)],
+            timestamp: Timestamp::from_seconds(0),
+            payer: None,
+            fees: vec![],
+            cw20_fees: vec![],
+        };
+        let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, "1", random_job).unwrap();
+        assert_eq!(
+            minted_gems(&res),
+            vec![
+                ("addr1".to_string(), "white".to_string(), "2".to_string()),
+                ("addr2".to_string(), "blue".to_string(), "3".to_string()),
+                ("addr3".to_string(), "gold".to_string(), "4".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_user_randomness() {
+        // same job and index always give the same randomness
+        assert_eq!(user_randomness(RANDOM_SEED, "1", 0), user_randomness(RANDOM_SEED, "1", 0));
+        // another index or another job gives another randomness
+        assert_ne!(user_randomness(RANDOM_SEED, "1", 0), user_randomness(RANDOM_SEED, "1", 1));
+        assert_ne!(user_randomness(RANDOM_SEED, "1", 0), user_randomness(RANDOM_SEED, "2", 0));
+        assert_ne!(user_randomness(RANDOM_SEED, "1", 0), RANDOM_SEED);
+    }
+
+    #[test]
+    fn test_users_with_same_rate_in_a_job_have_independent_outcomes() {
+        let mut deps = setup_storage();
+        // with a shared randomness everybody would either succeed or fail together
+        let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, "1", uniform_job(200, 5_000)).unwrap();
+        let successes = minted_gems(&res).len();
+        assert!((70..=130).contains(&successes), "{} successes out of 200", successes);
+    }
+
+    #[test]
+    fn test_success_distribution_follows_success_rate() {
+        let mut deps = setup_storage();
+        let mut seeds = sub_randomness(RANDOM_SEED);
+        for success_rate in [1_000u32, 2_500, 5_000, 9_000] {
+            // 100 jobs of 20 users
+            let mut successes = 0;
+            for job in 0..100 {
+                let res = select_gem_rewards(&mut deps.storage, seeds.provide(), &job.to_string(), uniform_job(20, success_rate)).unwrap();
+                successes += minted_gems(&res).len();
+            }
+            // expected successes out of 2000 draws, allowing ~4 standard deviations
+            let expected = 2_000 * success_rate as usize / 10_000;
+            assert!(successes.abs_diff(expected) <= 90, "{} successes out of 2000 with rate {}", successes, success_rate);
+        }
+    }
+
+    #[test]
+    fn test_certain_and_impossible_outcomes() {
+        let mut deps = setup_storage();
+        let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, "1", uniform_job(50, 10_000)).unwrap();
+        assert_eq!(minted_gems(&res).len(), 50);
+        let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, "2", uniform_job(50, 0)).unwrap();
+        assert_eq!(minted_gems(&res).len(), 0);
+    }
+
+    #[test]
+    fn test_failures_in_a_row_raise_the_success_rate() {
+        let mut deps = setup_storage();
+        let pity_config = PityConfig { bonus_curve: vec![0, 10_000], max_bonus: 10_000 };
+        PITY_CONFIG.save(&mut deps.storage, &pity_config).unwrap();
+        let pity_status = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>| -> Vec<(String, u32, u32)> {
+            let msg = QueryMsg::PityStatus { user: "addr0".to_string() };
+            from_json::<PityStatusResponse>(query(deps.as_ref(), mock_env(), msg).unwrap())
+                .unwrap()
+                .statuses
+                .into_iter()
+                .map(|status| (status.color, status.failures, status.bonus))
+                .collect()
+        };
+
+        // an impossible upgrade fails until the bonus makes it certain
+        for (job_id, failures, bonus) in [("1", 1, 0), ("2", 2, 10_000)] {
+            let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, job_id, uniform_job(1, 0)).unwrap();
+            assert!(minted_gems(&res).is_empty());
+            assert_eq!(pity_status(&deps), vec![("white".to_string(), failures, bonus)]);
+        }
+        let mut blue_job = uniform_job(1, 0);
+        blue_job.participants[0].gem_base_kind = gem_kind("blue-1");
+        select_gem_rewards(&mut deps.storage, RANDOM_SEED, "3", blue_job).unwrap();
+        let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, "4", uniform_job(1, 0)).unwrap();
+        assert_eq!(minted_gems(&res).len(), 1);
+        let results = query_results(&deps, QueryMsg::ForgeResult { job_id: "4".to_string() });
+        assert_eq!((results[0].success_rate, results[0].pity_bonus), (0, 10_000));
+
+        // the counter of the color is reset on success, the other colors are counted on their own
+        assert_eq!(pity_status(&deps), vec![("blue".to_string(), 1, 0)]);
+        assert!(PITY_COUNTERS.may_load(&deps.storage, (&Addr::unchecked("addr0"), "white")).unwrap().is_none());
+
+        // the bonus is capped and the last step applies to the longer streaks
+        let pity_config = PityConfig { bonus_curve: vec![1_000, 8_000], max_bonus: 5_000 };
+        assert_eq!(pity_bonus(Some(&pity_config), 0), 0);
+        assert_eq!(pity_bonus(Some(&pity_config), 1), 1_000);
+        assert_eq!(pity_bonus(Some(&pity_config), 2), 5_000);
+        assert_eq!(pity_bonus(Some(&pity_config), 9), 5_000);
+        assert_eq!(pity_bonus(None, 9), 0);
+    }
+
+    #[test]
+    fn test_boosted_success_rate_is_capped() {
+        let mut deps = setup_storage();
+        let pity_config = PityConfig { bonus_curve: vec![5_000], max_bonus: 10_000 };
+        PITY_CONFIG.save(&mut deps.storage, &pity_config).unwrap();
+        for i in 0..50 {
+            PITY_COUNTERS.save(&mut deps.storage, (&Addr::unchecked(format!("addr{}", i)), "white"), &1).unwrap();
+        }
+
+        // 9_000 + 5_000 basis points make a certain upgrade rather than an invalid rate
+        let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, "1", uniform_job(50, 9_000)).unwrap();
+        assert_eq!(minted_gems(&res).len(), 50);
+        let results = query_results(&deps, QueryMsg::ForgeResult { job_id: "1".to_string() });
+        assert!(results.iter().all(|result| (result.success_rate, result.pity_bonus) == (9_000, 5_000)));
+    }
+
+    #[test]
+    fn test_invalid_participants_are_rejected() {
+        let mut deps = setup_storage();
+        let mut settle = |gem_base_color_and_star: &str, success_rate: u32| {
+            let random_job = RandomJob {
+                participants: vec![participant("addr1", gem_base_color_and_star, success_rate)],
+                timestamp: Timestamp::from_seconds(0),
+                payer: None,
+                fees: vec![],
+                cw20_fees: vec![],
+            };
+            select_gem_rewards(&mut deps.storage, RANDOM_SEED, "1", random_job).unwrap_err()
+        };
+        assert!(matches!(settle("white-1", 10_001), ContractError::InvalidSuccessRate { success_rate: 10_001 }));
+        assert!(matches!(settle("white-1", u32::MAX), ContractError::InvalidSuccessRate { .. }));
+        // there is no star above the max star of the tier nor above 255
+        assert!(matches!(settle("white-7", 10_000), ContractError::StarOutOfRange {}));
+        assert!(matches!(settle("white-255", 10_000), ContractError::StarOutOfRange {}));
+        assert!(matches!(settle("pink-1", 10_000), ContractError::GemTierNotFound { color } if color == "pink"));
+    }
+
+    #[test]
+    fn test_nois_callback_is_checked() {
+        let mut deps = setup_storage();
+        let callback = |randomness: Vec<u8>| NoisCallback {
+            job_id: "1".to_string(),
+            published: Timestamp::from_seconds(1),
+            randomness: HexBinary::from(randomness),
+        };
+        let err = nois_receive(deps.as_mut(), mock_env(), mock_info("user", &[]), callback(vec![0; 32])).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let err = nois_receive(deps.as_mut(), mock_env(), mock_info("nois_proxy", &[]), callback(vec![0; 31])).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidRandomness {}));
+        let err = nois_receive(deps.as_mut(), mock_env(), mock_info("nois_proxy", &[]), callback(vec![0; 32])).unwrap_err();
+        assert!(matches!(err, ContractError::RandomJobNotFound {}));
+    }
+
+    // settle a queue entry of addr1 forging a white gem, returns the cw721 messages as (collection, action, token_id) and the result
+    fn settle_entry(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        gem_base_color_and_star: &str,
+        success_rate: u32,
+        shield_id: Option<&str>,
+    ) -> (Vec<(String, String, String)>, ForgeResult) {
+        let mut queued = participant("addr1", gem_base_color_and_star, success_rate);
+        queued.entry_id = Some(3);
+        queued.shield_id = shield_id.map(str::to_string);
+        let user_info = UserInfo {
+            user_addr: queued.user_addr.clone(),
+            gem_base: queued.gem_base.clone().unwrap(),
+            gem_materials: queued.gem_materials.clone(),
+            shield_id: queued.shield_id.clone(),
+            timestamp: Timestamp::from_seconds(0),
+            job_id: Some("1".to_string()),
+        };
+        push_queue_entry(&mut deps.storage, 3, &user_info).unwrap();
+        let random_job = RandomJob {
+            participants: vec![queued],
+            timestamp: Timestamp::from_seconds(0),
+            payer: None,
+            fees: vec![],
+            cw20_fees: vec![],
+        };
+        let res = select_gem_rewards(&mut deps.storage, RANDOM_SEED, "1", random_job).unwrap();
+        // the entry leaves the queue
+        assert!(!users_in_queue().has(&deps.storage, 3));
+        let messages = cw721_messages(&res)
+            .into_iter()
+            .map(|(contract, msg)| match msg {
+                Cw721BaseExecuteMsg::Mint { token_id, extension, .. } => {
+                    let attributes = extension.attributes.unwrap();
+                    (contract, format!("mint {}-{}", attributes[0].value, attributes[1].value), token_id)
+                }
+                Cw721BaseExecuteMsg::TransferNft { recipient, token_id } => (contract, format!("transfer to {}", recipient), token_id),
+                Cw721BaseExecuteMsg::Burn { token_id } => (contract, "burn".to_string(), token_id),
+                _ => panic!("unexpected cw721 message"),
+            })
+            .collect();
+        let results = query_results(deps, QueryMsg::ForgeResult { job_id: "1".to_string() });
+        (messages, results[0].clone())
+    }
+
+    fn message(contract: &str, action: &str, token_id: &str) -> (String, String, String) {
+        (contract.to_string(), action.to_string(), token_id.to_string())
+    }
+
+    #[test]
+    fn test_queue_entry_success() {
+        let mut deps = setup_storage();
+        FAILURE_PENALTIES.save(&mut deps.storage, "white", &FailurePenalty::Burn).unwrap();
+        let (messages, result) = settle_entry(&mut deps, "white-2", 10_000, Some("shield"));
+        // the gem_base and the material are consumed by the upgrade while the unused shield goes back to the user
+        assert_eq!(
+            messages,
+            vec![
+                message("auragon_collection", "mint white-3", "1"),
+                message("auragon_collection", "burn", "addr1-material"),
+                message("shield_collection", "transfer to addr1", "shield"),
+                message("auragon_collection", "burn", "addr1-base"),
+            ]
+        );
+        assert_eq!(result.outcome, ForgeOutcome::Success);
+        assert_eq!(result.penalty, None);
+        assert!(!result.shield_used);
+    }
+
+    #[test]
+    fn test_queue_entry_failure_penalties() {
+        // (penalty, gem_base, penalty applied, messages for the gem_base)
+        let cases = [
+            (None, "white-2", FailurePenalty::Keep, vec![message("auragon_collection", "transfer to addr1", "addr1-base")]),
+            (Some(FailurePenalty::Keep), "white-2", FailurePenalty::Keep, vec![message("auragon_collection", "transfer to addr1", "addr1-base")]),
+            (Some(FailurePenalty::Burn), "white-2", FailurePenalty::Burn, vec![message("auragon_collection", "burn", "addr1-base")]),
+            (
+                Some(FailurePenalty::Downgrade),
+                "white-2",
+                FailurePenalty::Downgrade,
+                vec![message("auragon_collection", "burn", "addr1-base"), message("auragon_collection", "mint white-1", "1")],
+            ),
+            // a 1 star gem cannot be downgraded
+            (Some(FailurePenalty::Downgrade), "white-1", FailurePenalty::Keep, vec![message("auragon_collection", "transfer to addr1", "addr1-base")]),
+        ];
+        for (failure_penalty, gem_base_color_and_star, applied, base_messages) in cases {
+            let mut deps = setup_storage();
+            if let Some(ref failure_penalty) = failure_penalty {
+                FAILURE_PENALTIES.save(&mut deps.storage, "white", failure_penalty).unwrap();
+            }
+            let (messages, result) = settle_entry(&mut deps, gem_base_color_and_star, 0, None);
+            let mut expected = vec![message("auragon_collection", "burn", "addr1-material")];
+            expected.extend(base_messages);
+            assert_eq!(messages, expected, "{:?}", failure_penalty);
+            assert_eq!(result.outcome, ForgeOutcome::Failure);
+            assert_eq!(result.penalty, Some(applied));
+            assert_eq!(result.minted_token_id.is_some(), failure_penalty == Some(FailurePenalty::Downgrade) && gem_base_color_and_star == "white-2");
+        }
+    }
+
+    #[test]
+    fn test_shield_protects_gem_base() {
+        for failure_penalty in [FailurePenalty::Burn, FailurePenalty::Downgrade] {
+            let mut deps = setup_storage();
+            FAILURE_PENALTIES.save(&mut deps.storage, "white", &failure_penalty).unwrap();
+            let (messages, result) = settle_entry(&mut deps, "white-2", 0, Some("shield"));
+            // the shield is consumed and the gem_base goes back to the user
+            assert_eq!(
+                messages,
+                vec![
+                    message("auragon_collection", "burn", "addr1-material"),
+                    message("shield_collection", "burn", "shield"),
+                    message("auragon_collection", "transfer to addr1", "addr1-base"),
+                ]
+            );
+            assert!(result.shield_used);
+            assert_eq!(result.penalty, None);
+            assert_eq!(result.shield_id, Some("shield".to_string()));
+        }
+
+        // nothing to protect from, the shield goes back to the user
+        let mut deps = setup_storage();
+        let (messages, result) = settle_entry(&mut deps, "white-2", 0, Some("shield"));
+        assert!(messages.contains(&message("shield_collection", "transfer to addr1", "shield")));
+        assert!(!result.shield_used);
+    }
+
+    #[test]
+    fn test_forge_results_are_recorded() {
+        let mut deps = setup_storage();
+        let random_job = RandomJob {
+            participants: vec![participant("addr1", "white-1", 10_000), participant("addr2", "blue-2", 0)],
+            timestamp: Timestamp::from_seconds(7),
+            payer: None,
+            fees: vec![],
+            cw20_fees: vec![],
+        };
+        select_gem_rewards(&mut deps.storage, RANDOM_SEED, "job", random_job.clone()).unwrap();
+
+        let results = query_results(&deps, QueryMsg::ForgeResult { job_id: "job".to_string() });
+        assert_eq!(results.len(), 2);
+        for (index, (result, participant)) in results.iter().zip(random_job.participants).enumerate() {
+            assert_eq!(result.job_id, "job");
+            assert_eq!(result.user_addr, participant.user_addr);
+            assert_eq!(result.gem_base, participant.gem_base);
+            assert_eq!(result.gem_materials, participant.gem_materials);
+            assert_eq!(result.success_rate, participant.success_rate);
+            assert_eq!(result.randomness, HexBinary::from(user_randomness(RANDOM_SEED, "job", index)));
+            assert_eq!(result.timestamp, Timestamp::from_seconds(7));
+        }
+        assert_eq!(results[0].outcome, ForgeOutcome::Success);
+        assert_eq!(results[0].minted_token_id, Some("1".to_string()));
+        assert_eq!(results[1].outcome, ForgeOutcome::Failure);
+        assert_eq!(results[1].minted_token_id, None);
+
+        assert!(query_results(&deps, QueryMsg::ForgeResult { job_id: "unknown".to_string() }).is_empty());
+    }
+
+    #[test]
+    fn test_forge_history_pagination() {
+        let mut deps = setup_storage();
+        // addr0 and addr1 take part in 3 jobs each
+        for job in 0..3 {
+            select_gem_rewards(&mut deps.storage, RANDOM_SEED, &job.to_string(), uniform_job(2, 5_000)).unwrap();
+        }
+
+        // newest first
+        let recent = query_results(&deps, QueryMsg::RecentForges { start_after: None, limit: Some(4) });
+        assert_eq!(recent.iter().map(|result| result.id).collect::<Vec<_>>(), vec![5, 4, 3, 2]);
+        let recent = query_results(&deps, QueryMsg::RecentForges { start_after: Some(2), limit: None });
+        assert_eq!(recent.iter().map(|result| result.id).collect::<Vec<_>>(), vec![1, 0]);
+
+        let history = query_results(&deps, QueryMsg::ForgeHistoryByUser { user: "addr1".to_string(), start_after: None, limit: Some(2) });
+        assert_eq!(history.iter().map(|result| result.job_id.as_str()).collect::<Vec<_>>(), vec!["2", "1"]);
+        assert!(history.iter().all(|result| result.user_addr == Addr::unchecked("addr1")));
+        let history = query_results(&deps, QueryMsg::ForgeHistoryByUser { user: "addr1".to_string(), start_after: Some(history[1].id), limit: Some(2) });
+        assert_eq!(history.iter().map(|result| result.job_id.as_str()).collect::<Vec<_>>(), vec!["0"]);
+    }
+}
+
+// Unit test for compute_success_rate
+#[cfg(test)]
+mod test_compute_success_rate {
+    use std::str::FromStr;
+
+    use cosmwasm_std::Decimal;
+
+    use crate::contract::{compute_success_rate, gem_kind_from_attributes};
+    use crate::state::{GemKind, Trait};
+    use crate::error::ContractError;
+
+    #[test]
+    fn test_compute_success_rate() {
+        // two white-1 materials (2 + 2) over the work load of a 1 star gem (3) is capped at 100%
+        let rate = compute_success_rate(Decimal::from_str("4").unwrap(), Decimal::from_str("3").unwrap());
+        assert_eq!(rate, 10_000);
+
+        // one white-1 material (2) over the work load of a 1 star gem (3)
+        let rate = compute_success_rate(Decimal::from_str("2").unwrap(), Decimal::from_str("3").unwrap());
+        assert_eq!(rate, 6_666);
+
+        // one white-1 material (2) over the work load of a 2 star gem (5)
+        let rate = compute_success_rate(Decimal::from_str("2").unwrap(), Decimal::from_str("5").unwrap());
+        assert_eq!(rate, 4_000);
+
+        // no materials
+        let rate = compute_success_rate(Decimal::zero(), Decimal::from_str("5").unwrap());
+        assert_eq!(rate, 0);
+
+        // no work load
+        let rate = compute_success_rate(Decimal::from_str("2").unwrap(), Decimal::zero());
+        assert_eq!(rate, 10_000);
+    }
+
+    #[test]
+    fn test_gem_kind_from_attributes() {
+        let attribute = |trait_type: &str, value: &str| Trait {
+            display_type: None,
+            trait_type: trait_type.to_string(),
+            value: value.to_string(),
+        };
+        let gold_3 = GemKind { color: "gold".to_string(), star: 3 };
+        // the attributes are found by name, whatever their order
+        let attributes = [attribute("color", "gold"), attribute("star", "3")];
+        assert_eq!(gem_kind_from_attributes("1", &attributes).unwrap(), gold_3);
+        let attributes = [attribute("star", "3"), attribute("rarity", "rare"), attribute("color", "gold")];
+        assert_eq!(gem_kind_from_attributes("1", &attributes).unwrap(), gold_3);
+        // the color is checked against the gem tiers, not when decoding
+        let attributes = [attribute("color", "pink"), attribute("star", "3")];
+        assert_eq!(gem_kind_from_attributes("1", &attributes).unwrap().color, "pink");
+
+        let attributes = [attribute("color", "gold")];
+        assert!(matches!(
+            gem_kind_from_attributes("1", &attributes),
+            Err(ContractError::MissingGemAttribute { nft_id, trait_type }) if nft_id == "1" && trait_type == "star"
+        ));
+        assert!(matches!(
+            gem_kind_from_attributes("1", &[]),
+            Err(ContractError::MissingGemAttribute { trait_type, .. }) if trait_type == "color"
+        ));
+        for star in ["x", "0", "-1", "256", ""] {
+            let attributes = [attribute("color", "gold"), attribute("star", star)];
+            assert!(matches!(
+                gem_kind_from_attributes("1", &attributes),
+                Err(ContractError::InvalidGemAttribute { trait_type, value, .. }) if trait_type == "star" && value == star
+            ));
+        }
+        let attributes = [attribute("color", ""), attribute("star", "3")];
+        assert!(matches!(
+            gem_kind_from_attributes("1", &attributes),
+            Err(ContractError::InvalidGemAttribute { trait_type, .. }) if trait_type == "color"
+        ));
+    }
+}
+
+// Unit test for the queue queries
+#[cfg(test)]
+mod test_query_queue {
+    use cosmwasm_std::{from_json, testing::{mock_env, MockApi, MockQuerier, MockStorage}, Addr, OwnedDeps, Timestamp};
+
+    use crate::{contract::{push_queue_entry, query, remove_queue_entry, test_fixtures::mock_deps_with_white_tier}, msg::{QueryMsg, QueueLengthResponse, QueuePositionResponse, QueueResponse}, state::{GemInfo, UserInfo}};
+
+    // addr0, addr1, addr0, addr2 in that order, entry 2 has left the queue
+    fn setup_queue() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_deps_with_white_tier();
+        for (entry_id, user) in [(0u64, "addr0"), (1, "addr1"), (3, "addr0"), (4, "addr2")] {
+            let user_info = UserInfo {
+                user_addr: Addr::unchecked(user),
+                gem_base: GemInfo {
+                    nft_id: entry_id.to_string(),
+                    nft_contract: Addr::unchecked("auragon_collection"),
+                },
+                gem_materials: vec![],
+                shield_id: None,
+                timestamp: Timestamp::from_seconds(entry_id),
+                job_id: None,
+            };
+            push_queue_entry(&mut deps.storage, entry_id, &user_info).unwrap();
+        }
+        deps
+    }
+
+    // (entry_id, position, user) of the entries
+    fn query_queue(deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, msg: QueryMsg) -> Vec<(u64, u32, String)> {
+        from_json::<QueueResponse>(query(deps.as_ref(), mock_env(), msg).unwrap())
+            .unwrap()
+            .entries
+            .into_iter()
+            .map(|entry| (entry.entry_id, entry.position, entry.user_info.user_addr.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_queue_pagination() {
+        let deps = setup_queue();
+        let entries = query_queue(&deps, QueryMsg::Queue { start_after: None, limit: Some(2) });
+        assert_eq!(entries, vec![(0, 0, "addr0".to_string()), (1, 1, "addr1".to_string())]);
+        let entries = query_queue(&deps, QueryMsg::Queue { start_after: Some(1), limit: Some(2) });
+        assert_eq!(entries, vec![(3, 2, "addr0".to_string()), (4, 3, "addr2".to_string())]);
+        let entries = query_queue(&deps, QueryMsg::Queue { start_after: Some(4), limit: None });
+        assert!(entries.is_empty());
+
+        let length: QueueLengthResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::QueueLength {}).unwrap()).unwrap();
+        assert_eq!(length.length, 4);
+    }
+
+    #[test]
+    fn test_queue_position_and_user_entries() {
+        let deps = setup_queue();
+        let position = |user: &str| -> Option<u32> {
+            let res: QueuePositionResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::QueuePosition { user: user.to_string() }).unwrap()).unwrap();
+            res.position
+        };
+        assert_eq!(position("addr0"), Some(0));
+        assert_eq!(position("addr2"), Some(3));
+        assert_eq!(position("addr3"), None);
+
+        let entries = query_queue(&deps, QueryMsg::UserQueueEntries { user: "addr0".to_string() });
+        assert_eq!(entries, vec![(0, 0, "addr0".to_string()), (3, 2, "addr0".to_string())]);
+        assert!(query_queue(&deps, QueryMsg::UserQueueEntries { user: "addr3".to_string() }).is_empty());
+    }
+
+    #[test]
+    fn test_queue_positions_across_pages() {
+        let mut deps = setup_queue();
+        for entry_id in [150u64, 320] {
+            let user_info = UserInfo {
+                user_addr: Addr::unchecked("addr3"),
+                gem_base: GemInfo {
+                    nft_id: entry_id.to_string(),
+                    nft_contract: Addr::unchecked("auragon_collection"),
+                },
+                gem_materials: vec![],
+                shield_id: None,
+                timestamp: Timestamp::from_seconds(entry_id),
+                job_id: None,
+            };
+            push_queue_entry(&mut deps.storage, entry_id, &user_info).unwrap();
+        }
+        remove_queue_entry(&mut deps.storage, 1).unwrap();
+        // removing an entry twice does not count it twice
+        remove_queue_entry(&mut deps.storage, 1).unwrap();
+
+        let length: QueueLengthResponse = from_json(query(deps.as_ref(), mock_env(), QueryMsg::QueueLength {}).unwrap()).unwrap();
+        assert_eq!(length.length, 5);
+        let entries = query_queue(&deps, QueryMsg::UserQueueEntries { user: "addr3".to_string() });
+        assert_eq!(entries, vec![(150, 3, "addr3".to_string()), (320, 4, "addr3".to_string())]);
+        let entries = query_queue(&deps, QueryMsg::Queue { start_after: Some(150), limit: None });
+        assert_eq!(entries, vec![(320, 4, "addr3".to_string())]);
+        let position: QueuePositionResponse =
+            from_json(query(deps.as_ref(), mock_env(), QueryMsg::QueuePosition { user: "addr2".to_string() }).unwrap()).unwrap();
+        assert_eq!(position.position, Some(2));
+    }
+}
+
+// Unit test for leaving the queue
+#[cfg(test)]
+mod test_leave_queue {
+    use cosmwasm_std::{from_json, testing::{mock_env, mock_info, MockApi, MockQuerier, MockStorage}, Addr, CosmosMsg, Empty, OwnedDeps, Timestamp, WasmMsg};
+    use cw721_base::ExecuteMsg as Cw721BaseExecuteMsg;
+
+    use crate::{contract::{execute, push_queue_entry, test_fixtures::mock_deps_with_white_tier}, error::ContractError, msg::ExecuteMsg, state::{GemInfo, Metadata, UserInfo, users_in_queue}};
+
+    fn gem(nft_id: &str) -> GemInfo {
+        GemInfo {
+            nft_id: nft_id.to_string(),
+            nft_contract: Addr::unchecked("dragon_collection"),
+        }
+    }
+
+    // entry 0 of addr1 is waiting, entry 1 of addr2 is being forged in job "job"
+    fn setup_queue() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_deps_with_white_tier();
+        for (entry_id, user, job_id) in [(0u64, "addr1", None), (1, "addr2", Some("job".to_string()))] {
+            let user_info = UserInfo {
+                user_addr: Addr::unchecked(user),
+                gem_base: gem(&format!("{}-base", user)),
+                gem_materials: vec![gem(&format!("{}-material", user))],
+                shield_id: Some(format!("{}-shield", user)),
+                timestamp: Timestamp::from_seconds(0),
+                job_id,
+            };
+            push_queue_entry(&mut deps.storage, entry_id, &user_info).unwrap();
+        }
+        deps
+    }
+
+    // (collection, recipient, token_id) of every transfer in the response
+    fn transfers(messages: Vec<CosmosMsg>) -> Vec<(String, String, String)> {
+        messages
+            .into_iter()
+            .map(|msg| match msg {
+                CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                    match from_json::<Cw721BaseExecuteMsg<Metadata, Empty>>(msg).unwrap() {
+                        Cw721BaseExecuteMsg::TransferNft { recipient, token_id } => (contract_addr, recipient, token_id),
+                        _ => panic!("unexpected cw721 message"),
+                    }
+                }
+                _ => panic!("unexpected message"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_user_leaves_queue_with_refund() {
+        let mut deps = setup_queue();
+        let res = execute(deps.as_mut(), mock_env(), mock_info("addr1", &[]), ExecuteMsg::LeaveQueue { entry_id: 0 }).unwrap();
+        assert!(!users_in_queue().has(&deps.storage, 0));
+        let messages = res.messages.into_iter().map(|sub_msg| sub_msg.msg).collect();
+        assert_eq!(
+            transfers(messages),
+            vec![
+                ("dragon_collection".to_string(), "addr1".to_string(), "addr1-base".to_string()),
+                ("dragon_collection".to_string(), "addr1".to_string(), "addr1-material".to_string()),
+                ("shield_collection".to_string(), "addr1".to_string(), "addr1-shield".to_string()),
+            ]
+        );
+
+        // the entry is gone
+        let err = execute(deps.as_mut(), mock_env(), mock_info("addr1", &[]), ExecuteMsg::LeaveQueue { entry_id: 0 }).unwrap_err();
+        assert!(matches!(err, ContractError::QueueEntryNotFound { entry_id: 0 }));
+    }
+
+    #[test]
+    fn test_only_user_or_owner_can_remove_entry() {
+        let mut deps = setup_queue();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("addr2", &[]), ExecuteMsg::LeaveQueue { entry_id: 0 }).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+
+        // the owner refunds the user in an emergency
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::LeaveQueue { entry_id: 0 }).unwrap();
+        let messages = res.messages.into_iter().map(|sub_msg| sub_msg.msg).collect();
+        assert!(transfers(messages).iter().all(|(_, recipient, _)| recipient == "addr1"));
+    }
+
+    #[test]
+    fn test_entry_pending_in_job_cannot_leave_nor_be_forged_again() {
+        let mut deps = setup_queue();
+        let err = execute(deps.as_mut(), mock_env(), mock_info("addr2", &[]), ExecuteMsg::LeaveQueue { entry_id: 1 }).unwrap_err();
+        assert!(matches!(err, ContractError::QueueEntryInJob { entry_id: 1, job_id } if job_id == "job"));
+        assert!(users_in_queue().has(&deps.storage, 1));
+
+        let forge = |entry_ids: Vec<u64>| ExecuteMsg::ForgeGem { entry_ids };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), forge(vec![0, 1])).unwrap_err();
+        assert!(matches!(err, ContractError::QueueEntryInJob { entry_id: 1, .. }));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), forge(vec![0, 0])).unwrap_err();
+        assert!(matches!(err, ContractError::QueueEntryInJob { entry_id: 0, .. }));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), forge(vec![7])).unwrap_err();
+        assert!(matches!(err, ContractError::QueueEntryNotFound { entry_id: 7 }));
+    }
+
+    #[test]
+    fn test_user_forged_once_per_job() {
+        let mut deps = setup_queue();
+        let mut user_info = users_in_queue().load(&deps.storage, 0).unwrap();
+        user_info.gem_base = gem("another-base");
+        push_queue_entry(&mut deps.storage, 2, &user_info).unwrap();
+        let msg = ExecuteMsg::ForgeGem { entry_ids: vec![0, 2] };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::DuplicateParticipant { user } if user == "addr1"));
+    }
+}
+
+// Unit test for instantiate
+#[cfg(test)]
+mod test_instantiate {
+    use cosmwasm_std::testing::{mock_dependencies, mock_env, mock_info};
+
+    use crate::{contract::instantiate, error::ContractError, msg::InstantiateMsg, state::RANDOM_SEED};
+
+    fn instantiate_msg(random_seed: &str) -> InstantiateMsg {
+        InstantiateMsg {
+            random_seed: random_seed.to_string(),
+            nois_proxy: "nois_proxy".to_string(),
+            dragon_collection: "dragon_collection".to_string(),
+            auragon_collection: "auragon_collection".to_string(),
+            shield_collection: "shield_collection".to_string(),
+            job_timeout: 3600,
+            nois_price: vec![],
+            gem_tiers: vec![],
+            dragon_gems: vec![],
+            shield_uri: "ipfs://shield".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_random_seed_must_be_32_bytes_of_hex() {
+        let seed = "46FAF1CD4845AB7C5A9DAA7D272259682BF84176A2658DE67CB1317A22134973";
+        for random_seed in ["", "zz", &seed[2..], &format!("{}00", seed), &seed.replace('4', "g")] {
+            let mut deps = mock_dependencies();
+            let err = instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), instantiate_msg(random_seed)).unwrap_err();
+            assert!(matches!(err, ContractError::InvalidSeed {}), "{}", random_seed);
+        }
+
+        let mut deps = mock_dependencies();
+        instantiate(deps.as_mut(), mock_env(), mock_info("owner", &[]), instantiate_msg(seed)).unwrap();
+        assert_eq!(RANDOM_SEED.load(&deps.storage).unwrap()[0], 0x46);
+    }
+}
+
+// Unit test for requesting a forge
+#[cfg(test)]
+mod test_request_forge {
+    use cosmwasm_std::{from_json, testing::{mock_env, mock_info, MockApi, MockQuerier, MockStorage}, Addr, OwnedDeps, Timestamp};
+
+    use crate::{contract::{execute, push_queue_entry, test_fixtures::mock_deps_with_white_tier}, error::ContractError, msg::{ExecuteMsg, ForgeGemResponse}, state::{Escrow, GemInfo, GemKind, RandomJob, UserInfo, CURRENT_JOB_ID, DRAGON_GEMS, ESCROWS, RANDOM_JOBS, users_in_queue}};
+
+    // addr1 and addr2 have the queue entries 0 and 1 with a white-1 gem_base, white gems have 2 stars
+    fn setup() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_deps_with_white_tier();
+        for (entry_id, user) in [(0u64, "addr1"), (1, "addr2")] {
+            let gem_base = GemInfo {
+                nft_id: format!("{}-base", user),
+                nft_contract: Addr::unchecked("dragon_collection"),
+            };
+            let escrow = Escrow { owner: Addr::unchecked(user), entry_id: Some(entry_id) };
+            ESCROWS.save(&mut deps.storage, (&gem_base.nft_contract, &gem_base.nft_id), &escrow).unwrap();
+            let user_info = UserInfo {
+                user_addr: Addr::unchecked(user),
+                gem_base,
+                gem_materials: vec![],
+                shield_id: None,
+                timestamp: Timestamp::from_seconds(0),
+                job_id: None,
+            };
+            push_queue_entry(&mut deps.storage, entry_id, &user_info).unwrap();
+        }
+        deps
+    }
+
+    #[test]
+    fn test_max_star_gems_cannot_be_forged() {
+        let mut deps = setup();
+        DRAGON_GEMS.save(&mut deps.storage, "ipfs://white-1", &GemKind { color: "white".to_string(), star: 2 }).unwrap();
+        let msg = ExecuteMsg::ForgeGem { entry_ids: vec![0] };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+        assert!(matches!(err, ContractError::MaxStarReached { color, max_star: 2 } if color == "white"));
+    }
+
+    #[test]
+    fn test_job_ids_are_generated_in_order() {
+        let mut deps = setup();
+        // a job named by a caller before the ids were generated is still pending
+        let legacy_job = RandomJob { participants: vec![], timestamp: Timestamp::from_seconds(0), payer: None, fees: vec![], cw20_fees: vec![] };
+        RANDOM_JOBS.save(&mut deps.storage, "1".to_string(), &legacy_job).unwrap();
+
+        let mut forge = |entry_id: u64| {
+            let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), ExecuteMsg::ForgeGem { entry_ids: vec![entry_id] }).unwrap();
+            from_json::<ForgeGemResponse>(res.data.unwrap()).unwrap().job_id
+        };
+        assert_eq!(forge(0), "0");
+        // the id of the pending job is skipped rather than overwritten
+        assert_eq!(forge(1), "2");
+        assert_eq!(RANDOM_JOBS.load(&deps.storage, "1".to_string()).unwrap(), legacy_job);
+        assert_eq!(users_in_queue().load(&deps.storage, 1).unwrap().job_id, Some("2".to_string()));
+        assert_eq!(CURRENT_JOB_ID.load(&deps.storage).unwrap(), 3);
+    }
+}
+
+#[cfg(test)]
+mod test_cw20_payment {
+    use cosmwasm_std::{from_json, testing::{mock_env, mock_info, MockApi, MockQuerier, MockStorage}, to_json_binary, Addr, CosmosMsg, HexBinary, OwnedDeps, Response, Timestamp, Uint128, WasmMsg};
+    use cw20::{Cw20CoinVerified, Cw20ExecuteMsg, Cw20ReceiveMsg};
+    use nois::NoisCallback;
+
+    use crate::{contract::{execute, push_queue_entry, query, test_fixtures::mock_deps_with_white_tier}, error::ContractError, msg::{ExecuteMsg, QueryMsg, ReceiveCw20Msg, TreasuryResponse}, state::{AcceptedToken, Escrow, GemInfo, UserInfo, CW20_PENDING_FEES, ESCROWS, RANDOM_JOBS, SHIELD_LATEST_TOKEN_ID}};
+
+    type Deps = OwnedDeps<MockStorage, MockApi, MockQuerier>;
+
+    // addr1 has the queue entry 0 with a white-1 gem_base, "token" is accepted
+    fn setup() -> Deps {
+        let mut deps = mock_deps_with_white_tier();
+        let gem_base = GemInfo { nft_id: "1".to_string(), nft_contract: Addr::unchecked("dragon_collection") };
+        let escrow = Escrow { owner: Addr::unchecked("addr1"), entry_id: Some(0) };
+        ESCROWS.save(&mut deps.storage, (&gem_base.nft_contract, &gem_base.nft_id), &escrow).unwrap();
+        let user_info = UserInfo {
+            user_addr: Addr::unchecked("addr1"),
+            gem_base,
+            gem_materials: vec![],
+            shield_id: None,
+            timestamp: Timestamp::from_seconds(0),
+            job_id: None,
+        };
+        push_queue_entry(&mut deps.storage, 0, &user_info).unwrap();
+
+        let prices = AcceptedToken {
+            forge_fees: vec![("white".to_string(), Uint128::new(100))],
+            shield_price: Some(Uint128::new(50)),
+        };
+        let msg = ExecuteMsg::UpdateAcceptedToken { token: "token".to_string(), prices: Some(prices) };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        deps
+    }
+
+    fn send_tokens(deps: &mut Deps, token: &str, amount: u128, hook: &ReceiveCw20Msg) -> Result<Response, ContractError> {
+        let msg = ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "addr1".to_string(),
+            amount: Uint128::new(amount),
+            msg: to_json_binary(hook).unwrap(),
+        });
+        execute(deps.as_mut(), mock_env(), mock_info(token, &[]), msg)
+    }
+
+    fn cw20_transfer(msg: &CosmosMsg) -> Option<(String, Cw20ExecuteMsg)> {
+        match msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                from_json(msg).ok().map(|msg| (contract_addr.clone(), msg))
+            }
+            _ => None,
+        }
+    }
+
+    fn treasury(deps: &Deps) -> TreasuryResponse {
+        from_json(query(deps.as_ref(), mock_env(), QueryMsg::Treasury {}).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_accepted_tokens_are_managed_by_the_owner() {
+        let mut deps = setup();
+        let update = |color: &str| ExecuteMsg::UpdateAcceptedToken {
+            token: "token".to_string(),
+            prices: Some(AcceptedToken { forge_fees: vec![(color.to_string(), Uint128::one())], shield_price: None }),
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("addr1", &[]), update("white")).unwrap_err();
+        assert!(matches!(err, ContractError::Unauthorized {}));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), update("pink")).unwrap_err();
+        assert!(matches!(err, ContractError::GemTierNotFound { color } if color == "pink"));
+        // nothing is sold for zero tokens
+        for (forge_fee, shield_price) in [(0, Some(50)), (100, Some(0))] {
+            let msg = ExecuteMsg::UpdateAcceptedToken {
+                token: "token".to_string(),
+                prices: Some(AcceptedToken {
+                    forge_fees: vec![("white".to_string(), Uint128::new(forge_fee))],
+                    shield_price: shield_price.map(Uint128::new),
+                }),
+            };
+            let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap_err();
+            assert!(matches!(err, ContractError::InvalidCoins { .. }));
+        }
+
+        let prices: Option<AcceptedToken> =
+            from_json(query(deps.as_ref(), mock_env(), QueryMsg::AcceptedToken { token: "token".to_string() }).unwrap()).unwrap();
+        assert_eq!(prices.unwrap().forge_fee("white"), Some(Uint128::new(100)));
+
+        let msg = ExecuteMsg::UpdateAcceptedToken { token: "token".to_string(), prices: None };
+        execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), msg).unwrap();
+        let err = send_tokens(&mut deps, "token", 50, &ReceiveCw20Msg::BuyShield {}).unwrap_err();
+        assert!(matches!(err, ContractError::UnacceptedToken { token } if token == "token"));
+    }
+
+    #[test]
+    fn test_forge_with_cw20() {
+        let mut deps = setup();
+        let forge = ReceiveCw20Msg::ForgeGem { entry_ids: vec![0] };
+        let err = send_tokens(&mut deps, "other_token", 100, &forge).unwrap_err();
+        assert!(matches!(err, ContractError::UnacceptedToken { .. }));
+        let err = send_tokens(&mut deps, "token", 80, &forge).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficentFund {}));
+        // the state written by the failed message is reverted on chain
+        let mut deps = setup();
+
+        // the fee is held for the job and the rest is returned
+        let res = send_tokens(&mut deps, "token", 120, &forge).unwrap();
+        let refund = res.messages.iter().find_map(|msg| cw20_transfer(&msg.msg)).unwrap();
+        assert_eq!(
+            refund,
+            ("token".to_string(), Cw20ExecuteMsg::Transfer { recipient: "addr1".to_string(), amount: Uint128::new(20) })
+        );
+        let job = RANDOM_JOBS.load(&deps.storage, "0".to_string()).unwrap();
+        assert_eq!(job.cw20_fees, vec![Cw20CoinVerified { address: Addr::unchecked("token"), amount: Uint128::new(100) }]);
+        assert_eq!(CW20_PENDING_FEES.load(&deps.storage, &Addr::unchecked("token")).unwrap(), Uint128::new(100));
+
+        // the fee is earned once the job is settled
+        let callback = NoisCallback {
+            job_id: "0".to_string(),
+            published: Timestamp::from_seconds(1),
+            randomness: HexBinary::from([7u8; 32]),
+        };
+        execute(deps.as_mut(), mock_env(), mock_info("nois_proxy", &[]), ExecuteMsg::NoisReceive { callback }).unwrap();
+        assert!(!CW20_PENDING_FEES.has(&deps.storage, &Addr::unchecked("token")));
+        assert_eq!(
+            treasury(&deps).cw20_balances,
+            vec![Cw20CoinVerified { address: Addr::unchecked("token"), amount: Uint128::new(100) }]
+        );
+
+        let withdraw = |amount: u128| ExecuteMsg::WithdrawCw20Treasury {
+            token: "token".to_string(),
+            amount: Uint128::new(amount),
+            recipient: None,
+        };
+        let err = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), withdraw(101)).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficientTreasury { .. }));
+        let res = execute(deps.as_mut(), mock_env(), mock_info("owner", &[]), withdraw(100)).unwrap();
+        assert_eq!(
+            cw20_transfer(&res.messages[0].msg).unwrap(),
+            ("token".to_string(), Cw20ExecuteMsg::Transfer { recipient: "owner".to_string(), amount: Uint128::new(100) })
+        );
+        assert!(treasury(&deps).cw20_balances.is_empty());
+    }
+
+    #[test]
+    fn test_buy_shield_with_cw20() {
+        let mut deps = setup();
+        let err = send_tokens(&mut deps, "token", 40, &ReceiveCw20Msg::BuyShield {}).unwrap_err();
+        assert!(matches!(err, ContractError::InsufficentFund {}));
+
+        let res = send_tokens(&mut deps, "token", 70, &ReceiveCw20Msg::BuyShield {}).unwrap();
+        assert_eq!(res.messages.len(), 2);
+        match &res.messages[0].msg {
+            CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, .. }) => assert_eq!(contract_addr, "shield_collection"),
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        assert_eq!(
+            cw20_transfer(&res.messages[1].msg).unwrap(),
+            ("token".to_string(), Cw20ExecuteMsg::Transfer { recipient: "addr1".to_string(), amount: Uint128::new(20) })
+        );
+        assert_eq!(SHIELD_LATEST_TOKEN_ID.load(&deps.storage).unwrap(), 1);
+        assert_eq!(
+            treasury(&deps).cw20_balances,
+            vec![Cw20CoinVerified { address: Addr::unchecked("token"), amount: Uint128::new(50) }]
+        );
     }
 }
@@ -1,4 +1,4 @@
-use cosmwasm_std::StdError;
+use cosmwasm_std::{StdError, Timestamp, Uint128};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -6,6 +6,9 @@ pub enum ContractError {
     #[error("{0}")]
     Std(#[from] StdError),
 
+    #[error("{0}")]
+    SemVer(#[from] semver::Error),
+
     #[error("Unauthorized")]
     Unauthorized {},
 
@@ -57,12 +60,129 @@ pub enum ContractError {
     #[error("Insufficent fund")]
     InsufficentFund {},
 
+    #[error("Invalid coins: {reason}")]
+    InvalidCoins { reason: String },
+
+    #[error("Prepaid Nois balance of {denom} is too low to pay the randomness")]
+    InsufficientNoisBalance { denom: String },
+
+    #[error("Token {token} is not accepted for this payment")]
+    UnacceptedToken { token: String },
+
+    #[error("Treasury balance of {denom} is too low")]
+    InsufficientTreasury { denom: String },
+
     #[error("Invalid slot reward")]
     InvalidSlotReward {},
 
+    #[error("Random seed must be a hex string of 32 bytes")]
+    InvalidSeed {},
+
+    #[error("Invalid success rate {success_rate}")]
+    InvalidSuccessRate { success_rate: u32 },
+
+    #[error("Invalid pity config: {reason}")]
+    InvalidPityConfig { reason: String },
+
     #[error("Random job not found")]
     RandomJobNotFound {},
 
+    #[error("Randomness of job {job_id} waits for the settlement to resume")]
+    JobSettlementPending { job_id: String },
+
+    #[error("{operation} is paused")]
+    Paused { operation: String },
+
+    #[error("Random job {job_id} does not expire before {expires_at}")]
+    JobNotExpired { job_id: String, expires_at: Timestamp },
+
+    #[error("No pending ownership transfer")]
+    NoPendingOwner {},
+
+    #[error("Unknown gem")]
+    UnknownGem {},
+
+    #[error("Gem star out of range")]
+    StarOutOfRange {},
+
+    #[error("{color} gems are at their max star {max_star} and cannot be upgraded")]
+    MaxStarReached { color: String, max_star: u8 },
+
+    #[error("Gems cannot be salvaged")]
+    SalvageClosed {},
+
+    #[error("No gem given to salvage")]
+    NoGemToSalvage {},
+
+    #[error("Invalid salvage config: {reason}")]
+    InvalidSalvageConfig { reason: String },
+
+    #[error("{fragments} fragments are needed, {balance} are owned")]
+    InsufficientFragments { fragments: Uint128, balance: Uint128 },
+
+    #[error("Random gems are not on sale")]
+    RandomGemSaleClosed {},
+
+    #[error("Random gems are sold out")]
+    RandomGemSoldOut {},
+
+    #[error("{color}-{star} gems cannot be fused")]
+    NoFusionRule { color: String, star: u8 },
+
+    #[error("Invalid fusion: {reason}")]
+    InvalidFusion { reason: String },
+
+    #[error("Gem tier {color} not found")]
+    GemTierNotFound { color: String },
+
+    #[error("Gem tier {color} already exists")]
+    GemTierExists { color: String },
+
+    #[error("Gem tier {color} is retired")]
+    GemTierRetired { color: String },
+
+    #[error("{color} gems have forge fees and cannot be forged when sent, join the queue and forge with ForgeGem")]
+    ForgeFeesRequired { color: String },
+
+    #[error("Invalid gem tier {color}: {reason}")]
+    InvalidGemTier { color: String, reason: String },
+
+    #[error("Dragon gem {nft_id} has an unregistered token uri {token_uri:?}")]
+    UnknownDragonGem { nft_id: String, token_uri: Option<String> },
+
+    #[error("Gem {nft_id} has no {trait_type} attribute")]
+    MissingGemAttribute { nft_id: String, trait_type: String },
+
+    #[error("Gem {nft_id} has an invalid {trait_type} attribute {value:?}")]
+    InvalidGemAttribute { nft_id: String, trait_type: String, value: String },
+
+    #[error("User {user} is more than once in the forge list")]
+    DuplicateParticipant { user: String },
+
+    #[error("Queue entry {entry_id} not found")]
+    QueueEntryNotFound { entry_id: u64 },
+
+    #[error("Queue entry {entry_id} is pending in job {job_id}")]
+    QueueEntryInJob { entry_id: u64, job_id: String },
+
+    #[error("Gem {nft_id} of {nft_contract} is not escrowed")]
+    GemNotEscrowed { nft_contract: String, nft_id: String },
+
+    #[error("Gem {nft_id} of {nft_contract} is used by queue entry {entry_id}")]
+    GemInUse { nft_contract: String, nft_id: String, entry_id: u64 },
+
+    #[error("Collection {collection} is not accepted")]
+    InvalidCollection { collection: String },
+
+    #[error("Cannot migrate from contract {contract}")]
+    InvalidMigrationContract { contract: String },
+
+    #[error("Cannot migrate from version {from} to older version {to}")]
+    MigrationDowngrade { from: String, to: String },
+
+    #[error("The legacy queue still has {entries} entries, migrate with drop_legacy_queue to drop them")]
+    LegacyQueueNotEmpty { entries: u32 },
+
     #[error("Custom Error val: {val:?}")]
     CustomError { val: String },
     // Add any other custom errors you like here.
@@ -2,6 +2,7 @@ pub mod contract;
 pub mod msg;
 pub mod state;
 pub mod error;
+pub mod migrations;
 
 #[cfg(test)]
 mod tests;
\ No newline at end of file
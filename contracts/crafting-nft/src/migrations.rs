@@ -0,0 +1,564 @@
+use cosmwasm_std::{Attribute, DepsMut, Env};
+use semver::Version;
+
+use crate::{error::ContractError, msg::MigrateMsg};
+
+type Migration = fn(DepsMut, &Env, &MigrateMsg) -> Result<Vec<Attribute>, ContractError>;
+
+/// State migrations in the order they must be applied.
+/// Each one upgrades the state written by any version older than its own.
+const MIGRATIONS: &[(&str, Migration)] = &[("0.2.0", v0_2_0::migrate)];
+
+/// run every migration newer than `from_version`, returns the versions that were applied and the attributes they emitted
+pub fn migrate_state(
+    mut deps: DepsMut,
+    env: &Env,
+    from_version: &Version,
+    msg: &MigrateMsg,
+) -> Result<(Vec<String>, Vec<Attribute>), ContractError> {
+    let mut applied = vec![];
+    let mut attributes = vec![];
+    for (version, migration) in MIGRATIONS {
+        let version: Version = version.parse()?;
+        if *from_version < version {
+            attributes.extend(migration(deps.branch(), env, msg)?);
+            applied.push(version.to_string());
+        }
+    }
+    Ok((applied, attributes))
+}
+
+/// 0.1.x -> 0.2.0
+mod v0_2_0 {
+    use cosmwasm_schema::cw_serde;
+    use cosmwasm_std::{Addr, Attribute, Decimal, DepsMut, Env, Order, StdResult, Timestamp};
+    use cw_storage_plus::{Deque, Item, Map};
+
+    use crate::{
+        contract::SUCCESS_RATE_PRECISION,
+        error::ContractError,
+        msg::MigrateMsg,
+        state::{Config, GemInfo, GemKind, GemTier, JobParticipant, RandomJob, CONFIG, DRAGON_GEMS, GEM_TIERS, QUEUE_LENGTH, RANDOM_JOBS},
+    };
+
+    // Config before the owner was added
+    #[cw_serde]
+    struct ConfigV0_1 {
+        nois_proxy: Addr,
+        dragon_collection: Addr,
+        auragon_collection: Addr,
+        shield_collection: Addr,
+    }
+
+    // RandomJob with parallel user lists and success rates in percent
+    #[cw_serde]
+    struct RandomJobV0_1 {
+        gem_base_nft_color_and_star_user_list: Vec<String>,
+        user_success_rate_list: Vec<(Addr, u32)>,
+        timestamp: Timestamp,
+    }
+
+    // token uris of the gems of each color, from 1 star
+    #[cw_serde]
+    struct AuragonURIV0_1 {
+        white: [String; 7],
+        blue: [String; 7],
+        gold: [String; 7],
+        red: [String; 7],
+    }
+
+    // Queue entry before the gems were escrowed
+    #[cw_serde]
+    struct UserInfoV0_1 {
+        user_addr: Addr,
+        gem_base: GemInfo,
+        gem_materials: Vec<GemInfo>,
+        shield_id: Option<String>,
+        timestamp: Timestamp,
+    }
+
+    const CONFIG_V0_1: Item<ConfigV0_1> = Item::new("config");
+
+    const AURAGON_URI_V0_1: Item<AuragonURIV0_1> = Item::new("auragon uri");
+
+    // the chance of white, blue, gold and red when drawing a random gem
+    const GEM_RATIO_V0_1: Item<[Decimal; 4]> = Item::new("gem ratio");
+
+    // the work loads were shared by every color
+    const GEM_WORK_LOAD_V0_1: Item<[Decimal; 6]> = Item::new("gem work load");
+
+    // (color, display name, work power of each star)
+    const GEM_WORK_POWERS_V0_1: [(&str, &str, Item<[Decimal; 7]>); 4] = [
+        ("white", "White", Item::new("white gem work power")),
+        ("blue", "Blue", Item::new("blue gem work power")),
+        ("gold", "Gold", Item::new("gold gem work power")),
+        ("red", "Red", Item::new("red gem work power")),
+    ];
+
+    const RANDOM_JOBS_V0_1: Map<String, RandomJobV0_1> = Map::new("random jobs");
+
+    // token uris of the 1 star dragon gems that used to be hard-coded
+    const DRAGON_GEM_URIS_V0_1: [(&str, &str); 4] = [
+        ("ipfs://Qme1dXSRNSqYvVQSDEmoL6WHMLqrYajZkszYhbRGj2F2oa", "white"),
+        ("ipfs://QmSp3iYpenTNr69g2EDSS128Vs1oRV2EHW8vakZ2Ro8G6P", "blue"),
+        ("ipfs://QmQP3N4jxJKGXPx18PgrjdhGLqYjX2qtinZ4q4YBeQhpw7", "gold"),
+        ("ipfs://QmTUy7E1UnLcbasfQap38kfxBAsFWzPfLmQimTh7pNw4QT", "red"),
+    ];
+
+    // jobs could not expire before, give their randomness a day to come back
+    const DEFAULT_JOB_TIMEOUT: u64 = 24 * 60 * 60;
+
+    const USERS_IN_QUEUE_V0_1: Deque<UserInfoV0_1> = Deque::new("users_in_queue");
+
+    pub fn migrate(deps: DepsMut, env: &Env, msg: &MigrateMsg) -> Result<Vec<Attribute>, ContractError> {
+        // the legacy entries only approved this contract and their gems never left the users,
+        // they cannot be escrowed nor refunded so they are only dropped when the migration asks for it
+        let entries = USERS_IN_QUEUE_V0_1.len(deps.storage)?;
+        if entries > 0 && !msg.drop_legacy_queue {
+            return Err(ContractError::LegacyQueueNotEmpty { entries });
+        }
+
+        // backfill the owner, defaulting to the admin of the contract
+        let legacy_config = CONFIG_V0_1.load(deps.storage)?;
+        let owner = match msg.owner {
+            Some(ref owner) => Some(
+                deps.api
+                    .addr_validate(owner)
+                    .map_err(|_| ContractError::InvalidAddress {})?,
+            ),
+            None => deps
+                .querier
+                .query_wasm_contract_info(env.contract.address.to_string())?
+                .admin
+                .map(Addr::unchecked),
+        };
+        CONFIG.save(
+            deps.storage,
+            &Config {
+                owner,
+                nois_proxy: legacy_config.nois_proxy,
+                dragon_collection: legacy_config.dragon_collection,
+                auragon_collection: legacy_config.auragon_collection,
+                shield_collection: legacy_config.shield_collection,
+                job_timeout: DEFAULT_JOB_TIMEOUT,
+                // the callers used to pay the randomness, the owner sets a price once the Nois balance is topped up
+                nois_price: vec![],
+            },
+        )?;
+
+        // pair up the users with their gem_base and move success rates from percent to basis points
+        let legacy_jobs: Vec<(String, RandomJobV0_1)> = RANDOM_JOBS_V0_1
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<_>>()?;
+        for (job_id, legacy_job) in legacy_jobs {
+            let participants = legacy_job
+                .user_success_rate_list
+                .into_iter()
+                .zip(legacy_job.gem_base_nft_color_and_star_user_list)
+                .map(|((user_addr, success_rate), gem_base_color_and_star)| {
+                    Ok(JobParticipant {
+                        // the gems were not escrowed nor recorded in the job
+                        entry_id: None,
+                        user_addr,
+                        gem_base: None,
+                        gem_base_kind: parse_gem_kind(&gem_base_color_and_star)?,
+                        gem_materials: vec![],
+                        shield_id: None,
+                        success_rate: success_rate
+                            .saturating_mul(SUCCESS_RATE_PRECISION / 100)
+                            .min(SUCCESS_RATE_PRECISION),
+                    })
+                })
+                .collect::<Result<_, ContractError>>()?;
+            RANDOM_JOBS.save(
+                deps.storage,
+                job_id,
+                &RandomJob {
+                    participants,
+                    timestamp: legacy_job.timestamp,
+                    // the fees were paid to the Nois proxy
+                    payer: None,
+                    fees: vec![],
+                    cw20_fees: vec![],
+                },
+            )?;
+        }
+
+        // build the gem tiers from the fixed settings of the four colors
+        let auragon_uri = AURAGON_URI_V0_1.load(deps.storage)?;
+        let work_loads = GEM_WORK_LOAD_V0_1.load(deps.storage)?;
+        let gem_ratio = GEM_RATIO_V0_1.load(deps.storage)?;
+        for ((color, display_name, work_power_item), gem_ratio) in GEM_WORK_POWERS_V0_1.into_iter().zip(gem_ratio) {
+            let uris = match color {
+                "white" => &auragon_uri.white,
+                "blue" => &auragon_uri.blue,
+                "gold" => &auragon_uri.gold,
+                _ => &auragon_uri.red,
+            };
+            let work_powers = work_power_item.load(deps.storage)?;
+            GEM_TIERS.save(
+                deps.storage,
+                color,
+                &GemTier {
+                    display_name: display_name.to_string(),
+                    max_star: 7,
+                    uris: uris.to_vec(),
+                    work_powers: work_powers.to_vec(),
+                    work_loads: work_loads.to_vec(),
+                    // forging was free
+                    forge_fees: vec![],
+                    gem_ratio,
+                    retired: false,
+                },
+            )?;
+            work_power_item.remove(deps.storage);
+        }
+        AURAGON_URI_V0_1.remove(deps.storage);
+        GEM_WORK_LOAD_V0_1.remove(deps.storage);
+        GEM_RATIO_V0_1.remove(deps.storage);
+
+        // the dragon gems were recognized from a fixed list of token uris
+        for (token_uri, color) in DRAGON_GEM_URIS_V0_1 {
+            DRAGON_GEMS.save(
+                deps.storage,
+                token_uri,
+                &GemKind {
+                    color: color.to_string(),
+                    star: 1,
+                },
+            )?;
+        }
+
+        // the users of the dropped entries join the queue again with their gems
+        let mut attributes = vec![];
+        while let Some(user_info) = USERS_IN_QUEUE_V0_1.pop_front(deps.storage)? {
+            attributes.push(Attribute::new(
+                "dropped_queue_entry",
+                format!("{}/{}/{}", user_info.user_addr, user_info.gem_base.nft_contract, user_info.gem_base.nft_id),
+            ));
+        }
+        QUEUE_LENGTH.save(deps.storage, &0)?;
+
+        Ok(attributes)
+    }
+
+    // the gems of the legacy jobs were identified as "color-star"
+    fn parse_gem_kind(color_and_star: &str) -> Result<GemKind, ContractError> {
+        let (color, star) = color_and_star
+            .split_once('-')
+            .ok_or(ContractError::UnknownGem {})?;
+        match star.parse::<u8>() {
+            Ok(star) if star > 0 => Ok(GemKind {
+                color: color.to_string(),
+                star,
+            }),
+            _ => Err(ContractError::UnknownGem {}),
+        }
+    }
+}
+
+// Unit test for migrate
+#[cfg(test)]
+mod test_migrate {
+    use cosmwasm_schema::cw_serde;
+    use cosmwasm_std::{
+        testing::{mock_dependencies, mock_env, mock_info, MockApi, MockQuerier, MockStorage},
+        from_json, to_json_binary, Addr, ContractInfoResponse, ContractResult, Decimal, OwnedDeps,
+        SystemResult, Timestamp, WasmQuery,
+    };
+    use cw2::{get_contract_version, set_contract_version};
+    use cw_storage_plus::{Deque, Item, Map};
+
+    use crate::{
+        contract::{execute, migrate},
+        error::ContractError,
+        msg::{ExecuteMsg, MigrateMsg},
+        state::{
+            GemInfo, GemKind, JobParticipant, CONFIG, CURRENT_QUEUE_ID, DRAGON_GEMS, GEM_TIERS,
+            QUEUE_LENGTH, RANDOM_JOBS,
+        },
+    };
+
+    #[cw_serde]
+    struct ConfigV0_1 {
+        nois_proxy: Addr,
+        dragon_collection: Addr,
+        auragon_collection: Addr,
+        shield_collection: Addr,
+    }
+
+    #[cw_serde]
+    struct RandomJobV0_1 {
+        gem_base_nft_color_and_star_user_list: Vec<String>,
+        user_success_rate_list: Vec<(Addr, u32)>,
+        timestamp: Timestamp,
+    }
+
+    #[cw_serde]
+    struct UserInfoV0_1 {
+        user_addr: Addr,
+        gem_base: GemInfo,
+        gem_materials: Vec<GemInfo>,
+        shield_id: Option<String>,
+        timestamp: Timestamp,
+    }
+
+    #[cw_serde]
+    struct AuragonURIV0_1 {
+        white: [String; 7],
+        blue: [String; 7],
+        gold: [String; 7],
+        red: [String; 7],
+    }
+
+    const CONTRACT_NAME: &str = "crates.io:wheel-of-fortune";
+
+    fn legacy_deps() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies();
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "0.1.0").unwrap();
+        Item::new("config")
+            .save(
+                &mut deps.storage,
+                &ConfigV0_1 {
+                    nois_proxy: Addr::unchecked("nois_proxy"),
+                    dragon_collection: Addr::unchecked("dragon_collection"),
+                    auragon_collection: Addr::unchecked("auragon_collection"),
+                    shield_collection: Addr::unchecked("shield_collection"),
+                },
+            )
+            .unwrap();
+        Map::<String, RandomJobV0_1>::new("random jobs")
+            .save(
+                &mut deps.storage,
+                "job".to_string(),
+                &RandomJobV0_1 {
+                    gem_base_nft_color_and_star_user_list: vec!["white-1".to_string(), "red-2".to_string()],
+                    user_success_rate_list: vec![(Addr::unchecked("addr1"), 40), (Addr::unchecked("addr2"), 100)],
+                    timestamp: Timestamp::from_seconds(1),
+                },
+            )
+            .unwrap();
+        let uri = |color: &str| -> [String; 7] {
+            core::array::from_fn(|star| format!("ipfs://{}-{}", color, star + 1))
+        };
+        Item::new("auragon uri")
+            .save(
+                &mut deps.storage,
+                &AuragonURIV0_1 {
+                    white: uri("white"),
+                    blue: uri("blue"),
+                    gold: uri("gold"),
+                    red: uri("red"),
+                },
+            )
+            .unwrap();
+        for (color, factor) in [("white", 1), ("blue", 10), ("gold", 100), ("red", 1000)] {
+            let work_power: [Decimal; 7] =
+                core::array::from_fn(|star| Decimal::from_atomics((star as u128 + 1) * factor, 0).unwrap());
+            Item::new(&format!("{} gem work power", color))
+                .save(&mut deps.storage, &work_power)
+                .unwrap();
+        }
+        let work_load: [Decimal; 6] = core::array::from_fn(|star| Decimal::from_atomics(star as u128 + 3, 0).unwrap());
+        Item::new("gem work load").save(&mut deps.storage, &work_load).unwrap();
+        let gem_ratio = [Decimal::percent(90), Decimal::permille(80), Decimal::permille(15), Decimal::permille(5)];
+        Item::new("gem ratio").save(&mut deps.storage, &gem_ratio).unwrap();
+        // both legacy users joined the queue
+        CURRENT_QUEUE_ID.save(&mut deps.storage, &2).unwrap();
+        let queue = Deque::<UserInfoV0_1>::new("users_in_queue");
+        for user in ["addr1", "addr2"] {
+            let user_info = UserInfoV0_1 {
+                user_addr: Addr::unchecked(user),
+                gem_base: GemInfo {
+                    nft_id: user.to_string(),
+                    nft_contract: Addr::unchecked("dragon_collection"),
+                },
+                gem_materials: vec![],
+                shield_id: None,
+                timestamp: Timestamp::from_seconds(1),
+            };
+            queue.push_back(&mut deps.storage, &user_info).unwrap();
+        }
+        // the contract admin is the default owner
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::ContractInfo { .. } => {
+                let mut info = ContractInfoResponse::default();
+                info.admin = Some("admin".to_string());
+                SystemResult::Ok(ContractResult::Ok(to_json_binary(&info).unwrap()))
+            }
+            _ => panic!("unexpected query"),
+        });
+        deps
+    }
+
+    #[test]
+    fn test_migrate_from_v0_1() {
+        let mut deps = legacy_deps();
+
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None, drop_legacy_queue: true }).unwrap();
+        assert!(res.attributes.iter().any(|attr| attr.key == "migrations" && attr.value == "0.2.0"));
+
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(config.owner, Some(Addr::unchecked("admin")));
+        assert_eq!(config.dragon_collection, Addr::unchecked("dragon_collection"));
+        assert_eq!(config.job_timeout, 86_400);
+
+        let job = RANDOM_JOBS.load(&deps.storage, "job".to_string()).unwrap();
+        assert_eq!(
+            job.participants,
+            vec![
+                JobParticipant {
+                    entry_id: None,
+                    user_addr: Addr::unchecked("addr1"),
+                    gem_base: None,
+                    gem_base_kind: GemKind {
+                        color: "white".to_string(),
+                        star: 1
+                    },
+                    gem_materials: vec![],
+                    shield_id: None,
+                    success_rate: 4_000,
+                },
+                JobParticipant {
+                    entry_id: None,
+                    user_addr: Addr::unchecked("addr2"),
+                    gem_base: None,
+                    gem_base_kind: GemKind {
+                        color: "red".to_string(),
+                        star: 2
+                    },
+                    gem_materials: vec![],
+                    shield_id: None,
+                    success_rate: 10_000,
+                },
+            ]
+        );
+        assert_eq!(job.timestamp, Timestamp::from_seconds(1));
+
+        // the legacy entries held no gem in escrow, they are dropped and reported
+        let dropped: Vec<_> = res
+            .attributes
+            .iter()
+            .filter(|attr| attr.key == "dropped_queue_entry")
+            .map(|attr| attr.value.as_str())
+            .collect();
+        assert_eq!(dropped, vec!["addr1/dragon_collection/addr1", "addr2/dragon_collection/addr2"]);
+        assert_eq!(QUEUE_LENGTH.load(&deps.storage).unwrap(), 0);
+        // the new entries do not reuse the ids of the dropped ones
+        assert_eq!(CURRENT_QUEUE_ID.load(&deps.storage).unwrap(), 2);
+        assert!(Deque::<UserInfoV0_1>::new("users_in_queue").is_empty(&deps.storage).unwrap());
+
+        // every color gets a tier of 7 stars sharing the same work loads
+        let tiers: Vec<_> = GEM_TIERS
+            .range(&deps.storage, None, None, cosmwasm_std::Order::Ascending)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(
+            tiers.iter().map(|(color, _)| color.as_str()).collect::<Vec<_>>(),
+            vec!["blue", "gold", "red", "white"]
+        );
+        let red = GEM_TIERS.load(&deps.storage, "red").unwrap();
+        assert_eq!(red.display_name, "Red");
+        assert_eq!(red.max_star, 7);
+        assert_eq!(red.uris[6], "ipfs://red-7");
+        assert_eq!(red.work_powers[1], Decimal::from_atomics(2000u128, 0).unwrap());
+        assert_eq!(red.work_loads, GEM_TIERS.load(&deps.storage, "white").unwrap().work_loads);
+        assert_eq!(red.work_loads[0], Decimal::from_atomics(3u128, 0).unwrap());
+        assert!(!red.retired);
+        assert_eq!(red.gem_ratio, Decimal::permille(5));
+        assert_eq!(GEM_TIERS.load(&deps.storage, "white").unwrap().gem_ratio, Decimal::percent(90));
+        assert!(!Item::<[Decimal; 4]>::new("gem ratio").exists(&deps.storage));
+        assert!(!Item::<[Decimal; 6]>::new("gem work load").exists(&deps.storage));
+        assert!(!Item::<AuragonURIV0_1>::new("auragon uri").exists(&deps.storage));
+
+        // the dragon gems are still recognized
+        assert_eq!(
+            DRAGON_GEMS
+                .load(&deps.storage, "ipfs://QmTUy7E1UnLcbasfQap38kfxBAsFWzPfLmQimTh7pNw4QT")
+                .unwrap(),
+            GemKind {
+                color: "red".to_string(),
+                star: 1
+            }
+        );
+
+        let version = get_contract_version(&deps.storage).unwrap();
+        assert_eq!(version.version, env!("CARGO_PKG_VERSION"));
+
+        // migrating again to the same version has nothing left to do
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None, drop_legacy_queue: true }).unwrap();
+        assert!(!res.attributes.iter().any(|attr| attr.key == "migrations"));
+    }
+
+    #[test]
+    fn test_migrated_entry_cannot_be_left_nor_forged() {
+        let mut deps = legacy_deps();
+        migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None, drop_legacy_queue: true }).unwrap();
+
+        // no gem of the legacy entries is transferred, they stayed with the users
+        let err = execute(deps.as_mut(), mock_env(), mock_info("addr1", &[]), ExecuteMsg::LeaveQueue { entry_id: 0 })
+            .unwrap_err();
+        assert!(matches!(err, ContractError::QueueEntryNotFound { entry_id: 0 }));
+        let err = execute(deps.as_mut(), mock_env(), mock_info("admin", &[]), ExecuteMsg::ForgeGem { entry_ids: vec![0] })
+            .unwrap_err();
+        assert!(matches!(err, ContractError::QueueEntryNotFound { entry_id: 0 }));
+    }
+
+    #[test]
+    fn test_migrate_refuses_to_drop_the_legacy_queue_by_default() {
+        let mut deps = legacy_deps();
+        let msg: MigrateMsg = from_json(r#"{"owner":null}"#).unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), msg).unwrap_err();
+        assert!(matches!(err, ContractError::LegacyQueueNotEmpty { entries: 2 }));
+        // nothing was migrated
+        assert_eq!(Deque::<UserInfoV0_1>::new("users_in_queue").len(&deps.storage).unwrap(), 2);
+        assert_eq!(get_contract_version(&deps.storage).unwrap().version, "0.1.0");
+
+        // an empty legacy queue needs no consent
+        let queue = Deque::<UserInfoV0_1>::new("users_in_queue");
+        while queue.pop_front(&mut deps.storage).unwrap().is_some() {}
+        let res = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None, drop_legacy_queue: false }).unwrap();
+        assert!(res.attributes.iter().any(|attr| attr.key == "migrations" && attr.value == "0.2.0"));
+        assert!(!res.attributes.iter().any(|attr| attr.key == "dropped_queue_entry"));
+    }
+
+    #[test]
+    fn test_migrate_rejects_malformed_legacy_jobs() {
+        for gem_base in ["white", "white-x", "white-0"] {
+            let mut deps = legacy_deps();
+            Map::<String, RandomJobV0_1>::new("random jobs")
+                .save(
+                    &mut deps.storage,
+                    "job".to_string(),
+                    &RandomJobV0_1 {
+                        gem_base_nft_color_and_star_user_list: vec![gem_base.to_string()],
+                        user_success_rate_list: vec![(Addr::unchecked("addr1"), 40)],
+                        timestamp: Timestamp::from_seconds(1),
+                    },
+                )
+                .unwrap();
+            let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None, drop_legacy_queue: true }).unwrap_err();
+            assert!(matches!(err, ContractError::UnknownGem {}), "{}", gem_base);
+        }
+    }
+
+    #[test]
+    fn test_migrate_with_explicit_owner() {
+        let mut deps = legacy_deps();
+        migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: Some("owner".to_string()), drop_legacy_queue: true }).unwrap();
+        let config = CONFIG.load(&deps.storage).unwrap();
+        assert_eq!(config.owner, Some(Addr::unchecked("owner")));
+    }
+
+    #[test]
+    fn test_migrate_refuses_downgrade_and_other_contracts() {
+        let mut deps = legacy_deps();
+        set_contract_version(&mut deps.storage, CONTRACT_NAME, "99.0.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None, drop_legacy_queue: true }).unwrap_err();
+        assert!(matches!(err, ContractError::MigrationDowngrade { .. }));
+
+        set_contract_version(&mut deps.storage, "crates.io:cw721-base", "0.1.0").unwrap();
+        let err = migrate(deps.as_mut(), mock_env(), MigrateMsg { owner: None, drop_legacy_queue: true }).unwrap_err();
+        assert!(matches!(err, ContractError::InvalidMigrationContract { .. }));
+    }
+}
@@ -1,8 +1,10 @@
 use cosmwasm_schema::{cw_serde, QueryResponses};
-use cosmwasm_std::Decimal;
+use cosmwasm_std::{Addr, Coin, Decimal, HexBinary, Uint128};
+use cw20::{Cw20CoinVerified, Cw20ReceiveMsg};
+use cw721::Cw721ReceiveMsg;
 use nois::NoisCallback;
 
-use crate::state::{Config, GemInfo, GemMetadata, RequestForgeGemInfo, UserInfo};
+use crate::state::{AcceptedToken, Config, Escrow, FailurePenalty, FusionRule, PauseState, ForgeResult, GemInfo, GemKind, GemTier, PityConfig, RandomGemSale, RandomJob, RandomMint, SalvageConfig, UserInfo};
 
 
 /// Message type for `instantiate` entry_point
@@ -18,43 +20,72 @@ pub struct InstantiateMsg {
     pub auragon_collection: String,
     // Shield NFT Collection address
     pub shield_collection: String,
-    // White Gem Work Power
-    pub white_gem_work_power: [Decimal; 7],
-    // White Gem uri
-    pub white_gem_uri: [String; 7],
-    // Blue Gem Work Power
-    pub blue_gem_work_power: [Decimal; 7],
-    // Blue Gem uri
-    pub blue_gem_uri: [String; 7],
-    // Gold Gem Work Power
-    pub gold_gem_work_power: [Decimal; 7],
-    // Gold Gem uri
-    pub gold_gem_uri: [String; 7],
-    // Red Gem Work Power
-    pub red_gem_work_power: [Decimal; 7],
-    // Red Gem uri
-    pub red_gem_uri: [String; 7],
+    // Seconds after which a random job whose randomness never came back can be expired
+    pub job_timeout: u64,
+    // Price of a randomness request, paid by this contract to the Nois proxy out of the prepaid Nois balance
+    pub nois_price: Vec<Coin>,
+    // Gem tier of each color
+    pub gem_tiers: Vec<GemTierMsg>,
+    // (token uri, gem) of the dragon gems
+    pub dragon_gems: Vec<(String, GemKind)>,
     // Shield uri
     pub shield_uri: String,
-    // Gem Ratio
-    pub gem_ratio: [Decimal; 4],
-    // Gem work load
-    pub gem_work_load: [Decimal; 6],
+}
+
+/// Settings of the gems of a color
+#[cw_serde]
+pub struct GemTierMsg {
+    pub color: String,
+    pub display_name: String,
+    pub max_star: u8,
+    // token uri of the gems of each star, from 1 star
+    pub uris: Vec<String>,
+    // work power of a material of each star, from 1 star
+    pub work_powers: Vec<Decimal>,
+    // work load to upgrade a gem from each star to the next one, from 1 star to max_star - 1
+    pub work_loads: Vec<Decimal>,
+    // fee to forge a gem_base of this color
+    pub forge_fees: Vec<Coin>,
+    // chance of a random gem to be of this color, relative to the ratio of the other colors
+    pub gem_ratio: Decimal,
+}
+
+/// Message type for `migrate` entry_point
+#[cw_serde]
+pub struct MigrateMsg {
+    // Owner to set when migrating from a version without one, defaults to the contract admin
+    pub owner: Option<String>,
+    // Drop the queue entries of a version that did not escrow the gems, the migration is refused while there are some
+    #[serde(default)]
+    pub drop_legacy_queue: bool,
 }
 
 /// Message type for `execute` entry_point
 #[cw_serde]
 pub enum ExecuteMsg {
-    // All users join the queue to forge gem
-    JoinQueue {
-        gem_base: GemInfo,
-        gem_materials: Vec<GemInfo>,
-        shield_id: Option<String>,
+    // Gems sent by users with cw721 SendNft, the hook message is a ReceiveNftMsg
+    ReceiveNft(Cw721ReceiveMsg),
+    // Accepted cw20 tokens sent by users with cw20 Send, the hook message is a ReceiveCw20Msg
+    Receive(Cw20ReceiveMsg),
+    // Take back a deposited gem that is not used by a queue entry
+    WithdrawNft {
+        nft_contract: String,
+        token_id: String,
     },
-    // Forging gem
+    // Forging gem of the queue entries, the id of the job is returned as ForgeGemResponse data.
+    // The owner forges any entry and a user their own ones, the funds must cover the forge fee of every gem_base
+    // and the rest is returned.
     ForgeGem {
-        request_forge_id: String,
-        forge_gem_list: Vec<RequestForgeGemInfo>,
+        entry_ids: Vec<u64>,
+    },
+    // Remove an entry from the queue and return its gems to the user
+    LeaveQueue {
+        entry_id: u64,
+    },
+    // Give up a random job whose randomness did not come back in time, anyone can call it once the timeout passed.
+    // The entries leave the queue with their gems returned and the fees held for the job are refunded.
+    ExpireJob {
+        job_id: String,
     },
     // SelectGemRewards {
     //     random_seed: String,
@@ -64,10 +95,24 @@ pub enum ExecuteMsg {
     // ForgeGemType1 {
     //     user_list: Vec<UserInfo>,
     // },
-    // Nois callback
+    // Nois callback, the randomness is buffered while the settlement is paused
     NoisReceive {
         callback: NoisCallback,
     },
+    // Settle the jobs whose randomness was buffered, anyone can call it once the settlement resumed
+    SettleBufferedJobs {
+        limit: Option<u32>,
+    },
+    // Settle one buffered job, only sent by this contract to itself so that a job failing to settle
+    // is reverted alone and can expire, instead of holding back the other buffered jobs
+    SettleBufferedJob {
+        job_id: String,
+        randomness: HexBinary,
+    },
+    // Freeze or resume each operation
+    SetPauseState {
+        pause_state: PauseState,
+    },
     // Update collection
     UpdateCollection {
         dragon_collection: Option<String>,
@@ -76,11 +121,103 @@ pub enum ExecuteMsg {
     },
     MintAuragonGem {
         owner: String,
-        gem_trait: GemMetadata,
+        gem_trait: GemKind,
     },
     MintShieldGem {
         owner: String,
     },
+    // Buy a 1 star gem whose color is drawn with the gem ratio once the randomness comes back,
+    // the funds must cover the price and the rest is returned. The id of the job is returned as ForgeGemResponse data.
+    MintRandomGem {},
+    // Burn deposited gems of the same color and star for a gem of the result color and star of their fusion rule
+    FuseGems {
+        gems: Vec<GemInfo>,
+    },
+    // Set how the gems of a color and star are fused, None stops their fusion
+    UpdateFusionRule {
+        color: String,
+        star: u8,
+        rule: Option<FusionRule>,
+    },
+    // Burn deposited gems for fragments, their number is scaled by the work power of the gems
+    SalvageGem {
+        gems: Vec<GemInfo>,
+    },
+    // Spend fragments on a shield minted to the sender
+    CraftShield {},
+    // Set the fragments given by the salvaged gems and the cost of a shield, None stops the salvage
+    UpdateSalvageConfig {
+        salvage_config: Option<SalvageConfig>,
+    },
+    // Set the bonus given to the users failing in a row, None stops giving it
+    UpdatePityConfig {
+        pity_config: Option<PityConfig>,
+    },
+    // Open the sale of the random gems at the given price, None closes it
+    UpdateRandomGemSale {
+        sale: Option<RandomGemSale>,
+    },
+    // Register the gems of a new color
+    AddGemTier {
+        tier: GemTierMsg,
+    },
+    // Replace the settings of a color, the gems already minted keep their token uri
+    UpdateGemTier {
+        tier: GemTierMsg,
+    },
+    // Stop forging and minting the gems of a color
+    RetireGemTier {
+        color: String,
+    },
+    // Recognize the dragon gems with the token uri as the given gem, None stops recognizing them
+    UpdateDragonGem {
+        token_uri: String,
+        gem: Option<GemKind>,
+    },
+    // Set the seconds after which a random job can be expired
+    UpdateJobTimeout {
+        job_timeout: u64,
+    },
+    // Set the price of a randomness request
+    UpdateNoisPrice {
+        nois_price: Vec<Coin>,
+    },
+    // Add the funds to the prepaid Nois balance
+    TopUpNoisBalance {},
+    // Take funds from the prepaid Nois balance, to the sender by default
+    WithdrawNoisBalance {
+        amount: Vec<Coin>,
+        recipient: Option<String>,
+    },
+    // Send forging fees from the treasury, to the sender by default
+    WithdrawTreasury {
+        amount: Vec<Coin>,
+        recipient: Option<String>,
+    },
+    // Send cw20 fees from the treasury, to the sender by default
+    WithdrawCw20Treasury {
+        token: String,
+        amount: Uint128,
+        recipient: Option<String>,
+    },
+    // Accept the cw20 token at the given prices, None stops accepting it
+    UpdateAcceptedToken {
+        token: String,
+        prices: Option<AcceptedToken>,
+    },
+    // Set what happens to the gems of a color when the forge fails
+    UpdateFailurePenalty {
+        color: String,
+        penalty: FailurePenalty,
+    },
+    // Propose a new owner, the transfer only takes effect once they accept it
+    TransferOwnership {
+        new_owner: String,
+    },
+    // Accept a pending ownership transfer
+    AcceptOwnership {},
+    // Give up ownership, privileged messages can no longer be executed afterwards
+    RenounceOwnership {},
 }
 
 #[cw_serde]
@@ -91,4 +228,292 @@ pub enum QueryMsg {
     // Random seed
     #[returns(String)]
     RandomSeed {},
-}
\ No newline at end of file
+    // Sale of the random gems
+    #[returns(RandomGemSaleResponse)]
+    RandomGemSale {},
+    // Operations frozen by the owner
+    #[returns(PauseState)]
+    PauseState {},
+    // Current and pending owner
+    #[returns(OwnershipResponse)]
+    Ownership {},
+    // Results of every participant of a forge job
+    #[returns(ForgeResultsResponse)]
+    ForgeResult {
+        job_id: String,
+    },
+    // Forge results of a user, newest first
+    #[returns(ForgeResultsResponse)]
+    ForgeHistoryByUser {
+        user: String,
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    // Forge results of all users, newest first
+    #[returns(ForgeResultsResponse)]
+    RecentForges {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    // Users waiting in the queue, in the order they joined
+    #[returns(QueueResponse)]
+    Queue {
+        start_after: Option<u64>,
+        limit: Option<u32>,
+    },
+    // Number of entries in the queue
+    #[returns(QueueLengthResponse)]
+    QueueLength {},
+    // Position of the first entry of a user in the queue
+    #[returns(QueuePositionResponse)]
+    QueuePosition {
+        user: String,
+    },
+    // Every entry of a user in the queue
+    #[returns(QueueResponse)]
+    UserQueueEntries {
+        user: String,
+    },
+    // Escrow of a gem sent to this contract
+    #[returns(Option<Escrow>)]
+    Escrow {
+        nft_contract: String,
+        token_id: String,
+    },
+    // Random jobs past their timeout, by job id
+    #[returns(RandomJobsResponse)]
+    ExpiredJobs {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    // Random gem mints past their timeout, by job id
+    #[returns(RandomMintsResponse)]
+    ExpiredRandomMints {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    // Gem tier of a color
+    #[returns(GemTier)]
+    GemTier {
+        color: String,
+    },
+    // Gem tiers of every color, retired ones included
+    #[returns(GemTiersResponse)]
+    GemTiers {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    // Gem recognized from a dragon gem token uri
+    #[returns(Option<GemKind>)]
+    DragonGem {
+        token_uri: String,
+    },
+    // Every recognized dragon gem token uri
+    #[returns(DragonGemsResponse)]
+    DragonGems {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    // Prepaid Nois balance and price of a randomness request
+    #[returns(NoisBalanceResponse)]
+    NoisBalance {},
+    // Forging fees earned by denom
+    #[returns(TreasuryResponse)]
+    Treasury {},
+    // Prices in an accepted cw20 token
+    #[returns(Option<AcceptedToken>)]
+    AcceptedToken {
+        token: String,
+    },
+    // Every accepted cw20 token
+    #[returns(AcceptedTokensResponse)]
+    AcceptedTokens {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    // Penalty on failure of every gem color
+    #[returns(FailurePenaltiesResponse)]
+    FailurePenalties {},
+    // Salvage of gems into fragments, None while gems cannot be salvaged
+    #[returns(Option<SalvageConfig>)]
+    SalvageConfig {},
+    // Fragments of a user
+    #[returns(FragmentsResponse)]
+    Fragments {
+        user: String,
+    },
+    // Fragments of every user
+    #[returns(AllFragmentsResponse)]
+    AllFragments {
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
+    // Bonus given to the users failing in a row, None while no bonus is given
+    #[returns(Option<PityConfig>)]
+    PityConfig {},
+    // Failures in a row of a user and the bonus of their next forge, for each color they failed
+    #[returns(PityStatusResponse)]
+    PityStatus {
+        user: String,
+    },
+    // Every fusion rule
+    #[returns(FusionRulesResponse)]
+    FusionRules {},
+}
+
+/// Hook message of the gems sent to this contract
+#[cw_serde]
+pub enum ReceiveNftMsg {
+    // Keep the gem in escrow to be used as a material or a shield
+    Deposit {},
+    // Join the queue with the gem as gem_base, the materials and the shield must be deposited first
+    // a gem at the max star of its tier is rejected with MaxStarReached
+    JoinQueue {
+        gem_materials: Vec<GemInfo>,
+        shield_id: Option<String>,
+    },
+    // Join the queue and forge the gem right away, only for the tiers without forge fees
+    // as no funds come with the gem, fails with ForgeFeesRequired otherwise
+    Forge {
+        gem_materials: Vec<GemInfo>,
+        shield_id: Option<String>,
+    },
+}
+
+/// Hook message of the cw20 tokens sent to this contract
+#[cw_serde]
+pub enum ReceiveCw20Msg {
+    // Forge the queue entries like ForgeGem, the tokens pay the forge fees and the rest is returned
+    ForgeGem {
+        entry_ids: Vec<u64>,
+    },
+    // Buy a shield minted to the sender, the rest of the tokens is returned
+    BuyShield {},
+}
+
+#[cw_serde]
+pub struct OwnershipResponse {
+    pub owner: Option<Addr>,
+    pub pending_owner: Option<Addr>,
+}
+
+#[cw_serde]
+pub struct RandomJobsResponse {
+    // (job id, job)
+    pub jobs: Vec<(String, RandomJob)>,
+}
+
+#[cw_serde]
+pub struct RandomMintsResponse {
+    // (job id, mint)
+    pub mints: Vec<(String, RandomMint)>,
+}
+
+/// Data of the responses requesting a forge
+#[cw_serde]
+pub struct ForgeGemResponse {
+    pub job_id: String,
+}
+
+#[cw_serde]
+pub struct ForgeResultsResponse {
+    pub results: Vec<ForgeResult>,
+}
+
+#[cw_serde]
+pub struct QueueEntryResponse {
+    pub entry_id: u64,
+    // 0 is the next entry to be forged
+    pub position: u32,
+    pub user_info: UserInfo,
+}
+
+#[cw_serde]
+pub struct QueueResponse {
+    pub entries: Vec<QueueEntryResponse>,
+}
+
+#[cw_serde]
+pub struct QueueLengthResponse {
+    pub length: u32,
+}
+
+#[cw_serde]
+pub struct QueuePositionResponse {
+    // None if the user is not in the queue
+    pub position: Option<u32>,
+}
+
+#[cw_serde]
+pub struct GemTiersResponse {
+    // (color, tier)
+    pub tiers: Vec<(String, GemTier)>,
+}
+
+#[cw_serde]
+pub struct DragonGemsResponse {
+    // (token uri, gem)
+    pub gems: Vec<(String, GemKind)>,
+}
+
+#[cw_serde]
+pub struct RandomGemSaleResponse {
+    // None while the sale is closed
+    pub sale: Option<RandomGemSale>,
+    pub sold: u64,
+}
+
+#[cw_serde]
+pub struct NoisBalanceResponse {
+    pub balances: Vec<Coin>,
+    pub nois_price: Vec<Coin>,
+}
+
+#[cw_serde]
+pub struct TreasuryResponse {
+    pub balances: Vec<Coin>,
+    pub cw20_balances: Vec<Cw20CoinVerified>,
+}
+
+#[cw_serde]
+pub struct AcceptedTokensResponse {
+    // (token, prices)
+    pub tokens: Vec<(Addr, AcceptedToken)>,
+}
+
+#[cw_serde]
+pub struct PityStatus {
+    pub color: String,
+    // Failures in a row
+    pub failures: u32,
+    // Bonus in basis points added to the success rate of the next forge
+    pub bonus: u32,
+}
+
+#[cw_serde]
+pub struct PityStatusResponse {
+    pub statuses: Vec<PityStatus>,
+}
+
+#[cw_serde]
+pub struct FragmentsResponse {
+    pub fragments: Uint128,
+}
+
+#[cw_serde]
+pub struct AllFragmentsResponse {
+    // (user, fragments)
+    pub fragments: Vec<(Addr, Uint128)>,
+}
+
+#[cw_serde]
+pub struct FusionRulesResponse {
+    // (color, star, rule)
+    pub rules: Vec<(String, u8, FusionRule)>,
+}
+
+#[cw_serde]
+pub struct FailurePenaltiesResponse {
+    // (color, penalty)
+    pub penalties: Vec<(String, FailurePenalty)>,
+}
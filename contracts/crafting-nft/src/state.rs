@@ -1,23 +1,41 @@
-use std::str::FromStr;
+use std::fmt;
 
 use cosmwasm_schema::cw_serde;
-use cosmwasm_std::{Addr, Coin, Timestamp, Uint128, Decimal};
-use cw_storage_plus::{Item, Map, Deque};
+use cosmwasm_std::{Addr, Coin, HexBinary, Timestamp, Decimal, Uint128};
+use cw20::Cw20CoinVerified;
+use cw_storage_plus::{Index, IndexList, IndexedMap, Item, Map, MultiIndex, UniqueIndex};
 
 #[cw_serde]
 pub struct Config {
+    // Admin allowed to send privileged messages, None once ownership is renounced
+    pub owner: Option<Addr>,
     pub nois_proxy: Addr,
     pub dragon_collection: Addr,
     pub auragon_collection: Addr,
     pub shield_collection: Addr,
+    // Seconds after which a random job whose randomness never came back can be expired
+    pub job_timeout: u64,
+    // Price of a randomness request, paid by this contract to the Nois proxy out of the prepaid Nois balance
+    pub nois_price: Vec<Coin>,
 }
 
+// Settings of the gems of a color, the stars go from 1 to max_star
 #[cw_serde]
-pub struct AuragonURI {
-    pub white: [String; 7],
-    pub blue: [String; 7],
-    pub gold: [String; 7],
-    pub red: [String; 7],
+pub struct GemTier {
+    pub display_name: String,
+    pub max_star: u8,
+    // token uri of the gems of each star, from 1 star
+    pub uris: Vec<String>,
+    // work power of a material of each star, from 1 star
+    pub work_powers: Vec<Decimal>,
+    // work load to upgrade a gem from each star to the next one, from 1 star to max_star - 1
+    pub work_loads: Vec<Decimal>,
+    // Fee to forge a gem_base of this color
+    pub forge_fees: Vec<Coin>,
+    // Chance of a random gem to be of this color, relative to the ratio of the other colors
+    pub gem_ratio: Decimal,
+    // Retired colors can no longer be forged nor minted, their gems still work as materials
+    pub retired: bool,
 }
 
 #[cw_serde]
@@ -33,31 +51,102 @@ pub struct UserInfo {
     pub gem_materials: Vec<GemInfo>,
     pub shield_id: Option<String>,
     pub timestamp: Timestamp,
+    // Randomness job the entry is being forged in, it cannot leave the queue meanwhile
+    pub job_id: Option<String>,
+}
+
+#[cw_serde]
+pub struct Escrow {
+    // User the gem belongs to, it is returned to them
+    pub owner: Addr,
+    // Queue entry using the gem, None while it is only deposited
+    pub entry_id: Option<u64>,
+}
+
+#[cw_serde]
+pub struct JobParticipant {
+    // Queue entry holding the escrowed gems, None for jobs requested before the queue was used
+    pub entry_id: Option<u64>,
+    pub user_addr: Addr,
+    // None for jobs requested before the gem_base was recorded in the job
+    pub gem_base: Option<GemInfo>,
+    pub gem_base_kind: GemKind,
+    pub gem_materials: Vec<GemInfo>,
+    pub shield_id: Option<String>,
+    // success rate in basis points
+    pub success_rate: u32,
 }
 
 #[cw_serde]
 pub struct RandomJob {
-    pub gem_base_nft_color_and_star_user_list: Vec<String>,
-    pub user_success_rate_list: Vec<(Addr, u32)>,
+    pub participants: Vec<JobParticipant>,
     pub timestamp: Timestamp,
+    // Sender who requested the job, None for jobs requested before it was recorded
+    pub payer: Option<Addr>,
+    // Fees held by this contract for the job, given back to the payer if the job expires
+    pub fees: Vec<Coin>,
+    // Fees paid with a cw20 token, held the same way
+    pub cw20_fees: Vec<Cw20CoinVerified>,
+}
+
+// What happens to the gem_base when the forge fails
+#[cw_serde]
+pub enum FailurePenalty {
+    // the gem_base is burnt
+    Burn,
+    // the gem_base is replaced by a gem of one star less, a 1 star gem is kept
+    Downgrade,
+    // the gem_base goes back to the user
+    Keep,
+}
+
+#[cw_serde]
+pub enum ForgeOutcome {
+    Success,
+    Failure,
 }
 
 #[cw_serde]
-pub struct RequestForgeGemInfo {
+pub struct ForgeResult {
+    // Sequence number, increasing in the order the results are settled
+    pub id: u64,
+    pub job_id: String,
     pub user_addr: Addr,
-    pub gem_base: GemInfo,
+    pub gem_base: Option<GemInfo>,
+    pub gem_base_kind: GemKind,
     pub gem_materials: Vec<GemInfo>,
-    pub success_rate: String,
     pub shield_id: Option<String>,
+    // success rate in basis points
+    pub success_rate: u32,
+    // bonus in basis points added to the success rate for the failures in a row of the user
+    pub pity_bonus: u32,
+    pub outcome: ForgeOutcome,
+    // Penalty applied to the gem_base, only on a failure the shield did not protect from
+    pub penalty: Option<FailurePenalty>,
+    // Whether the shield was consumed to protect the gem_base
+    pub shield_used: bool,
+    // Token id of the gem minted, upgraded on success or downgraded on failure
+    pub minted_token_id: Option<String>,
+    // Randomness the outcome was drawn from
+    pub randomness: HexBinary,
+    // Time the forge was requested
+    pub timestamp: Timestamp,
 }
 
+// Color and star of a gem
 #[cw_serde]
 #[derive(Default)]
-pub struct GemMetadata {
+pub struct GemKind {
     pub color: String,
     pub star: u8,
 }
 
+impl fmt::Display for GemKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.color, self.star)
+    }
+}
+
 #[cw_serde]
 pub struct Trait {
     pub display_type: Option<String>,
@@ -87,86 +176,217 @@ pub struct Metadata {
     pub royalty_payment_address: Option<String>,
 }
 
-pub const WHITE_GEM_WORK_POWER: Item<[Decimal; 7]> = Item::new("white gem work power");
-// [Decimal; 7] =[
-//     Decimal::from_str("2").unwrap(),
-//     Decimal::from_str("3").unwrap(),
-//     Decimal::from_str("5").unwrap(),
-//     Decimal::from_str("8").unwrap(),
-//     Decimal::from_str("13").unwrap(),
-//     Decimal::from_str("21").unwrap(),
-//     Decimal::from_str("34").unwrap(),
-// ];
-
-pub const BLUE_GEM_WORK_POWER: Item<[Decimal; 7]> = Item::new("blue gem work power");
-// [Decimal; 7] =[
-//     Decimal::from_str("22.5").unwrap(),
-//     Decimal::from_str("33.75").unwrap(),
-//     Decimal::from_str("56.25").unwrap(),
-//     Decimal::from_str("90").unwrap(),
-//     Decimal::from_str("146.25").unwrap(),
-//     Decimal::from_str("236.25").unwrap(),
-//     Decimal::from_str("382.5").unwrap(),
-// ];
-
-pub const GOLD_GEM_WORK_POWER: Item<[Decimal; 7]> = Item::new("gold gem work power");
-// [Decimal; 7] =[
-//     Decimal::from_str("120").unwrap(),
-//     Decimal::from_str("180").unwrap(),
-//     Decimal::from_str("300").unwrap(),
-//     Decimal::from_str("480").unwrap(),
-//     Decimal::from_str("780").unwrap(),
-//     Decimal::from_str("1260").unwrap(),
-//     Decimal::from_str("2040").unwrap(),
-// ];
-
-pub const RED_GEM_WORK_POWER: Item<[Decimal; 7]> = Item::new("red gem work power");
-// [Decimal; 7] =[
-//     Decimal::from_str("360").unwrap(),
-//     Decimal::from_str("540").unwrap(),
-//     Decimal::from_str("900").unwrap(),
-//     Decimal::from_str("1440").unwrap(),
-//     Decimal::from_str("2340").unwrap(),
-//     Decimal::from_str("3780").unwrap(),
-//     Decimal::from_str("6120").unwrap(),
-// ];
-
-pub const GEM_RATIO: Item<[Decimal; 4]> = Item::new("gem ratio");
-// [Decimal; 4] = [
-//     Decimal::from_str("0.9").unwrap(),
-//     Decimal::from_str("0.08").unwrap(),
-//     Decimal::from_str("0.015").unwrap(),
-//     Decimal::from_str("0.005").unwrap(),
-// ];
-
-pub const GEM_WORK_LOAD: Item<[Decimal; 6]> = Item::new("gem work load");
-// [Decimal; 6] = [
-//     Decimal::from_str("3").unwrap(),
-//     Decimal::from_str("5").unwrap(),
-//     Decimal::from_str("8").unwrap(),
-//     Decimal::from_str("13").unwrap(),
-//     Decimal::from_str("21").unwrap(),
-//     Decimal::from_str("34").unwrap(),
-// ];
+// Fusion of gems of a color and star into a gem of another color
+#[cw_serde]
+pub struct FusionRule {
+    // Number of gems burnt
+    pub gem_count: u32,
+    // Color of the gem minted, a tier of GEM_TIERS
+    pub result_color: String,
+    // Star of the gem minted
+    pub result_star: u8,
+}
+
+// Fusion rules keyed by (color, star) of the gems burnt, the gems without a rule cannot be fused
+pub const FUSION_RULES: Map<(&str, u8), FusionRule> = Map::new("fusion rules");
 
 pub const CONFIG: Item<Config> = Item::new("config");
 
+// Address proposed as the next owner, waiting to accept the ownership transfer
+pub const PENDING_OWNER: Item<Addr> = Item::new("pending owner");
+
 pub const RANDOM_SEED: Item<[u8; 32]> = Item::new("random seed");
 
 pub const RANDOM_JOBS: Map<String, RandomJob> = Map::new("random jobs");
 
+// Number of the next random job, its id is the number as a string
+pub const CURRENT_JOB_ID: Item<u64> = Item::new("current job id");
+
+pub struct ForgeResultIndexes<'a> {
+    // results of a user, ordered by id
+    pub user: UniqueIndex<'a, (Addr, u64), ForgeResult, (String, Addr)>,
+    // all results, ordered by id
+    pub id: UniqueIndex<'a, u64, ForgeResult, (String, Addr)>,
+}
+
+impl<'a> IndexList<ForgeResult> for ForgeResultIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<ForgeResult>> + '_> {
+        let v: Vec<&dyn Index<ForgeResult>> = vec![&self.user, &self.id];
+        Box::new(v.into_iter())
+    }
+}
+
+// Outcome of every settled forge, keyed by (job id, user)
+pub fn forge_results<'a>() -> IndexedMap<'a, (String, Addr), ForgeResult, ForgeResultIndexes<'a>> {
+    let indexes = ForgeResultIndexes {
+        user: UniqueIndex::new(|result| (result.user_addr.clone(), result.id), "forge results__user"),
+        id: UniqueIndex::new(|result| result.id, "forge results__id"),
+    };
+    IndexedMap::new("forge results", indexes)
+}
+
+// Number of forge results recorded so far, used as the id of the next one
+pub const FORGE_RESULT_COUNT: Item<u64> = Item::new("forge result count");
+
 pub const AURAGON_LATEST_TOKEN_ID: Item<u64> = Item::new("auragon latest token id");
 
 pub const SHIELD_LATEST_TOKEN_ID: Item<u64> = Item::new("shield latest token id");
 
-// DeQueue to store the gem forging requests from users
-pub const USERS_IN_QUEUE: Deque<UserInfo> = Deque::new("users_in_queue");
+pub struct QueueIndexes<'a> {
+    // entries of a user, ordered by entry id
+    pub user: MultiIndex<'a, Addr, UserInfo, u64>,
+}
+
+impl<'a> IndexList<UserInfo> for QueueIndexes<'a> {
+    fn get_indexes(&'_ self) -> Box<dyn Iterator<Item = &'_ dyn Index<UserInfo>> + '_> {
+        let v: Vec<&dyn Index<UserInfo>> = vec![&self.user];
+        Box::new(v.into_iter())
+    }
+}
+
+// Gem forging requests from users, keyed by entry id in the order they joined
+pub fn users_in_queue<'a>() -> IndexedMap<'a, u64, UserInfo, QueueIndexes<'a>> {
+    let indexes = QueueIndexes {
+        user: MultiIndex::new(|_, user_info| user_info.user_addr.clone(), "users in queue", "users in queue__user"),
+    };
+    IndexedMap::new("users in queue", indexes)
+}
+
+// Number of entries in the queue
+pub const QUEUE_LENGTH: Item<u32> = Item::new("queue length");
+
+// Number of entries in the queue by page of entry ids, to count the entries ahead of one without reading the whole queue
+pub const QUEUE_PAGES: Map<u64, u32> = Map::new("queue pages");
 
-// Current Queue ID
+// Id of the next queue entry
 pub const CURRENT_QUEUE_ID: Item<u64> = Item::new("current queue id");
 
-// Auragon URI
-pub const AURAGON_URI: Item<AuragonURI> = Item::new("auragon uri");
+// Gems sent to this contract, keyed by (nft_contract, nft_id)
+pub const ESCROWS: Map<(&Addr, &str), Escrow> = Map::new("escrows");
+
+// Gem tiers keyed by color
+pub const GEM_TIERS: Map<&str, GemTier> = Map::new("gem tiers");
 
 // Shield URI
 pub const SHIELD_URI: Item<String> = Item::new("shield uri");
+
+// Color and star of the dragon gems keyed by token uri, the dragon collection has no attributes
+pub const DRAGON_GEMS: Map<&str, GemKind> = Map::new("dragon gems");
+
+// Bonus to the success rate of the users failing in a row to upgrade gems of a color
+#[cw_serde]
+pub struct PityConfig {
+    // Bonus in basis points after 1, 2, ... failures in a row, the last one applies to longer streaks
+    pub bonus_curve: Vec<u32>,
+    // Max bonus in basis points
+    pub max_bonus: u32,
+}
+
+// No bonus is given until the owner sets the pity config
+pub const PITY_CONFIG: Item<PityConfig> = Item::new("pity config");
+
+// Failures in a row keyed by (user, color), reset on success
+pub const PITY_COUNTERS: Map<(&Addr, &str), u32> = Map::new("pity counters");
+
+// Salvage of unwanted gems into fragments, spent to craft shields
+#[cw_serde]
+pub struct SalvageConfig {
+    // Fragments given per unit of work power of the gems salvaged, rounded down
+    pub fragments_per_work_power: Decimal,
+    // Fragments spent to craft a shield
+    pub shield_cost: Uint128,
+}
+
+// Gems cannot be salvaged until the owner sets the salvage config
+pub const SALVAGE_CONFIG: Item<SalvageConfig> = Item::new("salvage config");
+
+// Fragments of each user
+pub const FRAGMENTS: Map<&Addr, Uint128> = Map::new("fragments");
+
+// Sale of 1 star auragon gems of a color drawn with the gem ratio
+#[cw_serde]
+pub struct RandomGemSale {
+    pub price: Vec<Coin>,
+    // Max number of gems sold, None for no limit
+    pub supply_limit: Option<u64>,
+}
+
+// Random gem bought by a user, minted once the randomness comes back
+#[cw_serde]
+pub struct RandomMint {
+    pub owner: Addr,
+    pub timestamp: Timestamp,
+    // Price held by this contract, given back to the owner if the job expires
+    pub fees: Vec<Coin>,
+}
+
+// The random gems are not sold until the owner opens the sale
+pub const RANDOM_GEM_SALE: Item<RandomGemSale> = Item::new("random gem sale");
+
+// Random gems sold, the pending ones included
+pub const RANDOM_GEMS_SOLD: Item<u64> = Item::new("random gems sold");
+
+// Random gems waiting for their randomness, keyed by job id like the forge jobs
+pub const RANDOM_MINTS: Map<String, RandomMint> = Map::new("random mints");
+
+// Operations frozen by the owner
+#[cw_serde]
+#[derive(Default)]
+pub struct PauseState {
+    // Joining the queue
+    pub join_queue: bool,
+    // Requesting the randomness of a forge
+    pub forge: bool,
+    // Minting auragon gems and shields
+    pub mint: bool,
+    // Settling the forges with the randomness, the callbacks are buffered meanwhile
+    pub settlement: bool,
+}
+
+// Prices in a cw20 token accepted as payment
+#[cw_serde]
+pub struct AcceptedToken {
+    // (color, fee) to forge a gem_base of each color, the colors without a fee cannot be forged with the token
+    pub forge_fees: Vec<(String, Uint128)>,
+    // Price of a shield, None if shields cannot be bought with the token
+    pub shield_price: Option<Uint128>,
+}
+
+impl AcceptedToken {
+    pub fn forge_fee(&self, color: &str) -> Option<Uint128> {
+        self.forge_fees
+            .iter()
+            .find(|(fee_color, _)| fee_color == color)
+            .map(|(_, fee)| *fee)
+    }
+}
+
+// Accepted cw20 tokens keyed by token address
+pub const ACCEPTED_TOKENS: Map<&Addr, AcceptedToken> = Map::new("accepted tokens");
+
+// Fees paid in cw20 tokens, kept by token address like the native ones
+pub const CW20_TREASURY: Map<&Addr, Uint128> = Map::new("cw20 treasury");
+
+// cw20 forge fees held for pending jobs, refunded on expiry and moved to CW20_TREASURY on settlement
+pub const CW20_PENDING_FEES: Map<&Addr, Uint128> = Map::new("cw20 pending fees");
+
+// Funds prepaid by the owner to pay the randomness requests, by denom
+pub const NOIS_BALANCE: Map<&str, Uint128> = Map::new("nois balance");
+
+// Forging fees earned by denom, withdrawn by the owner
+pub const TREASURY: Map<&str, Uint128> = Map::new("treasury");
+
+// Forging fees of the pending random jobs by denom, they are earned once the job is settled or refunded if it expires
+pub const PENDING_FEES: Map<&str, Uint128> = Map::new("pending fees");
+
+// Nothing is paused until the owner sets a pause state
+pub const PAUSE_STATE: Item<PauseState> = Item::new("pause state");
+
+// Randomness received while the settlement is paused, keyed by job id
+pub const BUFFERED_RANDOMNESS: Map<String, [u8; 32]> = Map::new("buffered randomness");
+
+// Buffered jobs being settled in a sub message, keyed by reply id
+pub const SETTLING_JOBS: Map<u64, String> = Map::new("settling jobs");
+
+// Penalty on failure by gem color, the gem_base is kept if none is set
+pub const FAILURE_PENALTIES: Map<&str, FailurePenalty> = Map::new("failure penalties");
@@ -2,19 +2,35 @@
 pub mod env {
     use std::str::FromStr;
 
-    use cosmwasm_std::{Addr, Coin, Decimal, Empty, Uint128};
-    use cw20::MinterResponse;
-    use cw721::Cw721ExecuteMsg;
-    use cw721_base::{InstantiateMsg as Cw721InstantiateMsg, ExecuteMsg as Cw721BaseExecuteMsg, QueryMsg as Cw721BaseQueryMsg};
+    use cosmwasm_std::{
+        to_json_binary, Addr, Binary, Coin, Decimal, Deps, DepsMut, Empty, Env, MessageInfo, Response,
+        StdResult, Uint128,
+    };
+    use cw721_base::{
+        ContractError as Cw721ContractError, Cw721Contract, ExecuteMsg as Cw721ExecuteMsg,
+        InstantiateMsg as Cw721InstantiateMsg, QueryMsg as Cw721QueryMsg,
+    };
     use cw_multi_test::{App, AppBuilder, Contract, ContractWrapper, Executor};
+    use nois::ProxyExecuteMsg;
 
     use crate::contract::{
         execute as ForgingGemExecute, instantiate as ForgingGemInstantiate, query as ForgingGemQuery,
+        reply as ForgingGemReply,
     };
-    use crate::msg::{InstantiateMsg as ForgingGemInstantiateMsg, ExecuteMsg as ForgingGemExecuteMsg, QueryMsg as ForgingGemQueryMsg};
+    use crate::msg::{
+        ExecuteMsg as ForgingGemExecuteMsg, GemTierMsg, InstantiateMsg as ForgingGemInstantiateMsg,
+    };
+    use crate::state::{GemKind, Metadata};
 
     pub const ADMIN: &str = "aura1000000000000000000000000000000000admin";
     pub const USER_1: &str = "aura1000000000000000000000000000000000user1";
+    pub const USER_2: &str = "aura1000000000000000000000000000000000user2";
+
+    // token uri of the 1 star white dragon gems
+    pub const WHITE_DRAGON_GEM_URI: &str = "ipfs://Qme1dXSRNSqYvVQSDEmoL6WHMLqrYajZkszYhbRGj2F2oa";
+
+    // seconds before a random job can be expired
+    pub const JOB_TIMEOUT: u64 = 3600;
 
     pub const NATIVE_DENOM: &str = "uaura";
     pub const NATIVE_BALANCE: u128 = 1_000_000_000_000u128;
@@ -22,6 +38,7 @@ pub mod env {
     pub const NATIVE_DENOM_2: &str = "utaura";
     pub const NATIVE_BALANCE_2: u128 = 1_000_000_000_000u128;
 
+    #[allow(dead_code)]
     pub struct ContractInfo {
         pub contract_addr: String,
         pub contract_code_id: u64,
@@ -49,8 +66,34 @@ pub mod env {
         })
     }
 
+    // a 7 star tier with the uris "https://ipfs.io/ipfs/{prefix}{star}", every color has the same work loads
+    pub fn gem_tier(color: &str, display_name: &str, prefix: &str, work_powers: [&str; 7]) -> GemTierMsg {
+        GemTierMsg {
+            color: color.to_string(),
+            display_name: display_name.to_string(),
+            max_star: 7,
+            uris: (1..=7).map(|star| format!("https://ipfs.io/ipfs/{}{}", prefix, star)).collect(),
+            work_powers: work_powers.iter().map(|work_power| Decimal::from_str(work_power).unwrap()).collect(),
+            work_loads: ["0.1", "0.2", "0.3", "0.4", "0.5", "0.6"]
+                .iter()
+                .map(|work_load| Decimal::from_str(work_load).unwrap())
+                .collect(),
+            forge_fees: vec![],
+            gem_ratio: Decimal::zero(),
+        }
+    }
+
+    // a gem tier drawn for the random gems with the given ratio
+    pub fn with_gem_ratio(tier: GemTierMsg, gem_ratio: &str) -> GemTierMsg {
+        GemTierMsg {
+            gem_ratio: Decimal::from_str(gem_ratio).unwrap(),
+            ..tier
+        }
+    }
+
     pub fn forging_gem_contract_template() -> Box<dyn Contract<Empty>> {
-        let contract = ContractWrapper::new(ForgingGemExecute, ForgingGemInstantiate, ForgingGemQuery);
+        let contract = ContractWrapper::new(ForgingGemExecute, ForgingGemInstantiate, ForgingGemQuery)
+            .with_reply(ForgingGemReply);
         Box::new(contract)
     }
 
@@ -63,15 +106,63 @@ pub mod env {
         Box::new(contract)
     }
 
+    // the auragon gems carry their color and star in the Metadata extension
+    type MetadataCollection<'a> = Cw721Contract<'a, Metadata, Empty, Empty, Empty>;
+
+    fn metadata_collection_instantiate(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        msg: Cw721InstantiateMsg,
+    ) -> StdResult<Response> {
+        MetadataCollection::default().instantiate(deps, env, info, msg)
+    }
+
+    fn metadata_collection_execute(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        msg: Cw721ExecuteMsg<Metadata, Empty>,
+    ) -> Result<Response, Cw721ContractError> {
+        MetadataCollection::default().execute(deps, env, info, msg)
+    }
+
+    fn metadata_collection_query(deps: Deps, env: Env, msg: Cw721QueryMsg<Empty>) -> StdResult<Binary> {
+        MetadataCollection::default().query(deps, env, msg)
+    }
+
     pub fn auragon_collection_contract_template() -> Box<dyn Contract<Empty>> {
         let contract = ContractWrapper::new(
-            cw721_base::entry::execute,
-            cw721_base::entry::instantiate,
-            cw721_base::entry::query,
+            metadata_collection_execute,
+            metadata_collection_instantiate,
+            metadata_collection_query,
         );
         Box::new(contract)
     }
 
+    // the mock proxy accepts every randomness request, the tests send the callbacks themselves
+    fn nois_proxy_instantiate(_deps: DepsMut, _env: Env, _info: MessageInfo, _msg: Empty) -> StdResult<Response> {
+        Ok(Response::new())
+    }
+
+    fn nois_proxy_execute(_deps: DepsMut, _env: Env, _info: MessageInfo, msg: ProxyExecuteMsg) -> StdResult<Response> {
+        match msg {
+            ProxyExecuteMsg::GetNextRandomness { job_id }
+            | ProxyExecuteMsg::GetRandomnessAfter { job_id, .. } => {
+                Ok(Response::new().add_attribute("job_id", job_id))
+            }
+        }
+    }
+
+    fn nois_proxy_query(_deps: Deps, _env: Env, _msg: Empty) -> StdResult<Binary> {
+        to_json_binary(&Empty {})
+    }
+
+    pub fn nois_proxy_contract_template() -> Box<dyn Contract<Empty>> {
+        let contract = ContractWrapper::new(nois_proxy_execute, nois_proxy_instantiate, nois_proxy_query);
+        Box::new(contract)
+    }
+
     pub fn shield_collection_contract_template() -> Box<dyn Contract<Empty>> {
         let contract = ContractWrapper::new(
             cw721_base::entry::execute,
@@ -84,7 +175,7 @@ pub mod env {
     pub fn instantiate_contracts() -> (App, Vec<ContractInfo>) {
         // Create a new app instance
         let mut app = mock_app();
-        // Create a vector to store all contract info ([halo factory - [0])
+        // Create a vector to store all contract info
         let mut contract_info_vec: Vec<ContractInfo> = Vec::new();
 
         // store code of all contracts to the app and get the code ids
@@ -92,6 +183,7 @@ pub mod env {
         let auragon_collection_code_id = app.store_code(auragon_collection_contract_template()); // [1]
         let shield_collection_code_id = app.store_code(shield_collection_contract_template()); // [2]
         let forging_gem_code_id = app.store_code(forging_gem_contract_template()); // [3]
+        let nois_proxy_code_id = app.store_code(nois_proxy_contract_template()); // [4]
 
         // dragon collection contract
         // create instantiate message for contract
@@ -119,12 +211,64 @@ pub mod env {
             contract_code_id: dragon_collection_code_id,
         });
 
-        // auragon collection contract
+        // nois proxy contract
+        let nois_proxy_contract_addr = app
+            .instantiate_contract(
+                nois_proxy_code_id,
+                Addr::unchecked(ADMIN),
+                &Empty {},
+                &[],
+                "test nois proxy",
+                None,
+            )
+            .unwrap();
+
+        // forging gem contract
+        // create instantiate message for contract
+        let forging_gem_instantiate_msg = ForgingGemInstantiateMsg {
+            random_seed: "46FAF1CD4845AB7C5A9DAA7D272259682BF84176A2658DE67CB1317A22134973"
+                .to_string(),
+            nois_proxy: nois_proxy_contract_addr.to_string(),
+            dragon_collection: dragon_collection_contract_addr.to_string(),
+            // the collections minted by this contract are set once they are instantiated
+            auragon_collection: dragon_collection_contract_addr.to_string(),
+            shield_collection: dragon_collection_contract_addr.to_string(),
+            job_timeout: JOB_TIMEOUT,
+            nois_price: vec![],
+            gem_tiers: vec![
+                with_gem_ratio(gem_tier("white", "White", "W", ["2", "3", "5", "8", "13", "21", "34"]), "0.9"),
+                with_gem_ratio(gem_tier("blue", "Blue", "B", ["22.5", "33.75", "56.25", "90", "146.25", "236.25", "382.5"]), "0.08"),
+                with_gem_ratio(gem_tier("gold", "Gold", "G", ["2", "3", "5", "8", "13", "21", "34"]), "0.015"),
+                with_gem_ratio(gem_tier("red", "Red", "R", ["22.5", "33.75", "56.25", "90", "146.25", "236.25", "382.5"]), "0.005"),
+            ],
+            dragon_gems: vec![(
+                WHITE_DRAGON_GEM_URI.to_string(),
+                GemKind {
+                    color: "white".to_string(),
+                    star: 1,
+                },
+            )],
+            shield_uri: "https://ipfs.io/ipfs/S1".to_string(),
+        };
+
+        // instantiate the contract
+        let forging_gem_contract_addr = app
+            .instantiate_contract(
+                forging_gem_code_id,
+                Addr::unchecked(ADMIN),
+                &forging_gem_instantiate_msg,
+                &[],
+                "test forging gem",
+                None,
+            )
+            .unwrap();
+
+        // auragon collection contract, the forging gem contract mints the upgraded gems
         // create instantiate message for contract
         let auragon_collection_instantiate_msg = Cw721InstantiateMsg {
             name: "Auragon Collection".to_string(),
             symbol: "AURAGON".to_string(),
-            minter: ADMIN.to_string(),
+            minter: forging_gem_contract_addr.to_string(),
         };
 
         // instantiate the contract
@@ -139,18 +283,12 @@ pub mod env {
             )
             .unwrap();
 
-        // add contract info to the vector
-        contract_info_vec.push(ContractInfo {
-            contract_addr: auragon_collection_contract_addr.to_string(),
-            contract_code_id: auragon_collection_code_id,
-        });
-
-        // shield collection contract
+        // shield collection contract, the forging gem contract mints the shields
         // create instantiate message for contract
         let shield_collection_instantiate_msg = Cw721InstantiateMsg {
             name: "Shield Collection".to_string(),
             symbol: "SHIELD".to_string(),
-            minter: ADMIN.to_string(),
+            minter: forging_gem_contract_addr.to_string(),
         };
 
         // instantiate the contract
@@ -165,131 +303,63 @@ pub mod env {
             )
             .unwrap();
 
+        app.execute_contract(
+            Addr::unchecked(ADMIN),
+            forging_gem_contract_addr.clone(),
+            &ForgingGemExecuteMsg::UpdateCollection {
+                dragon_collection: None,
+                auragon_collection: Some(auragon_collection_contract_addr.to_string()),
+                shield_collection: Some(shield_collection_contract_addr.to_string()),
+            },
+            &[],
+        )
+        .unwrap();
+
         // add contract info to the vector
+        contract_info_vec.push(ContractInfo {
+            contract_addr: auragon_collection_contract_addr.to_string(),
+            contract_code_id: auragon_collection_code_id,
+        });
         contract_info_vec.push(ContractInfo {
             contract_addr: shield_collection_contract_addr.to_string(),
             contract_code_id: shield_collection_code_id,
         });
-
-        // forging gem contract
-        // create instantiate message for contract
-        let forging_gem_instantiate_msg = ForgingGemInstantiateMsg {
-            random_seed: "46FAF1CD4845AB7C5A9DAA7D272259682BF84176A2658DE67CB1317A22134973"
-                .to_string(),
-            nois_proxy: "aura14s9rm7fneysy7srqcq8mrgk3us9alqwpes3mudshp03z2ktvltaqgux4xg"
-                .to_string(),
-            dragon_collection: dragon_collection_contract_addr.to_string(),
-            auragon_collection: auragon_collection_contract_addr.to_string(),
-            shield_collection: shield_collection_contract_addr.to_string(),
-            white_gem_work_power: [
-                Decimal::from_str("2").unwrap(),
-                Decimal::from_str("3").unwrap(),
-                Decimal::from_str("5").unwrap(),
-                Decimal::from_str("8").unwrap(),
-                Decimal::from_str("13").unwrap(),
-                Decimal::from_str("21").unwrap(),
-                Decimal::from_str("34").unwrap(),
-            ],
-            white_gem_uri: [
-                "https://ipfs.io/ipfs/W1".to_string(),
-                "https://ipfs.io/ipfs/W2".to_string(),
-                "https://ipfs.io/ipfs/W3".to_string(),
-                "https://ipfs.io/ipfs/W4".to_string(),
-                "https://ipfs.io/ipfs/W5".to_string(),
-                "https://ipfs.io/ipfs/W6".to_string(),
-                "https://ipfs.io/ipfs/W7".to_string(),
-            ],
-            blue_gem_work_power: [
-                Decimal::from_str("22.5").unwrap(),
-                Decimal::from_str("33.75").unwrap(),
-                Decimal::from_str("56.25").unwrap(),
-                Decimal::from_str("90").unwrap(),
-                Decimal::from_str("146.25").unwrap(),
-                Decimal::from_str("236.25").unwrap(),
-                Decimal::from_str("382.5").unwrap(),
-            ],
-            blue_gem_uri: [
-                "https://ipfs.io/ipfs/B1".to_string(),
-                "https://ipfs.io/ipfs/B2".to_string(),
-                "https://ipfs.io/ipfs/B3".to_string(),
-                "https://ipfs.io/ipfs/B4".to_string(),
-                "https://ipfs.io/ipfs/B5".to_string(),
-                "https://ipfs.io/ipfs/B6".to_string(),
-                "https://ipfs.io/ipfs/B7".to_string(),
-            ],
-            gold_gem_work_power: [
-                Decimal::from_str("2").unwrap(),
-                Decimal::from_str("3").unwrap(),
-                Decimal::from_str("5").unwrap(),
-                Decimal::from_str("8").unwrap(),
-                Decimal::from_str("13").unwrap(),
-                Decimal::from_str("21").unwrap(),
-                Decimal::from_str("34").unwrap(),
-            ],
-            gold_gem_uri: [
-                "https://ipfs.io/ipfs/G1".to_string(),
-                "https://ipfs.io/ipfs/G2".to_string(),
-                "https://ipfs.io/ipfs/G3".to_string(),
-                "https://ipfs.io/ipfs/G4".to_string(),
-                "https://ipfs.io/ipfs/G5".to_string(),
-                "https://ipfs.io/ipfs/G6".to_string(),
-                "https://ipfs.io/ipfs/G7".to_string(),
-            ],
-            red_gem_work_power: [
-                Decimal::from_str("22.5").unwrap(),
-                Decimal::from_str("33.75").unwrap(),
-                Decimal::from_str("56.25").unwrap(),
-                Decimal::from_str("90").unwrap(),
-                Decimal::from_str("146.25").unwrap(),
-                Decimal::from_str("236.25").unwrap(),
-                Decimal::from_str("382.5").unwrap(),
-            ],
-            red_gem_uri: [
-                "https://ipfs.io/ipfs/R1".to_string(),
-                "https://ipfs.io/ipfs/R2".to_string(),
-                "https://ipfs.io/ipfs/R3".to_string(),
-                "https://ipfs.io/ipfs/R4".to_string(),
-                "https://ipfs.io/ipfs/R5".to_string(),
-                "https://ipfs.io/ipfs/R6".to_string(),
-                "https://ipfs.io/ipfs/R7".to_string(),
-            ],
-            shield_uri: "https://ipfs.io/ipfs/S1".to_string(),
-            gem_ratio: [
-                Decimal::from_str("0.9").unwrap(),
-                Decimal::from_str("0.08").unwrap(),
-                Decimal::from_str("0.015").unwrap(),
-                Decimal::from_str("0.005").unwrap(),
-            ],
-            gem_work_load: [
-                Decimal::from_str("0.1").unwrap(),
-                Decimal::from_str("0.2").unwrap(),
-                Decimal::from_str("0.3").unwrap(),
-                Decimal::from_str("0.4").unwrap(),
-                Decimal::from_str("0.5").unwrap(),
-                Decimal::from_str("0.6").unwrap(),
-            ],
-        };
-
-        // instantiate the contract
-        let forging_gem_contract_addr = app
-            .instantiate_contract(
-                forging_gem_code_id,
-                Addr::unchecked(ADMIN),
-                &forging_gem_instantiate_msg,
-                &[],
-                "test forging gem",
-                None,
-            )
-            .unwrap();
-
-        // add contract info to the vector
         contract_info_vec.push(ContractInfo {
             contract_addr: forging_gem_contract_addr.to_string(),
             contract_code_id: forging_gem_code_id,
         });
+        contract_info_vec.push(ContractInfo {
+            contract_addr: nois_proxy_contract_addr.to_string(),
+            contract_code_id: nois_proxy_code_id,
+        });
 
         (app, contract_info_vec)
     }
 
+    // mint a 1 star white dragon gem to `owner`
+    pub fn mint_dragon_gem(app: &mut App, contracts: &[ContractInfo], owner: &str, token_id: &str) {
+        mint_dragon_gem_with_uri(app, contracts, owner, token_id, WHITE_DRAGON_GEM_URI);
+    }
 
-}
\ No newline at end of file
+    // mint a dragon gem with the given token uri to `owner`
+    pub fn mint_dragon_gem_with_uri(
+        app: &mut App,
+        contracts: &[ContractInfo],
+        owner: &str,
+        token_id: &str,
+        token_uri: &str,
+    ) {
+        app.execute_contract(
+            Addr::unchecked(ADMIN),
+            Addr::unchecked(&contracts[0].contract_addr),
+            &Cw721ExecuteMsg::<Option<Empty>, Empty>::Mint {
+                token_id: token_id.to_string(),
+                owner: owner.to_string(),
+                token_uri: Some(token_uri.to_string()),
+                extension: None,
+            },
+            &[],
+        )
+        .unwrap();
+    }
+}
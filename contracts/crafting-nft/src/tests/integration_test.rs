@@ -2,28 +2,17 @@
 mod tests {
     const INIT_1000_000_NATIVE_BALANCE_2: u128 = 1_000_000_000_000u128;
     mod execute_proper_operation {
-        use crate::state::{Config, GemInfo, GemMetadata, CONFIG, AURAGON_LATEST_TOKEN_ID, RANDOM_SEED};
         use crate::tests::integration_test::tests::INIT_1000_000_NATIVE_BALANCE_2;
         use cosmwasm_std::{
-            from_json, to_json_binary, Addr, BalanceResponse as BankBalanceResponse, BankQuery,
-            BlockInfo, Coin, Decimal, Querier, QueryRequest, Uint128, WasmQuery,
+            from_json, to_json_binary, BalanceResponse as BankBalanceResponse, BankQuery,
+            Querier, QueryRequest, Uint128,
         };
-        use cw_multi_test::Executor;
-        use crate::msg::{ExecuteMsg as ForgingGemExecuteMsg, InstantiateMsg as ForgingGemInstantiateMsg, QueryMsg as ForgingGemQueryMsg};
-        use crate::tests::env_setup::env::{forging_gem_contract_template, instantiate_contracts, ADMIN, NATIVE_DENOM_2};
+        use crate::tests::env_setup::env::{instantiate_contracts, ADMIN, NATIVE_DENOM_2};
 
         #[test]
         fn proper_operation() {
             // get integration test app and contracts
-            let (mut app, contracts) = instantiate_contracts();
-            // get dragon_collection contract address
-            let dragon_collection_addr = &contracts[0].contract_addr;
-            // get auragon_collection contract address
-            let auragon_collection_addr = &contracts[1].contract_addr;
-            // get shield_collection contract address
-            let shield_collection_addr = &contracts[2].contract_addr;
-            // get forging_gem contract address
-            let forging_gem_addr = &contracts[3].contract_addr;
+            let (app, _contracts) = instantiate_contracts();
 
             // query balance of ADMIN in native token
             let req: QueryRequest<BankQuery> = QueryRequest::Bank(BankQuery::Balance {
@@ -41,4 +30,1772 @@ mod tests {
 
         }
     }
-}
\ No newline at end of file
+
+    mod ownership {
+        use cosmwasm_std::Addr;
+        use cw_multi_test::Executor;
+
+        use crate::error::ContractError;
+        use crate::msg::{ExecuteMsg as ForgingGemExecuteMsg, OwnershipResponse, QueryMsg as ForgingGemQueryMsg};
+        use crate::tests::env_setup::env::{instantiate_contracts, ADMIN, USER_1, USER_2};
+
+        #[test]
+        fn only_owner_can_send_privileged_messages() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+
+            let update_collection_msg = ForgingGemExecuteMsg::UpdateCollection {
+                dragon_collection: Some(USER_1.to_string()),
+                auragon_collection: None,
+                shield_collection: None,
+            };
+
+            // a random user cannot repoint collections
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &update_collection_msg,
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+
+            // nor mint shields
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::MintShieldGem {
+                        owner: USER_1.to_string(),
+                    },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+
+            // the owner can
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &update_collection_msg,
+                &[],
+            )
+            .unwrap();
+        }
+
+        #[test]
+        fn two_step_ownership_transfer() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+
+            // the instantiator is the owner
+            let ownership: OwnershipResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Ownership {})
+                .unwrap();
+            assert_eq!(ownership.owner, Some(Addr::unchecked(ADMIN)));
+            assert_eq!(ownership.pending_owner, None);
+
+            // only the owner can propose a new owner
+            app.execute_contract(
+                Addr::unchecked(USER_1),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::TransferOwnership {
+                    new_owner: USER_1.to_string(),
+                },
+                &[],
+            )
+            .unwrap_err();
+
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::TransferOwnership {
+                    new_owner: USER_1.to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+
+            let ownership: OwnershipResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Ownership {})
+                .unwrap();
+            assert_eq!(ownership.owner, Some(Addr::unchecked(ADMIN)));
+            assert_eq!(ownership.pending_owner, Some(Addr::unchecked(USER_1)));
+
+            // only the pending owner can accept
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_2),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::AcceptOwnership {},
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+
+            app.execute_contract(
+                Addr::unchecked(USER_1),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::AcceptOwnership {},
+                &[],
+            )
+            .unwrap();
+
+            let ownership: OwnershipResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Ownership {})
+                .unwrap();
+            assert_eq!(ownership.owner, Some(Addr::unchecked(USER_1)));
+            assert_eq!(ownership.pending_owner, None);
+
+            // the previous owner lost its rights
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::MintShieldGem {
+                    owner: ADMIN.to_string(),
+                },
+                &[],
+            )
+            .unwrap_err();
+
+            // nothing left to accept
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::AcceptOwnership {},
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::NoPendingOwner {}));
+        }
+
+        #[test]
+        fn renounce_ownership() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::TransferOwnership {
+                    new_owner: USER_1.to_string(),
+                },
+                &[],
+            )
+            .unwrap();
+
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::RenounceOwnership {},
+                &[],
+            )
+            .unwrap();
+
+            // renouncing also drops the pending transfer
+            let ownership: OwnershipResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Ownership {})
+                .unwrap();
+            assert_eq!(ownership.owner, None);
+            assert_eq!(ownership.pending_owner, None);
+
+            app.execute_contract(
+                Addr::unchecked(USER_1),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::AcceptOwnership {},
+                &[],
+            )
+            .unwrap_err();
+
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::MintShieldGem {
+                    owner: ADMIN.to_string(),
+                },
+                &[],
+            )
+            .unwrap_err();
+        }
+    }
+
+    mod forge_gem {
+        use cosmwasm_std::{Addr, Decimal};
+        use cw721::{Cw721QueryMsg, NftInfoResponse};
+        use cw_multi_test::Executor;
+
+        use crate::error::ContractError;
+
+        use crate::msg::{
+            ExecuteMsg as ForgingGemExecuteMsg, FailurePenaltiesResponse, GemTierMsg, GemTiersResponse,
+            QueryMsg as ForgingGemQueryMsg,
+        };
+        use crate::state::{FailurePenalty, GemKind, GemTier, Metadata, PityConfig};
+        use crate::tests::env_setup::env::{instantiate_contracts, ADMIN, USER_1};
+
+        #[test]
+        fn update_pity_config() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let update = |bonus_curve: Vec<u32>, max_bonus: u32| ForgingGemExecuteMsg::UpdatePityConfig {
+                pity_config: Some(PityConfig { bonus_curve, max_bonus }),
+            };
+
+            let err = app
+                .execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &update(vec![1_000], 1_000), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+            // no bonus goes over 10_000 basis points
+            for (bonus_curve, max_bonus) in [(vec![1_000, 10_001], 10_000), (vec![1_000], 10_001)] {
+                let err = app
+                    .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &update(bonus_curve, max_bonus), &[])
+                    .unwrap_err();
+                assert!(matches!(err.downcast().unwrap(), ContractError::InvalidPityConfig { .. }));
+            }
+
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &update(vec![1_000, 10_000], 10_000), &[])
+                .unwrap();
+            let pity_config: Option<PityConfig> = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::PityConfig {})
+                .unwrap();
+            assert_eq!(pity_config, Some(PityConfig { bonus_curve: vec![1_000, 10_000], max_bonus: 10_000 }));
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdatePityConfig { pity_config: None },
+                &[],
+            )
+            .unwrap();
+            let pity_config: Option<PityConfig> = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::PityConfig {})
+                .unwrap();
+            assert_eq!(pity_config, None);
+        }
+
+        #[test]
+        fn update_failure_penalty() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let update = |color: &str| ForgingGemExecuteMsg::UpdateFailurePenalty {
+                color: color.to_string(),
+                penalty: FailurePenalty::Downgrade,
+            };
+
+            let err = app
+                .execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &update("gold"), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+            let err = app
+                .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &update("pink"), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::GemTierNotFound { color } if color == "pink"));
+
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &update("gold"), &[])
+                .unwrap();
+            // the gems are kept by default
+            let res: FailurePenaltiesResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::FailurePenalties {})
+                .unwrap();
+            assert_eq!(
+                res.penalties,
+                vec![
+                    ("blue".to_string(), FailurePenalty::Keep),
+                    ("gold".to_string(), FailurePenalty::Downgrade),
+                    ("red".to_string(), FailurePenalty::Keep),
+                    ("white".to_string(), FailurePenalty::Keep),
+                ]
+            );
+        }
+
+        #[test]
+        fn manage_gem_tiers() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let pink = |max_star: u8, work_loads: usize| GemTierMsg {
+                color: "pink".to_string(),
+                display_name: "Pink".to_string(),
+                max_star,
+                uris: (1..=max_star).map(|star| format!("ipfs://pink-{}", star)).collect(),
+                work_powers: vec![Decimal::one(); max_star.into()],
+                work_loads: vec![Decimal::one(); work_loads],
+                forge_fees: vec![],
+                gem_ratio: Decimal::zero(),
+            };
+
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::AddGemTier { tier: pink(3, 2) },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+            // one work load per upgrade, there is none from the max star
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(ADMIN),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::AddGemTier { tier: pink(3, 3) },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::InvalidGemTier { .. }));
+            // the gems of a color unknown to the registry cannot be minted
+            let mint = |color: &str, star: u8| ForgingGemExecuteMsg::MintAuragonGem {
+                owner: USER_1.to_string(),
+                gem_trait: GemKind {
+                    color: color.to_string(),
+                    star,
+                },
+            };
+            let err = app
+                .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &mint("pink", 1), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::GemTierNotFound { .. }));
+
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::AddGemTier { tier: pink(3, 2) },
+                &[],
+            )
+            .unwrap();
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(ADMIN),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::AddGemTier { tier: pink(3, 2) },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::GemTierExists { .. }));
+
+            // the new color is minted with the uri and name of its tier, up to its max star
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &mint("pink", 3), &[])
+                .unwrap();
+            let nft_info: NftInfoResponse<Metadata> = app
+                .wrap()
+                .query_wasm_smart(&contracts[1].contract_addr, &Cw721QueryMsg::NftInfo { token_id: "1".to_string() })
+                .unwrap();
+            assert_eq!(nft_info.token_uri, Some("ipfs://pink-3".to_string()));
+            assert_eq!(nft_info.extension.name, Some("Pink 3 Star".to_string()));
+            let err = app
+                .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &mint("pink", 4), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::StarOutOfRange {}));
+
+            // raising the max star allows the next star
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateGemTier { tier: pink(4, 3) },
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &mint("pink", 4), &[])
+                .unwrap();
+
+            // a retired color stays in the registry but cannot be minted anymore, even once updated
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::RetireGemTier { color: "pink".to_string() },
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateGemTier { tier: pink(4, 3) },
+                &[],
+            )
+            .unwrap();
+            let err = app
+                .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &mint("pink", 1), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::GemTierRetired { .. }));
+
+            let tier: GemTier = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::GemTier { color: "pink".to_string() })
+                .unwrap();
+            assert_eq!(tier.max_star, 4);
+            assert!(tier.retired);
+            let res: GemTiersResponse = app
+                .wrap()
+                .query_wasm_smart(
+                    forging_gem_addr,
+                    &ForgingGemQueryMsg::GemTiers {
+                        start_after: Some("gold".to_string()),
+                        limit: Some(2),
+                    },
+                )
+                .unwrap();
+            assert_eq!(
+                res.tiers.iter().map(|(color, _)| color.as_str()).collect::<Vec<_>>(),
+                vec!["pink", "red"]
+            );
+        }
+
+        #[test]
+        fn forge_only_queued_entries() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(ADMIN),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::ForgeGem {
+                        entry_ids: vec![0],
+                    },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast().unwrap(),
+                ContractError::QueueEntryNotFound { entry_id: 0 }
+            ));
+
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::LeaveQueue { entry_id: 0 },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast().unwrap(),
+                ContractError::QueueEntryNotFound { entry_id: 0 }
+            ));
+        }
+    }
+
+    mod escrow {
+        use cosmwasm_std::{coins, from_json, to_json_binary, Addr, Decimal, Empty, Event, HexBinary, Timestamp, Uint128};
+        use cw721::{Cw721ExecuteMsg, Cw721QueryMsg, Cw721ReceiveMsg, NftInfoResponse, OwnerOfResponse};
+        use cw_multi_test::{App, AppResponse, Executor};
+        use nois::{select_from_weighted, NoisCallback};
+
+        use crate::error::ContractError;
+        use crate::msg::{
+            AllFragmentsResponse, DragonGemsResponse, ExecuteMsg as ForgingGemExecuteMsg, ForgeGemResponse, ForgeResultsResponse,
+            FragmentsResponse, FusionRulesResponse, NoisBalanceResponse, QueryMsg as ForgingGemQueryMsg, QueueResponse,
+            RandomGemSaleResponse, RandomJobsResponse, RandomMintsResponse, ReceiveNftMsg, TreasuryResponse,
+        };
+        use crate::state::{Escrow, ForgeOutcome, FusionRule, GemInfo, GemKind, Metadata, PauseState, RandomGemSale, SalvageConfig};
+        use crate::tests::env_setup::env::{
+            gem_tier, instantiate_contracts, mint_dragon_gem, mint_dragon_gem_with_uri, with_gem_ratio, ContractInfo, ADMIN,
+            JOB_TIMEOUT, NATIVE_DENOM, NATIVE_DENOM_2, USER_1, USER_2,
+        };
+
+        fn dragon_gem(contracts: &[ContractInfo], token_id: &str) -> GemInfo {
+            GemInfo {
+                nft_id: token_id.to_string(),
+                nft_contract: Addr::unchecked(&contracts[0].contract_addr),
+            }
+        }
+
+        // send a dragon gem to the forging gem contract with a hook message
+        fn send_dragon_gem(
+            app: &mut App,
+            contracts: &[ContractInfo],
+            sender: &str,
+            token_id: &str,
+            hook: &ReceiveNftMsg,
+        ) -> Result<AppResponse, ContractError> {
+            app.execute_contract(
+                Addr::unchecked(sender),
+                Addr::unchecked(&contracts[0].contract_addr),
+                &Cw721ExecuteMsg::SendNft {
+                    contract: contracts[3].contract_addr.clone(),
+                    token_id: token_id.to_string(),
+                    msg: to_json_binary(hook).unwrap(),
+                },
+                &[],
+            )
+            .map_err(|err| err.downcast().unwrap())
+        }
+
+        fn owner_of(app: &App, collection: &str, token_id: &str) -> String {
+            let res: OwnerOfResponse = app
+                .wrap()
+                .query_wasm_smart(
+                    collection,
+                    &Cw721QueryMsg::OwnerOf {
+                        token_id: token_id.to_string(),
+                        include_expired: None,
+                    },
+                )
+                .unwrap();
+            res.owner
+        }
+
+        fn escrow(app: &App, contracts: &[ContractInfo], token_id: &str) -> Option<Escrow> {
+            app.wrap()
+                .query_wasm_smart(
+                    &contracts[3].contract_addr,
+                    &ForgingGemQueryMsg::Escrow {
+                        nft_contract: contracts[0].contract_addr.clone(),
+                        token_id: token_id.to_string(),
+                    },
+                )
+                .unwrap()
+        }
+
+        // USER_1 joins the queue with dragon gem 1 as gem_base and dragon gem 2 as material
+        fn join_queue(app: &mut App, contracts: &[ContractInfo]) {
+            mint_dragon_gem(app, contracts, USER_1, "1");
+            mint_dragon_gem(app, contracts, USER_1, "2");
+            send_dragon_gem(app, contracts, USER_1, "2", &ReceiveNftMsg::Deposit {}).unwrap();
+            send_dragon_gem(
+                app,
+                contracts,
+                USER_1,
+                "1",
+                &ReceiveNftMsg::JoinQueue {
+                    gem_materials: vec![dragon_gem(contracts, "2")],
+                    shield_id: None,
+                },
+            )
+            .unwrap();
+        }
+
+        #[test]
+        fn join_queue_and_leave_with_refund() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            join_queue(&mut app, &contracts);
+
+            // the gems are escrowed by the contract for the entry
+            let queue: QueueResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Queue { start_after: None, limit: None })
+                .unwrap();
+            assert_eq!(queue.entries.len(), 1);
+            assert_eq!(queue.entries[0].user_info.user_addr, Addr::unchecked(USER_1));
+            for token_id in ["1", "2"] {
+                assert_eq!(owner_of(&app, &contracts[0].contract_addr, token_id), *forging_gem_addr);
+                assert_eq!(
+                    escrow(&app, &contracts, token_id),
+                    Some(Escrow { owner: Addr::unchecked(USER_1), entry_id: Some(0) })
+                );
+            }
+
+            // a used gem cannot be withdrawn on its own
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::WithdrawNft {
+                        nft_contract: contracts[0].contract_addr.clone(),
+                        token_id: "2".to_string(),
+                    },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::GemInUse { entry_id: 0, .. }));
+
+            app.execute_contract(
+                Addr::unchecked(USER_1),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::LeaveQueue { entry_id: 0 },
+                &[],
+            )
+            .unwrap();
+            for token_id in ["1", "2"] {
+                assert_eq!(owner_of(&app, &contracts[0].contract_addr, token_id), USER_1);
+                assert_eq!(escrow(&app, &contracts, token_id), None);
+            }
+        }
+
+        #[test]
+        fn deposit_and_withdraw() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            mint_dragon_gem(&mut app, &contracts, USER_1, "1");
+            send_dragon_gem(&mut app, &contracts, USER_1, "1", &ReceiveNftMsg::Deposit {}).unwrap();
+
+            let withdraw = ForgingGemExecuteMsg::WithdrawNft {
+                nft_contract: contracts[0].contract_addr.clone(),
+                token_id: "1".to_string(),
+            };
+            // only the depositor can take it back
+            let err = app
+                .execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &withdraw, &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+
+            app.execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &withdraw, &[])
+                .unwrap();
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "1"), USER_1);
+            assert_eq!(escrow(&app, &contracts, "1"), None);
+        }
+
+        #[test]
+        fn only_deposited_gems_of_the_user_can_be_used() {
+            let (mut app, contracts) = instantiate_contracts();
+            mint_dragon_gem(&mut app, &contracts, USER_1, "1");
+            mint_dragon_gem(&mut app, &contracts, USER_2, "2");
+            mint_dragon_gem(&mut app, &contracts, USER_2, "3");
+            send_dragon_gem(&mut app, &contracts, USER_2, "2", &ReceiveNftMsg::Deposit {}).unwrap();
+
+            let join_queue = |material: &str| ReceiveNftMsg::JoinQueue {
+                gem_materials: vec![dragon_gem(&contracts, material)],
+                shield_id: None,
+            };
+            // gem 2 is deposited by USER_2
+            let err = send_dragon_gem(&mut app, &contracts, USER_1, "1", &join_queue("2")).unwrap_err();
+            assert!(matches!(err, ContractError::Unauthorized {}));
+            // gem 3 is not deposited
+            let err = send_dragon_gem(&mut app, &contracts, USER_1, "1", &join_queue("3")).unwrap_err();
+            assert!(matches!(err, ContractError::GemNotEscrowed { .. }));
+
+            // gems can only come from the collections
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(&contracts[3].contract_addr),
+                    &ForgingGemExecuteMsg::ReceiveNft(Cw721ReceiveMsg {
+                        sender: USER_1.to_string(),
+                        token_id: "1".to_string(),
+                        msg: to_json_binary(&ReceiveNftMsg::Deposit {}).unwrap(),
+                    }),
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::InvalidCollection { .. }));
+        }
+
+        #[test]
+        fn forge_queued_gems() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            join_queue(&mut app, &contracts);
+
+            let res = app
+                .execute_contract(
+                    Addr::unchecked(ADMIN),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::ForgeGem {
+                        entry_ids: vec![0],
+                    },
+                    &[],
+                )
+                .unwrap();
+            // the job id is generated by the contract
+            let job_id = from_json::<ForgeGemResponse>(res.data.unwrap()).unwrap().job_id;
+            assert_eq!(job_id, "0");
+
+            // the entry is pending until the randomness comes back
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::LeaveQueue { entry_id: 0 },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::QueueEntryInJob { entry_id: 0, .. }));
+
+            app.execute_contract(
+                Addr::unchecked(&contracts[4].contract_addr),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::NoisReceive {
+                    callback: NoisCallback {
+                        job_id: job_id.clone(),
+                        published: Timestamp::from_seconds(1),
+                        randomness: HexBinary::from([7u8; 32]),
+                    },
+                },
+                &[],
+            )
+            .unwrap();
+
+            // a white-1 material is enough to upgrade a white-1 gem
+            let results: ForgeResultsResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ForgeResult { job_id })
+                .unwrap();
+            assert_eq!(results.results[0].outcome, ForgeOutcome::Success);
+            assert_eq!(results.results[0].minted_token_id, Some("1".to_string()));
+
+            let gem: NftInfoResponse<Metadata> = app
+                .wrap()
+                .query_wasm_smart(&contracts[1].contract_addr, &Cw721QueryMsg::NftInfo { token_id: "1".to_string() })
+                .unwrap();
+            let attributes = gem.extension.attributes.unwrap();
+            assert_eq!((attributes[0].value.as_str(), attributes[1].value.as_str()), ("white", "2"));
+            assert_eq!(owner_of(&app, &contracts[1].contract_addr, "1"), USER_1);
+
+            // the gem_base and the material are burnt
+            for token_id in ["1", "2"] {
+                let burnt: Result<NftInfoResponse<Empty>, _> = app
+                    .wrap()
+                    .query_wasm_smart(&contracts[0].contract_addr, &Cw721QueryMsg::NftInfo { token_id: token_id.to_string() });
+                assert!(burnt.is_err());
+            }
+            assert_eq!(escrow(&app, &contracts, "1"), None);
+            assert_eq!(escrow(&app, &contracts, "2"), None);
+        }
+
+        #[test]
+        fn settlement_is_atomic() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            join_queue(&mut app, &contracts);
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ForgeGem {
+                    entry_ids: vec![0],
+                },
+                &[],
+            )
+            .unwrap();
+
+            // this contract cannot mint on the dragon collection so the upgrade fails
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateCollection {
+                    dragon_collection: None,
+                    auragon_collection: Some(contracts[0].contract_addr.clone()),
+                    shield_collection: None,
+                },
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(
+                Addr::unchecked(&contracts[4].contract_addr),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::NoisReceive {
+                    callback: NoisCallback {
+                        job_id: "0".to_string(),
+                        published: Timestamp::from_seconds(1),
+                        randomness: HexBinary::from([7u8; 32]),
+                    },
+                },
+                &[],
+            )
+            .unwrap_err();
+
+            // nothing is burnt and the gems stay in escrow
+            for token_id in ["1", "2"] {
+                assert_eq!(owner_of(&app, &contracts[0].contract_addr, token_id), *forging_gem_addr);
+                assert_eq!(
+                    escrow(&app, &contracts, token_id),
+                    Some(Escrow { owner: Addr::unchecked(USER_1), entry_id: Some(0) })
+                );
+            }
+            let results: ForgeResultsResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ForgeResult { job_id: "0".to_string() })
+                .unwrap();
+            assert!(results.results.is_empty());
+        }
+
+        #[test]
+        fn expire_stuck_job() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            join_queue(&mut app, &contracts);
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ForgeGem {
+                    entry_ids: vec![0],
+                },
+                &[],
+            )
+            .unwrap();
+            let expire = ForgingGemExecuteMsg::ExpireJob { job_id: "0".to_string() };
+            let expired_jobs = |app: &App| -> RandomJobsResponse {
+                app.wrap()
+                    .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ExpiredJobs { start_after: None, limit: None })
+                    .unwrap()
+            };
+
+            // the randomness may still come back
+            let err = app
+                .execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &expire, &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::JobNotExpired { job_id, .. } if job_id == "0"));
+            assert!(expired_jobs(&app).jobs.is_empty());
+
+            // anyone can expire the job once the timeout passed
+            app.update_block(|block| block.time = block.time.plus_seconds(JOB_TIMEOUT));
+            assert_eq!(expired_jobs(&app).jobs.len(), 1);
+            app.execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &expire, &[])
+                .unwrap();
+            assert!(expired_jobs(&app).jobs.is_empty());
+
+            // the entry left the queue and the gems are back to the user
+            for token_id in ["1", "2"] {
+                assert_eq!(owner_of(&app, &contracts[0].contract_addr, token_id), USER_1);
+                assert_eq!(escrow(&app, &contracts, token_id), None);
+            }
+            let queue: QueueResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Queue { start_after: None, limit: None })
+                .unwrap();
+            assert!(queue.entries.is_empty());
+
+            // the late randomness finds no job to settle
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(&contracts[4].contract_addr),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::NoisReceive {
+                        callback: NoisCallback {
+                            job_id: "0".to_string(),
+                            published: Timestamp::from_seconds(1),
+                            randomness: HexBinary::from([7u8; 32]),
+                        },
+                    },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::RandomJobNotFound {}));
+        }
+
+        #[test]
+        fn forge_fees_go_to_the_treasury() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let mut white = gem_tier("white", "White", "W", ["2", "3", "5", "8", "13", "21", "34"]);
+            white.forge_fees = coins(100, NATIVE_DENOM);
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateGemTier { tier: white },
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateNoisPrice { nois_price: coins(10, NATIVE_DENOM_2) },
+                &[],
+            )
+            .unwrap();
+            app.send_tokens(Addr::unchecked(ADMIN), Addr::unchecked(USER_1), &coins(1000, NATIVE_DENOM))
+                .unwrap();
+            join_queue(&mut app, &contracts);
+            let forge = ForgingGemExecuteMsg::ForgeGem { entry_ids: vec![0] };
+            let balance = |app: &App, addr: &str, denom: &str| app.wrap().query_balance(addr, denom).unwrap().amount;
+            let treasury = |app: &App| -> TreasuryResponse {
+                app.wrap().query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Treasury {}).unwrap()
+            };
+
+            // a user can only forge their own entries
+            let err = app
+                .execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &forge, &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+            let err = app
+                .execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &forge, &coins(50, NATIVE_DENOM))
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::InsufficentFund {}));
+            // the fees do not pay the randomness
+            let err = app
+                .execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &forge, &coins(150, NATIVE_DENOM))
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast().unwrap(),
+                ContractError::InsufficientNoisBalance { denom } if denom == NATIVE_DENOM_2
+            ));
+
+            // the contract pays the randomness out of the prepaid balance and the excess funds are returned
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::TopUpNoisBalance {},
+                &coins(15, NATIVE_DENOM_2),
+            )
+            .unwrap();
+            app.execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &forge, &coins(150, NATIVE_DENOM))
+                .unwrap();
+            assert_eq!(balance(&app, USER_1, NATIVE_DENOM), Uint128::new(900));
+            assert_eq!(balance(&app, &contracts[4].contract_addr, NATIVE_DENOM_2), Uint128::new(10));
+            let nois_balance: NoisBalanceResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::NoisBalance {})
+                .unwrap();
+            assert_eq!(nois_balance.balances, coins(5, NATIVE_DENOM_2));
+
+            // the rest of the prepaid balance can be taken back
+            let withdraw_nois = |amount: u128| ForgingGemExecuteMsg::WithdrawNoisBalance {
+                amount: coins(amount, NATIVE_DENOM_2),
+                recipient: None,
+            };
+            let err = app
+                .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &withdraw_nois(6), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::InsufficientNoisBalance { .. }));
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &withdraw_nois(5), &[])
+                .unwrap();
+            assert_eq!(balance(&app, forging_gem_addr, NATIVE_DENOM_2), Uint128::zero());
+            // the fees are earned once the job is settled
+            assert!(treasury(&app).balances.is_empty());
+            app.execute_contract(
+                Addr::unchecked(&contracts[4].contract_addr),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::NoisReceive {
+                    callback: NoisCallback {
+                        job_id: "0".to_string(),
+                        published: Timestamp::from_seconds(1),
+                        randomness: HexBinary::from([7u8; 32]),
+                    },
+                },
+                &[],
+            )
+            .unwrap();
+            assert_eq!(treasury(&app).balances, coins(100, NATIVE_DENOM));
+
+            let withdraw = |amount: u128| ForgingGemExecuteMsg::WithdrawTreasury {
+                amount: coins(amount, NATIVE_DENOM),
+                recipient: Some(USER_2.to_string()),
+            };
+            let err = app
+                .execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &withdraw(100), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+            let err = app
+                .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &withdraw(101), &[])
+                .unwrap_err();
+            assert!(matches!(
+                err.downcast().unwrap(),
+                ContractError::InsufficientTreasury { denom } if denom == NATIVE_DENOM
+            ));
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &withdraw(100), &[])
+                .unwrap();
+            assert_eq!(balance(&app, USER_2, NATIVE_DENOM), Uint128::new(100));
+            assert!(treasury(&app).balances.is_empty());
+        }
+
+        #[test]
+        fn expired_job_refunds_the_forge_fees() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let mut white = gem_tier("white", "White", "W", ["2", "3", "5", "8", "13", "21", "34"]);
+            white.forge_fees = coins(100, NATIVE_DENOM);
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateGemTier { tier: white },
+                &[],
+            )
+            .unwrap();
+            app.send_tokens(Addr::unchecked(ADMIN), Addr::unchecked(USER_1), &coins(1000, NATIVE_DENOM))
+                .unwrap();
+            join_queue(&mut app, &contracts);
+            app.execute_contract(
+                Addr::unchecked(USER_1),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ForgeGem { entry_ids: vec![0] },
+                &coins(100, NATIVE_DENOM),
+            )
+            .unwrap();
+            let balance = |app: &App, addr: &str| app.wrap().query_balance(addr, NATIVE_DENOM).unwrap().amount;
+            assert_eq!(balance(&app, USER_1), Uint128::new(900));
+            assert_eq!(balance(&app, forging_gem_addr), Uint128::new(100));
+
+            // the fees held for the job go back to its payer along with the gems
+            app.update_block(|block| block.time = block.time.plus_seconds(JOB_TIMEOUT));
+            app.execute_contract(
+                Addr::unchecked(USER_2),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ExpireJob { job_id: "0".to_string() },
+                &[],
+            )
+            .unwrap();
+            assert_eq!(balance(&app, USER_1), Uint128::new(1000));
+            assert_eq!(balance(&app, forging_gem_addr), Uint128::zero());
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "1"), USER_1);
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "2"), USER_1);
+            let treasury: TreasuryResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Treasury {})
+                .unwrap();
+            assert!(treasury.balances.is_empty());
+        }
+
+        #[test]
+        fn pause_operations_and_buffer_callbacks() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let set_pause_state = |pause_state: PauseState| ForgingGemExecuteMsg::SetPauseState { pause_state };
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &set_pause_state(PauseState { join_queue: true, ..Default::default() }),
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+
+            // each operation is paused on its own
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &set_pause_state(PauseState { join_queue: true, mint: true, ..Default::default() }),
+                &[],
+            )
+            .unwrap();
+            mint_dragon_gem(&mut app, &contracts, USER_1, "1");
+            let join = ReceiveNftMsg::JoinQueue { gem_materials: vec![], shield_id: None };
+            let err = send_dragon_gem(&mut app, &contracts, USER_1, "1", &join).unwrap_err();
+            assert!(matches!(err, ContractError::Paused { operation } if operation == "join_queue"));
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(ADMIN),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::MintShieldGem { owner: USER_1.to_string() },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Paused { operation } if operation == "mint"));
+
+            // the randomness received while the settlement is paused is kept
+            let pause_state = PauseState { settlement: true, ..Default::default() };
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &set_pause_state(pause_state.clone()), &[])
+                .unwrap();
+            let queried: PauseState = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::PauseState {})
+                .unwrap();
+            assert_eq!(queried, pause_state);
+            send_dragon_gem(&mut app, &contracts, USER_1, "1", &ReceiveNftMsg::Forge { gem_materials: vec![], shield_id: None })
+                .unwrap();
+            app.execute_contract(
+                Addr::unchecked(&contracts[4].contract_addr),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::NoisReceive {
+                    callback: NoisCallback {
+                        job_id: "0".to_string(),
+                        published: Timestamp::from_seconds(1),
+                        randomness: HexBinary::from([7u8; 32]),
+                    },
+                },
+                &[],
+            )
+            .unwrap();
+            let results = |app: &App| -> ForgeResultsResponse {
+                app.wrap()
+                    .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ForgeResult { job_id: "0".to_string() })
+                    .unwrap()
+            };
+            assert!(results(&app).results.is_empty());
+
+            // the job cannot expire before its timeout meanwhile
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_2),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::ExpireJob { job_id: "0".to_string() },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::JobSettlementPending { job_id } if job_id == "0"));
+
+            let settle = ForgingGemExecuteMsg::SettleBufferedJobs { limit: None };
+            let err = app
+                .execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &settle, &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Paused { operation } if operation == "settlement"));
+
+            // anyone settles the buffered jobs once the settlement resumes
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &set_pause_state(PauseState::default()),
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &settle, &[])
+                .unwrap();
+            assert_eq!(results(&app).results.len(), 1);
+            assert_eq!(escrow(&app, &contracts, "1"), None);
+        }
+
+        #[test]
+        fn buffered_job_failing_to_settle_expires() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::SetPauseState { pause_state: PauseState { settlement: true, ..Default::default() } },
+                &[],
+            )
+            .unwrap();
+
+            // job 0 upgrades a white-1 gem for sure, job 1 fails for sure and keeps its gem
+            join_queue(&mut app, &contracts);
+            app.execute_contract(
+                Addr::unchecked(USER_1),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ForgeGem { entry_ids: vec![0] },
+                &[],
+            )
+            .unwrap();
+            mint_dragon_gem(&mut app, &contracts, USER_2, "3");
+            send_dragon_gem(&mut app, &contracts, USER_2, "3", &ReceiveNftMsg::Forge { gem_materials: vec![], shield_id: None })
+                .unwrap();
+            for job_id in ["0", "1"] {
+                app.execute_contract(
+                    Addr::unchecked(&contracts[4].contract_addr),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::NoisReceive {
+                        callback: NoisCallback {
+                            job_id: job_id.to_string(),
+                            published: Timestamp::from_seconds(1),
+                            randomness: HexBinary::from([7u8; 32]),
+                        },
+                    },
+                    &[],
+                )
+                .unwrap();
+            }
+
+            // only the contract settles a single buffered job
+            let err = app
+                .execute_contract(
+                    Addr::unchecked(USER_2),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::SettleBufferedJob {
+                        job_id: "1".to_string(),
+                        randomness: HexBinary::from([7u8; 32]),
+                    },
+                    &[],
+                )
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+
+            // white-2 gems cannot be minted anymore, job 0 cannot be settled
+            let mut white = gem_tier("white", "White", "W", ["2", "3", "5", "8", "13", "21", "34"]);
+            white.max_star = 1;
+            white.uris.truncate(1);
+            white.work_powers.truncate(1);
+            white.work_loads.clear();
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateGemTier { tier: white },
+                &[],
+            )
+            .unwrap();
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::SetPauseState { pause_state: PauseState::default() },
+                &[],
+            )
+            .unwrap();
+            let res = app
+                .execute_contract(
+                    Addr::unchecked(USER_2),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::SettleBufferedJobs { limit: None },
+                    &[],
+                )
+                .unwrap();
+            assert!(res.has_event(
+                &Event::new("wasm")
+                    .add_attribute("action", "settle_buffered_job_failed")
+                    .add_attribute("job_id", "0")
+            ));
+
+            // job 1 is settled despite job 0, whose gems stay escrowed
+            let results = |app: &App, job_id: &str| -> ForgeResultsResponse {
+                app.wrap()
+                    .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ForgeResult { job_id: job_id.to_string() })
+                    .unwrap()
+            };
+            assert_eq!(results(&app, "1").results.len(), 1);
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "3"), USER_2);
+            assert!(results(&app, "0").results.is_empty());
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "1"), *forging_gem_addr);
+
+            // job 0 is refunded once it expires
+            let expire = ForgingGemExecuteMsg::ExpireJob { job_id: "0".to_string() };
+            let err = app
+                .execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &expire, &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::JobNotExpired { .. }));
+            app.update_block(|block| block.time = block.time.plus_seconds(JOB_TIMEOUT));
+            app.execute_contract(Addr::unchecked(USER_2), Addr::unchecked(forging_gem_addr), &expire, &[])
+                .unwrap();
+            for token_id in ["1", "2"] {
+                assert_eq!(owner_of(&app, &contracts[0].contract_addr, token_id), USER_1);
+                assert_eq!(escrow(&app, &contracts, token_id), None);
+            }
+        }
+
+        #[test]
+        fn buffered_job_expires_once_its_timeout_passed() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::SetPauseState { pause_state: PauseState { settlement: true, ..Default::default() } },
+                &[],
+            )
+            .unwrap();
+            mint_dragon_gem(&mut app, &contracts, USER_1, "1");
+            send_dragon_gem(&mut app, &contracts, USER_1, "1", &ReceiveNftMsg::Forge { gem_materials: vec![], shield_id: None })
+                .unwrap();
+            app.execute_contract(
+                Addr::unchecked(&contracts[4].contract_addr),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::NoisReceive {
+                    callback: NoisCallback {
+                        job_id: "0".to_string(),
+                        published: Timestamp::from_seconds(1),
+                        randomness: HexBinary::from([7u8; 32]),
+                    },
+                },
+                &[],
+            )
+            .unwrap();
+
+            // the settlement stays paused past the timeout, the job is listed and refunded like the others
+            app.update_block(|block| block.time = block.time.plus_seconds(JOB_TIMEOUT));
+            let expired: RandomJobsResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ExpiredJobs { start_after: None, limit: None })
+                .unwrap();
+            assert_eq!(expired.jobs.len(), 1);
+            app.execute_contract(
+                Addr::unchecked(USER_2),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ExpireJob { job_id: "0".to_string() },
+                &[],
+            )
+            .unwrap();
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "1"), USER_1);
+
+            // nothing is left to settle once the settlement resumes
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::SetPauseState { pause_state: PauseState::default() },
+                &[],
+            )
+            .unwrap();
+            let res = app
+                .execute_contract(
+                    Addr::unchecked(USER_2),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::SettleBufferedJobs { limit: None },
+                    &[],
+                )
+                .unwrap();
+            assert!(!res.has_event(&Event::new("wasm").add_attribute("action", "settle_buffered_job")));
+        }
+
+        #[test]
+        fn buy_random_gems() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            for user in [USER_1, USER_2] {
+                app.send_tokens(Addr::unchecked(ADMIN), Addr::unchecked(user), &coins(1000, NATIVE_DENOM))
+                    .unwrap();
+            }
+            let buy = |app: &mut App, user: &str, amount: u128| {
+                app.execute_contract(
+                    Addr::unchecked(user),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::MintRandomGem {},
+                    &coins(amount, NATIVE_DENOM),
+                )
+                .map_err(|err| err.downcast::<ContractError>().unwrap())
+            };
+            let open_sale = |supply_limit: u64| ForgingGemExecuteMsg::UpdateRandomGemSale {
+                sale: Some(RandomGemSale {
+                    price: coins(100, NATIVE_DENOM),
+                    supply_limit: Some(supply_limit),
+                }),
+            };
+            let sold = |app: &App| -> u64 {
+                let res: RandomGemSaleResponse = app
+                    .wrap()
+                    .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::RandomGemSale {})
+                    .unwrap();
+                res.sold
+            };
+            let balance = |app: &App, addr: &str| app.wrap().query_balance(addr, NATIVE_DENOM).unwrap().amount;
+
+            // the gems are not sold until the owner opens the sale
+            assert!(matches!(buy(&mut app, USER_1, 100).unwrap_err(), ContractError::RandomGemSaleClosed {}));
+            let err = app
+                .execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &open_sale(1), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &open_sale(1), &[])
+                .unwrap();
+
+            assert!(matches!(buy(&mut app, USER_1, 50).unwrap_err(), ContractError::InsufficentFund {}));
+            let res = buy(&mut app, USER_1, 150).unwrap();
+            let job_id = from_json::<ForgeGemResponse>(res.data.unwrap()).unwrap().job_id;
+            assert_eq!(balance(&app, USER_1), Uint128::new(900));
+            assert_eq!(sold(&app), 1);
+            assert!(matches!(buy(&mut app, USER_2, 100).unwrap_err(), ContractError::RandomGemSoldOut {}));
+
+            // the color is drawn with the gem ratio once the randomness comes back
+            let randomness = [7u8; 32];
+            app.execute_contract(
+                Addr::unchecked(&contracts[4].contract_addr),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::NoisReceive {
+                    callback: NoisCallback {
+                        job_id,
+                        published: Timestamp::from_seconds(1),
+                        randomness: HexBinary::from(randomness),
+                    },
+                },
+                &[],
+            )
+            .unwrap();
+            // the ratios of the tiers, by color
+            let weights = [("blue", 800), ("gold", 150), ("red", 50), ("white", 9000)];
+            let color = select_from_weighted(randomness, &weights).unwrap();
+            let gem: NftInfoResponse<Metadata> = app
+                .wrap()
+                .query_wasm_smart(&contracts[1].contract_addr, &Cw721QueryMsg::NftInfo { token_id: "1".to_string() })
+                .unwrap();
+            let attributes = gem.extension.attributes.unwrap();
+            assert_eq!((attributes[0].value.as_str(), attributes[1].value.as_str()), (color, "1"));
+            assert_eq!(owner_of(&app, &contracts[1].contract_addr, "1"), USER_1);
+            let treasury: TreasuryResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Treasury {})
+                .unwrap();
+            assert_eq!(treasury.balances, coins(100, NATIVE_DENOM));
+
+            // a gem never minted is refunded and goes back to the supply
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &open_sale(2), &[])
+                .unwrap();
+            let res = buy(&mut app, USER_2, 100).unwrap();
+            let job_id = from_json::<ForgeGemResponse>(res.data.unwrap()).unwrap().job_id;
+            assert_eq!(balance(&app, USER_2), Uint128::new(900));
+            let expired_mints = |app: &App| -> RandomMintsResponse {
+                app.wrap()
+                    .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ExpiredRandomMints { start_after: None, limit: None })
+                    .unwrap()
+            };
+            assert!(expired_mints(&app).mints.is_empty());
+            app.update_block(|block| block.time = block.time.plus_seconds(JOB_TIMEOUT));
+            let mints = expired_mints(&app).mints;
+            assert_eq!(mints.len(), 1);
+            assert_eq!((mints[0].0.as_str(), mints[0].1.fees.clone()), (job_id.as_str(), coins(100, NATIVE_DENOM)));
+            // the mints are not forge jobs
+            let expired_jobs: RandomJobsResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::ExpiredJobs { start_after: None, limit: None })
+                .unwrap();
+            assert!(expired_jobs.jobs.is_empty());
+            app.execute_contract(
+                Addr::unchecked(USER_2),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ExpireJob { job_id },
+                &[],
+            )
+            .unwrap();
+            assert_eq!(balance(&app, USER_2), Uint128::new(1000));
+            assert_eq!(sold(&app), 1);
+            assert!(expired_mints(&app).mints.is_empty());
+        }
+
+        #[test]
+        fn random_gems_are_drawn_from_the_gem_tiers() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let execute = |app: &mut App, msg: &ForgingGemExecuteMsg| {
+                app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), msg, &[])
+                    .map_err(|err| err.downcast::<ContractError>().unwrap())
+            };
+            execute(
+                &mut app,
+                &ForgingGemExecuteMsg::UpdateRandomGemSale {
+                    sale: Some(RandomGemSale { price: vec![], supply_limit: None }),
+                },
+            )
+            .unwrap();
+            // only a tier added after the instantiation is left to draw from
+            for (color, display_name, prefix) in [("white", "White", "W"), ("blue", "Blue", "B"), ("gold", "Gold", "G"), ("red", "Red", "R")] {
+                let tier = gem_tier(color, display_name, prefix, ["2", "3", "5", "8", "13", "21", "34"]);
+                execute(&mut app, &ForgingGemExecuteMsg::UpdateGemTier { tier }).unwrap();
+            }
+            let pink = with_gem_ratio(gem_tier("pink", "Pink", "P", ["2", "3", "5", "8", "13", "21", "34"]), "0.5");
+            execute(&mut app, &ForgingGemExecuteMsg::AddGemTier { tier: pink }).unwrap();
+
+            let res = execute(&mut app, &ForgingGemExecuteMsg::MintRandomGem {}).unwrap();
+            let job_id = from_json::<ForgeGemResponse>(res.data.unwrap()).unwrap().job_id;
+            app.execute_contract(
+                Addr::unchecked(&contracts[4].contract_addr),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::NoisReceive {
+                    callback: NoisCallback {
+                        job_id,
+                        published: Timestamp::from_seconds(1),
+                        randomness: HexBinary::from([7u8; 32]),
+                    },
+                },
+                &[],
+            )
+            .unwrap();
+            let gem: NftInfoResponse<Metadata> = app
+                .wrap()
+                .query_wasm_smart(&contracts[1].contract_addr, &Cw721QueryMsg::NftInfo { token_id: "1".to_string() })
+                .unwrap();
+            assert_eq!(gem.extension.attributes.unwrap()[0].value, "pink");
+
+            // a retired tier is not drawn anymore
+            execute(&mut app, &ForgingGemExecuteMsg::RetireGemTier { color: "pink".to_string() }).unwrap();
+            assert!(matches!(
+                execute(&mut app, &ForgingGemExecuteMsg::MintRandomGem {}).unwrap_err(),
+                ContractError::RandomGemSaleClosed {}
+            ));
+        }
+
+        #[test]
+        fn fuse_gems_into_the_next_color() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            for (user, token_id) in [(USER_1, "1"), (USER_1, "2"), (USER_1, "3"), (USER_2, "4")] {
+                mint_dragon_gem(&mut app, &contracts, user, token_id);
+                send_dragon_gem(&mut app, &contracts, user, token_id, &ReceiveNftMsg::Deposit {}).unwrap();
+            }
+            let fuse = |app: &mut App, token_ids: &[&str]| {
+                app.execute_contract(
+                    Addr::unchecked(USER_1),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::FuseGems {
+                        gems: token_ids.iter().map(|token_id| dragon_gem(&contracts, token_id)).collect(),
+                    },
+                    &[],
+                )
+                .map_err(|err| err.downcast::<ContractError>().unwrap())
+            };
+            let update_rule = |app: &mut App, sender: &str, color: &str, result_color: &str| {
+                app.execute_contract(
+                    Addr::unchecked(sender),
+                    Addr::unchecked(forging_gem_addr),
+                    &ForgingGemExecuteMsg::UpdateFusionRule {
+                        color: color.to_string(),
+                        star: 1,
+                        rule: Some(FusionRule { gem_count: 3, result_color: result_color.to_string(), result_star: 2 }),
+                    },
+                    &[],
+                )
+                .map_err(|err| err.downcast::<ContractError>().unwrap())
+            };
+
+            // the gems without a rule cannot be fused
+            let err = fuse(&mut app, &["1", "2", "3"]).unwrap_err();
+            assert!(matches!(err, ContractError::NoFusionRule { color, star: 1 } if color == "white"));
+            assert!(matches!(update_rule(&mut app, USER_1, "white", "blue").unwrap_err(), ContractError::Unauthorized {}));
+            let err = update_rule(&mut app, ADMIN, "red", "pink").unwrap_err();
+            assert!(matches!(err, ContractError::GemTierNotFound { color } if color == "pink"));
+            update_rule(&mut app, ADMIN, "white", "blue").unwrap();
+            let rules: FusionRulesResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::FusionRules {})
+                .unwrap();
+            assert_eq!(rules.rules, vec![("white".to_string(), 1, FusionRule { gem_count: 3, result_color: "blue".to_string(), result_star: 2 })]);
+
+            assert!(matches!(fuse(&mut app, &["1", "2"]).unwrap_err(), ContractError::InvalidFusion { .. }));
+            assert!(matches!(fuse(&mut app, &["1", "1", "2"]).unwrap_err(), ContractError::InvalidFusion { .. }));
+            assert!(matches!(fuse(&mut app, &["1", "2", "4"]).unwrap_err(), ContractError::Unauthorized {}));
+
+            // the gems are burnt for a gem of the result color
+            fuse(&mut app, &["1", "2", "3"]).unwrap();
+            let gem: NftInfoResponse<Metadata> = app
+                .wrap()
+                .query_wasm_smart(&contracts[1].contract_addr, &Cw721QueryMsg::NftInfo { token_id: "1".to_string() })
+                .unwrap();
+            let attributes = gem.extension.attributes.unwrap();
+            assert_eq!((attributes[0].value.as_str(), attributes[1].value.as_str()), ("blue", "2"));
+            assert_eq!(owner_of(&app, &contracts[1].contract_addr, "1"), USER_1);
+            for token_id in ["1", "2", "3"] {
+                let burnt: Result<NftInfoResponse<Empty>, _> = app
+                    .wrap()
+                    .query_wasm_smart(&contracts[0].contract_addr, &Cw721QueryMsg::NftInfo { token_id: token_id.to_string() });
+                assert!(burnt.is_err());
+                assert_eq!(escrow(&app, &contracts, token_id), None);
+            }
+        }
+
+        #[test]
+        fn salvage_gems_into_shields() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            for token_id in ["1", "2"] {
+                mint_dragon_gem(&mut app, &contracts, USER_1, token_id);
+                send_dragon_gem(&mut app, &contracts, USER_1, token_id, &ReceiveNftMsg::Deposit {}).unwrap();
+            }
+            let execute = |app: &mut App, sender: &str, msg: &ForgingGemExecuteMsg| {
+                app.execute_contract(Addr::unchecked(sender), Addr::unchecked(forging_gem_addr), msg, &[])
+                    .map_err(|err| err.downcast::<ContractError>().unwrap())
+            };
+            let salvage = |token_id: &str| ForgingGemExecuteMsg::SalvageGem { gems: vec![dragon_gem(&contracts, token_id)] };
+            let fragments = |app: &App| -> Uint128 {
+                let res: FragmentsResponse = app
+                    .wrap()
+                    .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Fragments { user: USER_1.to_string() })
+                    .unwrap();
+                res.fragments
+            };
+            let update_salvage_config = ForgingGemExecuteMsg::UpdateSalvageConfig {
+                salvage_config: Some(SalvageConfig {
+                    fragments_per_work_power: Decimal::percent(750),
+                    shield_cost: Uint128::new(20),
+                }),
+            };
+
+            // the gems cannot be salvaged until the owner sets the salvage config
+            assert!(matches!(execute(&mut app, USER_1, &salvage("1")).unwrap_err(), ContractError::SalvageClosed {}));
+            assert!(matches!(
+                execute(&mut app, USER_1, &update_salvage_config).unwrap_err(),
+                ContractError::Unauthorized {}
+            ));
+            for (fragments_per_work_power, shield_cost) in [(Decimal::percent(750), 0), (Decimal::zero(), 20)] {
+                let invalid_salvage_config = ForgingGemExecuteMsg::UpdateSalvageConfig {
+                    salvage_config: Some(SalvageConfig {
+                        fragments_per_work_power,
+                        shield_cost: Uint128::new(shield_cost),
+                    }),
+                };
+                assert!(matches!(
+                    execute(&mut app, ADMIN, &invalid_salvage_config).unwrap_err(),
+                    ContractError::InvalidSalvageConfig { .. }
+                ));
+            }
+            execute(&mut app, ADMIN, &update_salvage_config).unwrap();
+            assert!(matches!(
+                execute(&mut app, USER_1, &ForgingGemExecuteMsg::SalvageGem { gems: vec![] }).unwrap_err(),
+                ContractError::NoGemToSalvage {}
+            ));
+
+            // a white-1 gem has a work power of 2
+            execute(&mut app, USER_1, &salvage("1")).unwrap();
+            assert_eq!(fragments(&app), Uint128::new(15));
+            let burnt: Result<NftInfoResponse<Empty>, _> = app
+                .wrap()
+                .query_wasm_smart(&contracts[0].contract_addr, &Cw721QueryMsg::NftInfo { token_id: "1".to_string() });
+            assert!(burnt.is_err());
+            assert_eq!(escrow(&app, &contracts, "1"), None);
+            assert!(matches!(execute(&mut app, USER_1, &salvage("1")).unwrap_err(), ContractError::GemNotEscrowed { .. }));
+
+            let err = execute(&mut app, USER_1, &ForgingGemExecuteMsg::CraftShield {}).unwrap_err();
+            assert!(matches!(
+                err,
+                ContractError::InsufficientFragments { fragments, balance }
+                    if fragments == Uint128::new(20) && balance == Uint128::new(15)
+            ));
+            execute(&mut app, USER_1, &salvage("2")).unwrap();
+            execute(&mut app, USER_1, &ForgingGemExecuteMsg::CraftShield {}).unwrap();
+            assert_eq!(owner_of(&app, &contracts[2].contract_addr, "1"), USER_1);
+            let all_fragments: AllFragmentsResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::AllFragments { start_after: None, limit: None })
+                .unwrap();
+            assert_eq!(all_fragments.fragments, vec![(Addr::unchecked(USER_1), Uint128::new(10))]);
+        }
+
+        #[test]
+        fn forge_right_away() {
+            let (mut app, contracts) = instantiate_contracts();
+            mint_dragon_gem(&mut app, &contracts, USER_1, "1");
+            let res = send_dragon_gem(
+                &mut app,
+                &contracts,
+                USER_1,
+                "1",
+                &ReceiveNftMsg::Forge { gem_materials: vec![], shield_id: None },
+            )
+            .unwrap();
+            // the randomness is requested for a job of the entry alone
+            assert!(res.events.iter().any(|event| {
+                event.ty == "wasm" && event.attributes.iter().any(|attr| attr.key == "job_id" && attr.value == "0")
+            }));
+            let queue: QueueResponse = app
+                .wrap()
+                .query_wasm_smart(&contracts[3].contract_addr, &ForgingGemQueryMsg::UserQueueEntries { user: USER_1.to_string() })
+                .unwrap();
+            assert_eq!(queue.entries[0].user_info.job_id, Some("0".to_string()));
+
+            // the next job gets the next id
+            mint_dragon_gem(&mut app, &contracts, USER_2, "2");
+            let res = send_dragon_gem(
+                &mut app,
+                &contracts,
+                USER_2,
+                "2",
+                &ReceiveNftMsg::Forge { gem_materials: vec![], shield_id: None },
+            )
+            .unwrap();
+            assert!(res.events.iter().any(|event| {
+                event.ty == "wasm" && event.attributes.iter().any(|attr| attr.key == "job_id" && attr.value == "1")
+            }));
+        }
+
+        #[test]
+        fn forge_right_away_only_without_forge_fees() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let mut white = gem_tier("white", "White", "W", ["2", "3", "5", "8", "13", "21", "34"]);
+            white.forge_fees = coins(100, NATIVE_DENOM);
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateGemTier { tier: white },
+                &[],
+            )
+            .unwrap();
+            mint_dragon_gem(&mut app, &contracts, USER_1, "1");
+
+            // no funds come with the gem, it stays with the user
+            let err = send_dragon_gem(
+                &mut app,
+                &contracts,
+                USER_1,
+                "1",
+                &ReceiveNftMsg::Forge { gem_materials: vec![], shield_id: None },
+            )
+            .unwrap_err();
+            assert!(matches!(err, ContractError::ForgeFeesRequired { color } if color == "white"));
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "1"), USER_1);
+            assert_eq!(escrow(&app, &contracts, "1"), None);
+            let queue: QueueResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Queue { start_after: None, limit: None })
+                .unwrap();
+            assert!(queue.entries.is_empty());
+
+            // the gem joins the queue and the fees are paid with ForgeGem
+            send_dragon_gem(
+                &mut app,
+                &contracts,
+                USER_1,
+                "1",
+                &ReceiveNftMsg::JoinQueue { gem_materials: vec![], shield_id: None },
+            )
+            .unwrap();
+            app.send_tokens(Addr::unchecked(ADMIN), Addr::unchecked(USER_1), &coins(100, NATIVE_DENOM))
+                .unwrap();
+            app.execute_contract(
+                Addr::unchecked(USER_1),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::ForgeGem { entry_ids: vec![0] },
+                &coins(100, NATIVE_DENOM),
+            )
+            .unwrap();
+        }
+
+        #[test]
+        fn max_star_gems_cannot_join_the_queue() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let mut white = gem_tier("white", "White", "W", ["2", "3", "5", "8", "13", "21", "34"]);
+            white.max_star = 1;
+            white.uris.truncate(1);
+            white.work_powers.truncate(1);
+            white.work_loads.clear();
+            app.execute_contract(
+                Addr::unchecked(ADMIN),
+                Addr::unchecked(forging_gem_addr),
+                &ForgingGemExecuteMsg::UpdateGemTier { tier: white },
+                &[],
+            )
+            .unwrap();
+            mint_dragon_gem(&mut app, &contracts, USER_1, "1");
+            mint_dragon_gem(&mut app, &contracts, USER_1, "2");
+            send_dragon_gem(&mut app, &contracts, USER_1, "2", &ReceiveNftMsg::Deposit {}).unwrap();
+
+            // the white-1 gem is at the max star, neither it nor its material is held for an entry
+            let hooks = [
+                ReceiveNftMsg::JoinQueue { gem_materials: vec![dragon_gem(&contracts, "2")], shield_id: None },
+                ReceiveNftMsg::Forge { gem_materials: vec![dragon_gem(&contracts, "2")], shield_id: None },
+            ];
+            for hook in &hooks {
+                let err = send_dragon_gem(&mut app, &contracts, USER_1, "1", hook).unwrap_err();
+                assert!(matches!(err, ContractError::MaxStarReached { color, max_star: 1 } if color == "white"));
+                assert_eq!(owner_of(&app, &contracts[0].contract_addr, "1"), USER_1);
+                assert_eq!(escrow(&app, &contracts, "1"), None);
+                assert_eq!(escrow(&app, &contracts, "2"), Some(Escrow { owner: Addr::unchecked(USER_1), entry_id: None }));
+                let queue: QueueResponse = app
+                    .wrap()
+                    .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::Queue { start_after: None, limit: None })
+                    .unwrap();
+                assert!(queue.entries.is_empty());
+            }
+        }
+
+        #[test]
+        fn dragon_gems_are_recognized_by_token_uri() {
+            let (mut app, contracts) = instantiate_contracts();
+            let forging_gem_addr = &contracts[3].contract_addr;
+            let token_uri = "ipfs://blue-dragon-gem";
+            mint_dragon_gem_with_uri(&mut app, &contracts, USER_1, "1", token_uri);
+            let forge = ReceiveNftMsg::Forge { gem_materials: vec![], shield_id: None };
+
+            // the token uri is not registered, the gem stays with the user
+            let err = send_dragon_gem(&mut app, &contracts, USER_1, "1", &forge).unwrap_err();
+            assert!(matches!(
+                err,
+                ContractError::UnknownDragonGem { nft_id, token_uri: Some(uri) } if nft_id == "1" && uri == token_uri
+            ));
+            assert_eq!(owner_of(&app, &contracts[0].contract_addr, "1"), USER_1);
+
+            let register = |star: u8| ForgingGemExecuteMsg::UpdateDragonGem {
+                token_uri: token_uri.to_string(),
+                gem: Some(GemKind { color: "blue".to_string(), star }),
+            };
+            let err = app
+                .execute_contract(Addr::unchecked(USER_1), Addr::unchecked(forging_gem_addr), &register(1), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::Unauthorized {}));
+            let err = app
+                .execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &register(8), &[])
+                .unwrap_err();
+            assert!(matches!(err.downcast().unwrap(), ContractError::StarOutOfRange {}));
+            app.execute_contract(Addr::unchecked(ADMIN), Addr::unchecked(forging_gem_addr), &register(1), &[])
+                .unwrap();
+
+            let gem: Option<GemKind> = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::DragonGem { token_uri: token_uri.to_string() })
+                .unwrap();
+            assert_eq!(gem, Some(GemKind { color: "blue".to_string(), star: 1 }));
+            let gems: DragonGemsResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::DragonGems { start_after: None, limit: None })
+                .unwrap();
+            assert_eq!(gems.gems.len(), 2);
+
+            // the gem is forged as a blue gem
+            send_dragon_gem(&mut app, &contracts, USER_1, "1", &forge).unwrap();
+            let queue: QueueResponse = app
+                .wrap()
+                .query_wasm_smart(forging_gem_addr, &ForgingGemQueryMsg::UserQueueEntries { user: USER_1.to_string() })
+                .unwrap();
+            assert_eq!(queue.entries[0].user_info.job_id, Some("0".to_string()));
+        }
+    }
+}